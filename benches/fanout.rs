@@ -0,0 +1,109 @@
+//! Measures the cost of fanning a message out to a channel of [`MEMBERS`] members, with the
+//! message broadcast as a `String` every member encodes on its own, as it used to be, and as a
+//! [`Frame`] encoded once and shared by every member.
+//!
+//! Run it with `cargo bench --bench fanout`. Along with the timings, the number of allocations a
+//! single fan-out makes is printed for both.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bytes::BytesMut;
+use chat::codec::{Frame, LineCodec, DEFAULT_LENGTH_LIMIT};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::sync::broadcast;
+use tokio_util::codec::{Encoder, LinesCodec};
+
+/// How many members the channel has.
+const MEMBERS: usize = 1_000;
+
+/// The message fanned out, about as long as a line of chat.
+const LINE: &str = "[chan] user_42: the quick brown fox jumps over the lazy dog, twice over";
+
+/// Counts the allocations made, so the benchmark can tell how many a fan-out makes.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// A channel and its members, each with the codec and write buffer of its connection.
+struct Channel<T, C> {
+    tx: broadcast::Sender<T>,
+    members: Vec<(broadcast::Receiver<T>, C, BytesMut)>,
+}
+
+impl<T: Clone + fmt::Debug, C: Encoder<T> + Clone> Channel<T, C>
+where
+    C::Error: fmt::Debug,
+{
+    fn new(codec: C) -> Self {
+        let (tx, _) = broadcast::channel(16);
+        let members = (0..MEMBERS)
+            .map(|_| (tx.subscribe(), codec.clone(), BytesMut::with_capacity(1024)))
+            .collect();
+        Self { tx, members }
+    }
+
+    /// Broadcasts `msg` and has every member write it to its buffer.
+    fn fan_out(&mut self, msg: T) {
+        self.tx.send(msg).unwrap();
+        for (rx, codec, buf) in &mut self.members {
+            // Write buffers are reused once flushed, as they are by the connections.
+            buf.clear();
+            let msg = rx.try_recv().unwrap();
+            codec.encode(msg, buf).unwrap();
+        }
+    }
+}
+
+/// Counts the allocations made by `f`.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn fanout(c: &mut Criterion) {
+    let mut strings = Channel::new(LinesCodec::new_with_max_length(DEFAULT_LENGTH_LIMIT));
+    let mut frames = Channel::new(LineCodec::new_with_max_length(DEFAULT_LENGTH_LIMIT));
+
+    println!(
+        "allocations per fan-out to {} members: {} as `String`, {} as `Frame`",
+        MEMBERS,
+        allocations(|| strings.fan_out(LINE.to_owned())),
+        allocations(|| frames.fan_out(Frame::from(LINE))),
+    );
+
+    let mut group = c.benchmark_group("fanout");
+    group.bench_function(BenchmarkId::new("String", MEMBERS), |b| {
+        b.iter(|| strings.fan_out(LINE.to_owned()))
+    });
+    group.bench_function(BenchmarkId::new("Frame", MEMBERS), |b| {
+        b.iter(|| frames.fan_out(Frame::from(LINE)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
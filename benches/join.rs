@@ -0,0 +1,144 @@
+//! Measures how fast clients join channels, with every channel behind a single lock, as they
+//! used to be, and spread over the default number of shards.
+//!
+//! The single lock is only an approximation of the registry this replaced, which was one
+//! `std::sync::Mutex` around a map holding every channel by value. Here it is a registry with a
+//! single shard: joins and leaves are serialised on the shard's lock just the same, since it is
+//! held while the channel is updated, but each channel still sits behind a lock of its own, and
+//! messages are sent without taking the shard's lock at all. The numbers for one shard show how
+//! much contention on a single lock costs, not exactly how the old design performed.
+//!
+//! Run it with `cargo bench --bench join`. Clients are connected to
+//! [`Server::handle_client`] through in-memory pipes rather than sockets, so that tens of
+//! thousands of them don't run out of file descriptors or ports.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use chat::{
+    codec::ChatCodec,
+    config::ServerConfig,
+    server::{Server, ServerError},
+    session::Shared,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{self, DuplexStream},
+    runtime::Runtime,
+    task::JoinHandle,
+};
+
+/// The numbers of clients joining at once.
+const CLIENTS: [usize; 3] = [2_000, 10_000, 50_000];
+
+/// How many clients join each channel, which is about how many lines each of them receives.
+const MEMBERS: usize = 50;
+
+/// How many times each number of clients joins, with a fresh server every time.
+const RUNS: usize = 3;
+
+/// Buffer size of the pipe between each client and the server.
+const PIPE_SIZE: usize = 16 * 1024;
+
+fn main() -> Result<(), Error> {
+    let runtime = Runtime::new()?;
+    let sharded = ServerConfig::default().channel_shards;
+    for &clients in CLIENTS.iter() {
+        for &shards in [1, sharded].iter() {
+            let mut latencies = Vec::with_capacity(clients * RUNS);
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..RUNS {
+                let run = runtime.block_on(run(clients, shards))?;
+                elapsed += run.0;
+                latencies.extend(run.1);
+            }
+            latencies.sort_unstable();
+            let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+            println!(
+                "{:>6} clients, {:>3} shard(s): {:>9.0} joins/s, latency p50 {:>10.3?}, \
+                 p99 {:>10.3?}, max {:>10.3?}",
+                clients,
+                shards,
+                latencies.len() as f64 / elapsed.as_secs_f64(),
+                percentile(50),
+                percentile(99),
+                percentile(100),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Has `clients` clients join channels of [`MEMBERS`] members all at once, on a server with
+/// `shards` shards.
+///
+/// Returns how long it took for every client to join, along with how long each of them waited
+/// to be told they had.
+async fn run(clients: usize, shards: usize) -> Result<(Duration, Vec<Duration>), Error> {
+    let server = Server::new(ServerConfig {
+        bind: Vec::new(),
+        channel_shards: shards,
+        ..ServerConfig::default()
+    })
+    .await?;
+    let shared = server.shared();
+
+    // Everyone is connected before the clock starts, so only joining is measured.
+    let (streams, handlers): (Vec<_>, Vec<_>) = (0..clients).map(|i| connect(&shared, i)).unzip();
+    let start = Instant::now();
+    let joins: Vec<_> = streams
+        .into_iter()
+        .enumerate()
+        .map(|(i, stream)| tokio::spawn(join(stream, i)))
+        .collect();
+    let mut latencies = Vec::with_capacity(clients);
+    let mut joined = Vec::with_capacity(clients);
+    for join in joins {
+        let (latency, client) = join.await??;
+        latencies.push(latency);
+        joined.push(client);
+    }
+    let elapsed = start.elapsed();
+
+    // Clients only leave once everyone has joined, so channels keep growing until then.
+    drop(joined);
+    for handler in handlers {
+        handler.await?.ok();
+    }
+    Ok((elapsed, latencies))
+}
+
+/// Connects client number `i` to the server, returning its end of the pipe along with the task
+/// handling the other end.
+fn connect(shared: &Arc<Shared>, i: usize) -> (DuplexStream, JoinHandle<Result<(), ServerError>>) {
+    let (client, server) = io::duplex(PIPE_SIZE);
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), (i % 60_000) as u16 + 1024);
+    let handler = tokio::spawn(Server::handle_client(shared.clone(), server, addr));
+    (client, handler)
+}
+
+/// Has client number `i` join its channel, returning how long it waited to be told it had.
+async fn join(
+    stream: DuplexStream,
+    i: usize,
+) -> Result<(Duration, ChatCodec<DuplexStream>), Error> {
+    let mut client = ChatCodec::new(stream);
+    let user = format!("user_{}", i);
+    let joined = format!("{} has joined", user);
+    let start = Instant::now();
+    client
+        .send(&format!("JOIN chan_{} {}", i / MEMBERS, user))
+        .await?;
+    // Others joining the channel first are announced before we are.
+    loop {
+        match client.next().await.transpose()? {
+            Some(line) if line == joined => return Ok((start.elapsed(), client)),
+            Some(_) => continue,
+            None => return Err(anyhow!("`{}` was disconnected before joining", user)),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Registered user names of the [`Server`](crate::server::Server).
+//!
+//! Users can register their name with a password, after which the server may refuse that name to
+//! anyone who hasn't identified with the password. Only argon2 hashes of the passwords are kept.
+//! The accounts can also be appended to a file, from which they are loaded again when the server
+//! starts, in which case every line of the file is an account in the form `USER HASH`, where
+//! `HASH` is in the PHC string format.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use argon2::{
+    password_hash::{self, rand_core::OsRng, PasswordHash, SaltString},
+    Argon2, PasswordHasher, PasswordVerifier,
+};
+use thiserror::Error;
+use tokio::task;
+
+use crate::HashMap;
+
+/// Error type for [`Accounts`].
+#[derive(Debug, Error)]
+pub enum AccountError {
+    #[error("failed to open accounts file `{0}`")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("failed to read accounts file `{0}`")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("failed to write to accounts file `{0}`")]
+    Write(PathBuf, #[source] io::Error),
+    #[error("failed to hash password: {0}")]
+    Hash(password_hash::Error),
+    #[error("user name `{0}` is already registered")]
+    AlreadyRegistered(String),
+}
+
+/// Every registered user name, along with the hash of its password.
+pub struct Accounts {
+    hashes: Mutex<HashMap<String, String>>,
+    /// The file every new account is appended to, if any.
+    file: Option<(PathBuf, Mutex<File>)>,
+}
+
+impl Accounts {
+    /// Creates accounts kept in memory only.
+    pub fn new() -> Self {
+        Self {
+            hashes: Default::default(),
+            file: None,
+        }
+    }
+
+    /// Creates accounts that are appended to the file at `path`, after loading the ones it
+    /// already holds.
+    pub fn open(path: &Path) -> Result<Self, AccountError> {
+        let mut accounts = Self::new();
+
+        match File::open(path) {
+            Ok(file) => {
+                let hashes = accounts.hashes.get_mut().unwrap();
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(|e| AccountError::Read(path.into(), e))?;
+                    // Lines that can't be made sense of, such as one cut short by a crash, are
+                    // simply skipped.
+                    if let Some((user_name, hash)) = line.split_once(' ') {
+                        if PasswordHash::new(hash).is_ok() {
+                            hashes.insert(user_name.to_owned(), hash.to_owned());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(AccountError::Open(path.into(), e)),
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| AccountError::Open(path.into(), e))?;
+        accounts.file = Some((path.into(), Mutex::new(file)));
+
+        Ok(accounts)
+    }
+
+    /// Whether `user_name` is registered.
+    pub fn is_registered(&self, user_name: &str) -> bool {
+        self.hashes.lock().unwrap().contains_key(user_name)
+    }
+
+    /// Registers `user_name` with `password`.
+    ///
+    /// Hashing is slow on purpose, so it's done on the blocking thread pool.
+    pub async fn register(
+        self: &Arc<Self>,
+        user_name: &str,
+        password: &str,
+    ) -> Result<(), AccountError> {
+        if self.is_registered(user_name) {
+            return Err(AccountError::AlreadyRegistered(user_name.into()));
+        }
+        let password = password.to_owned();
+        let hash = task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+        })
+        .await
+        .expect("password hashing panicked")
+        .map_err(AccountError::Hash)?;
+
+        // Someone else may have registered the name while we were hashing.
+        let mut hashes = self.hashes.lock().unwrap();
+        if hashes.contains_key(user_name) {
+            return Err(AccountError::AlreadyRegistered(user_name.into()));
+        }
+        if let Some((path, file)) = &self.file {
+            let line = format!("{} {}\n", user_name, hash);
+            file.lock()
+                .unwrap()
+                .write_all(line.as_bytes())
+                .map_err(|e| AccountError::Write(path.clone(), e))?;
+        }
+        hashes.insert(user_name.to_owned(), hash);
+        Ok(())
+    }
+
+    /// Whether `password` is the one `user_name` registered with.
+    ///
+    /// Returns `false` if the name isn't registered at all.
+    pub async fn verify(self: &Arc<Self>, user_name: &str, password: &str) -> bool {
+        let hash = match self.hashes.lock().unwrap().get(user_name) {
+            Some(hash) => hash.clone(),
+            None => return false,
+        };
+        let password = password.to_owned();
+        task::spawn_blocking(move || {
+            // Only valid hashes are ever stored.
+            let hash = PasswordHash::new(&hash).unwrap();
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .await
+        .expect("password verification panicked")
+    }
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,476 @@
+//! The channels of a [`Server`](crate::server::Server), and the users who are members of them.
+//!
+//! The first member of a channel becomes its operator, who may kick other members out, make
+//! them operators too, and ban users from joining. A ban is a glob matched against the user's
+//! name, or against `USER@ADDRESS` if it contains an `@`, e.g. `*@192.168.1.*`.
+//!
+//! Operators also set the [`ChannelModes`], which restrict who may join the channel and who may
+//! speak in it.
+//!
+//! The channels are kept in [`Channels`], which spreads them over shards so that clients busy
+//! with different channels rarely wait on each other.
+
+use std::{
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::SendError};
+use tracing::debug;
+
+use crate::{
+    command::ModeChange,
+    session::{Broadcast, ChatMessage, Message},
+    HashMap,
+};
+
+/// The transmission portion of a channel's messages.
+///
+/// Messages are put in their [`ChatMessage`] envelope as they are sent, under a lock, so that
+/// members receive every channel's messages in the order of their ids.
+#[derive(Debug, Clone)]
+pub(crate) struct Tx {
+    chan_name: Arc<str>,
+    sender: Arc<Mutex<broadcast::Sender<Broadcast>>>,
+}
+
+impl Tx {
+    fn new(chan_name: &str, capacity: usize) -> Self {
+        Self {
+            chan_name: chan_name.into(),
+            sender: Arc::new(Mutex::new(broadcast::channel(capacity).0)),
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Broadcast> {
+        lock(&self.sender).subscribe()
+    }
+
+    /// Broadcasts `msg` to every member of the channel, giving it back if there are none.
+    pub(crate) fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
+        let sender = lock(&self.sender);
+        let msg = ChatMessage::new(&self.chan_name, msg);
+        sender
+            .send(msg.into())
+            .map(drop)
+            .map_err(|SendError(broadcast)| SendError(broadcast.message().clone()))
+    }
+}
+
+/// The channels whose names hash to the same shard of [`Channels`].
+type Shard = Mutex<HashMap<String, Arc<Mutex<Channel>>>>;
+
+/// Every channel of the server, by name.
+///
+/// The names are spread over shards, each behind its own lock, which is only held long enough to
+/// find, create or delete a channel. Each channel is behind a lock of its own, so that joining
+/// one channel doesn't wait on everyone talking in, or joining, another.
+///
+/// Whenever both are held, the shard is locked before the channel.
+pub(crate) struct Channels {
+    shards: Box<[Shard]>,
+    hasher: ahash::RandomState,
+}
+
+impl Channels {
+    /// Creates a registry without any channels, spread over `shards` shards.
+    ///
+    /// With a single shard, every join and leave goes through the same lock.
+    pub(crate) fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards).map(|_| Shard::default()).collect(),
+            hasher: ahash::RandomState::new(),
+        }
+    }
+
+    fn shard(&self, chan_name: &str) -> MutexGuard<'_, HashMap<String, Arc<Mutex<Channel>>>> {
+        let mut hasher = self.hasher.build_hasher();
+        chan_name.hash(&mut hasher);
+        lock(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+
+    /// Runs `f` on `chan_name`, returning `None` if there's no such channel.
+    ///
+    /// The shard is unlocked by the time `f` runs, so if the channel is deleted in the meantime,
+    /// `f` sees it as it was when its last member left.
+    pub(crate) fn with<R>(&self, chan_name: &str, f: impl FnOnce(&mut Channel) -> R) -> Option<R> {
+        let channel = self.shard(chan_name).get(chan_name).cloned()?;
+        let mut channel = lock(&channel);
+        Some(f(&mut channel))
+    }
+
+    /// Runs `f` on `chan_name`, creating the channel first, holding at most `capacity` messages,
+    /// if there's none under that name.
+    ///
+    /// The channel can't be deleted while `f` runs, so members it adds stay in it. A channel left
+    /// without members, e.g. because `f` refused to add anyone to a new one, is deleted.
+    pub(crate) fn with_or_create<R>(
+        &self,
+        chan_name: &str,
+        capacity: usize,
+        f: impl FnOnce(&mut Channel) -> R,
+    ) -> R {
+        let mut shard = self.shard(chan_name);
+        let channel = shard
+            .entry(chan_name.into())
+            .or_insert_with(|| Arc::new(Mutex::new(Channel::new(chan_name, capacity))))
+            .clone();
+        let mut channel = lock(&channel);
+        let result = f(&mut channel);
+        if channel.members.is_empty() {
+            shard.remove(chan_name);
+        }
+        result
+    }
+
+    /// Removes `user_name` from the members of `chan_name`, deleting the channel once it's empty.
+    pub(crate) fn remove_member(&self, chan_name: &str, user_name: &str) {
+        let mut shard = self.shard(chan_name);
+        let is_empty = match shard.get(chan_name) {
+            Some(channel) => {
+                let mut channel = lock(channel);
+                channel.members.remove(user_name);
+                channel.members.is_empty()
+            }
+            None => return,
+        };
+        if is_empty {
+            debug!("channel `{}` is now empty and will be deleted.", chan_name);
+            shard.remove(chan_name);
+        }
+    }
+
+    /// Runs `f` on every channel, along with its name, in no particular order.
+    ///
+    /// Each shard is only locked long enough to see which channels it holds, so channels created
+    /// or deleted in the meantime may or may not be seen.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&str, &mut Channel)) {
+        for shard in self.shards.iter() {
+            let channels: Vec<_> = lock(shard)
+                .iter()
+                .map(|(chan_name, channel)| (chan_name.clone(), channel.clone()))
+                .collect();
+            for (chan_name, channel) in channels {
+                f(&chan_name, &mut lock(&channel));
+            }
+        }
+    }
+}
+
+/// Locks `mutex`, even if a thread panicked while holding it.
+///
+/// Every update leaves the channels consistent, and sessions leave their channels from `Drop`,
+/// where giving up on a poisoned lock would leave the user a member forever.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A user's membership of a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    /// The address the user is connected from.
+    pub addr: SocketAddr,
+    /// When the user joined the channel.
+    pub joined_at: DateTime<Utc>,
+    /// Whether the user is an operator of the channel.
+    pub is_operator: bool,
+    /// Whether the user may speak while the channel is moderated.
+    pub is_voiced: bool,
+}
+
+/// The modes of a channel, every one of which is off for new channels.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelModes {
+    /// Only invited users may join, `i`.
+    pub invite_only: bool,
+    /// Users must give this key to join, `k`.
+    pub key: Option<String>,
+    /// No more than this many users may be members at once, `l`.
+    pub limit: Option<usize>,
+    /// Only operators and voiced members may speak, `m`.
+    pub moderated: bool,
+}
+
+impl ChannelModes {
+    /// Applies `change`, returning `false` if the modes were already that way.
+    ///
+    /// Changes to a single member, such as [`ModeChange::Voice`], leave the modes untouched.
+    pub fn apply(&mut self, change: &ModeChange) -> bool {
+        fn replace<T: PartialEq>(mode: &mut T, value: T) -> bool {
+            let changed = *mode != value;
+            *mode = value;
+            changed
+        }
+        match change {
+            ModeChange::InviteOnly(on) => replace(&mut self.invite_only, *on),
+            ModeChange::Key(key) => replace(&mut self.key, key.clone()),
+            ModeChange::Limit(limit) => replace(&mut self.limit, *limit),
+            ModeChange::Moderated(on) => replace(&mut self.moderated, *on),
+            ModeChange::Voice(..) => false,
+        }
+    }
+}
+
+/// Renders the modes the way `MODE` sets them, e.g. `+ikl secret 10`, or just `+` if none are
+/// set.
+impl fmt::Display for ChannelModes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("+")?;
+        let flags = [
+            (self.invite_only, "i"),
+            (self.key.is_some(), "k"),
+            (self.limit.is_some(), "l"),
+            (self.moderated, "m"),
+        ];
+        for (_, flag) in flags.iter().filter(|(on, _)| *on) {
+            f.write_str(flag)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, " {}", key)?;
+        }
+        if let Some(limit) = self.limit {
+            write!(f, " {}", limit)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a user may not join a channel, see [`Channel::admit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    Banned,
+    InviteOnly,
+    BadKey,
+    Full,
+}
+
+/// A channel, with its members, its topic and the sender its messages are broadcast on.
+pub(crate) struct Channel {
+    members: HashMap<String, Member>,
+    topic: Option<String>,
+    /// The masks of the users banned from joining the channel.
+    bans: Vec<String>,
+    modes: ChannelModes,
+    /// The users invited to join the channel, until they do.
+    invited: Vec<String>,
+    tx: Tx,
+}
+
+impl Channel {
+    /// Creates an empty channel named `chan_name`, holding at most `capacity` messages for its
+    /// slowest member.
+    pub(crate) fn new(chan_name: &str, capacity: usize) -> Self {
+        Self {
+            members: HashMap::default(),
+            topic: None,
+            bans: Vec::new(),
+            modes: ChannelModes::default(),
+            invited: Vec::new(),
+            tx: Tx::new(chan_name, capacity),
+        }
+    }
+
+    pub(crate) fn tx(&self) -> &Tx {
+        &self.tx
+    }
+
+    pub(crate) fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+
+    pub(crate) fn set_topic(&mut self, topic: &str) {
+        self.topic = Some(topic.to_owned());
+    }
+
+    pub(crate) fn modes(&self) -> &ChannelModes {
+        &self.modes
+    }
+
+    pub(crate) fn modes_mut(&mut self) -> &mut ChannelModes {
+        &mut self.modes
+    }
+
+    pub(crate) fn member_count(&self) -> usize {
+        self.members.len()
+    }
+
+    pub(crate) fn is_member(&self, user_name: &str) -> bool {
+        self.members.contains_key(user_name)
+    }
+
+    pub(crate) fn member(&self, user_name: &str) -> Option<&Member> {
+        self.members.get(user_name)
+    }
+
+    pub(crate) fn member_mut(&mut self, user_name: &str) -> Option<&mut Member> {
+        self.members.get_mut(user_name)
+    }
+
+    /// Adds `user_name` to the members, returning a receiver for the channel's messages.
+    ///
+    /// The first member of the channel is made its operator, as is anyone added as one.
+    ///
+    /// Returns `None` if there's already a member by that name.
+    pub(crate) fn add(
+        &mut self,
+        user_name: &str,
+        addr: SocketAddr,
+        is_operator: bool,
+    ) -> Option<broadcast::Receiver<Broadcast>> {
+        if self.is_member(user_name) {
+            return None;
+        }
+        let member = Member {
+            addr,
+            joined_at: Utc::now(),
+            is_operator: is_operator || self.members.is_empty(),
+            is_voiced: false,
+        };
+        self.invited.retain(|name| name != user_name);
+        self.members.insert(user_name.to_owned(), member);
+        Some(self.tx.subscribe())
+    }
+
+    /// Renames the member `old_name` to `new_name`, keeping their place in the channel, or their
+    /// invite if they were invited to it.
+    pub(crate) fn rename(&mut self, old_name: &str, new_name: &str) {
+        if let Some(member) = self.members.remove(old_name) {
+            self.members.insert(new_name.to_owned(), member);
+        }
+        if let Some(invited) = self.invited.iter_mut().find(|name| *name == old_name) {
+            *invited = new_name.to_owned();
+        }
+    }
+
+    /// Removes `user_name` from the members, returning `false` if they weren't one.
+    ///
+    /// Unlike [`Channels::remove_member`], this never deletes the channel.
+    pub(crate) fn remove(&mut self, user_name: &str) -> bool {
+        self.members.remove(user_name).is_some()
+    }
+
+    /// Checks whether `user_name`, connected from `addr`, may join the channel with `key`.
+    ///
+    /// Invited users get past invite-only, but still need the key and a free spot.
+    pub(crate) fn admit(
+        &self,
+        user_name: &str,
+        addr: SocketAddr,
+        key: Option<&str>,
+    ) -> Result<(), Refusal> {
+        if self.is_banned(user_name, addr) {
+            Err(Refusal::Banned)
+        } else if self.modes.invite_only && !self.invited.iter().any(|name| name == user_name) {
+            Err(Refusal::InviteOnly)
+        } else if self.modes.key.is_some() && self.modes.key.as_deref() != key {
+            Err(Refusal::BadKey)
+        } else if self
+            .modes
+            .limit
+            .map_or(false, |limit| self.members.len() >= limit)
+        {
+            Err(Refusal::Full)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lets `user_name` join the channel while it's invite-only, returning `false` if they
+    /// already could.
+    pub(crate) fn invite(&mut self, user_name: &str) -> bool {
+        if self.invited.iter().any(|name| name == user_name) {
+            return false;
+        }
+        self.invited.push(user_name.to_owned());
+        true
+    }
+
+    /// Whether `user_name` may speak in the channel, which takes operator or voice status while
+    /// it's moderated.
+    pub(crate) fn can_speak(&self, user_name: &str) -> bool {
+        !self.modes.moderated
+            || self
+                .member(user_name)
+                .map_or(false, |member| member.is_operator || member.is_voiced)
+    }
+
+    /// Whether `user_name`, connected from `addr`, matches any of the bans.
+    pub(crate) fn is_banned(&self, user_name: &str, addr: SocketAddr) -> bool {
+        let user_at_addr = format!("{}@{}", user_name, addr.ip());
+        self.bans.iter().any(|mask| {
+            if mask.contains('@') {
+                glob_match(mask, &user_at_addr)
+            } else {
+                glob_match(mask, user_name)
+            }
+        })
+    }
+
+    /// The masks of the users banned from the channel, in the order they were banned.
+    pub(crate) fn bans(&self) -> &[String] {
+        &self.bans
+    }
+
+    /// Bans the users matching `mask`, returning `false` if they already were.
+    pub(crate) fn ban(&mut self, mask: &str) -> bool {
+        if self.bans.iter().any(|m| m == mask) {
+            return false;
+        }
+        self.bans.push(mask.to_owned());
+        true
+    }
+
+    /// Lifts the ban on `mask`, returning `false` if there was none.
+    pub(crate) fn unban(&mut self, mask: &str) -> bool {
+        let len = self.bans.len();
+        self.bans.retain(|m| m != mask);
+        self.bans.len() != len
+    }
+
+    /// The members of the channel, in the order they joined it.
+    pub(crate) fn members(&self) -> Vec<(&str, &Member)> {
+        let mut members: Vec<_> = self
+            .members
+            .iter()
+            .map(|(name, member)| (name.as_str(), member))
+            .collect();
+        members.sort_by_key(|(name, member)| (member.joined_at, *name));
+        members
+    }
+}
+
+/// Whether `name` matches the glob `pattern`, where `*` stands for any number of characters and
+/// `?` for exactly one.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` seen, and of the character in `name` it was matched up to.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // On a mismatch, have the last `*` swallow one more character and try again.
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
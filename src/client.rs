@@ -1,40 +1,104 @@
 //! Simple chat client.
 
-use std::{io, net::SocketAddr};
+use std::{collections::VecDeque, convert::TryFrom, io, net::SocketAddr, path::Path};
 
 use futures::{SinkExt, StreamExt};
+use serde::de;
 use thiserror::Error;
-use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, rustls::ServerName};
 
-use crate::codec::{ChatCodec, ChatCodecError};
+use crate::{
+    codec::{ChatCodec, ChatCodecError},
+    command::Verb,
+    json::{self, Event, Request},
+    reply::{self, ErrorCode, ErrorReply, ListReply, NamesReply, WhoReply},
+    tls::{self, TlsError},
+};
 
 #[derive(Debug, Error)]
 pub enum ClientError {
     #[error("failed to connect to server at address `{0}`")]
     ConnectToServer(SocketAddr, #[source] io::Error),
+    #[error("failed to load tls certificate authority")]
+    LoadTls(#[source] TlsError),
+    #[error("invalid server name `{0}`")]
+    InvalidServerName(String),
+    #[error("failed tls handshake with server at address `{0}`")]
+    TlsHandshake(SocketAddr, #[source] io::Error),
     #[error("failed to send message to server")]
     SendMessage(#[source] ChatCodecError),
     #[error("failed to receive message from server")]
     RecvMessage(#[source] ChatCodecError),
     #[error("connection to server closed")]
     ConnectionClosed,
+    #[error("server replied with an error")]
+    Server(#[source] ErrorReply),
+    #[error("invalid event `{0}` from server")]
+    InvalidEvent(String, #[source] serde_json::Error),
 }
 
 /// A basic chat client, made to communicate with [`crate::server::Server`].
 ///
 /// This is mostly used in internal testing, and is a simple wrapper around [`ChatCodec`].
-pub struct Client {
-    socket: ChatCodec<TcpStream>,
+pub struct Client<S = TcpStream> {
+    socket: ChatCodec<S>,
+    /// Messages received while waiting for the reply to a query, to be returned by
+    /// [`Client::recv`].
+    pending: VecDeque<String>,
 }
 
 impl Client {
     /// Creates a new [`Client`] connected to `server_addr`.
     pub async fn new(server_addr: &SocketAddr) -> Result<Self, ClientError> {
+        let stream = Self::connect(server_addr).await?;
+        Ok(Self::from_stream(stream))
+    }
+
+    async fn connect(server_addr: &SocketAddr) -> Result<TcpStream, ClientError> {
         let stream = TcpStream::connect(server_addr)
             .await
             .map_err(|e| ClientError::ConnectToServer(*server_addr, e))?;
-        let chat = ChatCodec::new(stream);
-        Ok(Self { socket: chat })
+        // Like the server, we don't want our (small) lines held back by Nagle's algorithm, which
+        // stalls a line sent right after another until the server's delayed ACK comes.
+        stream
+            .set_nodelay(true)
+            .map_err(|e| ClientError::ConnectToServer(*server_addr, e))?;
+        Ok(stream)
+    }
+}
+
+impl Client<TlsStream<TcpStream>> {
+    /// Creates a new [`Client`] connected to `server_addr` over TLS.
+    ///
+    /// The server must present a certificate for `server_name`, issued by one of the PEM encoded
+    /// certificate authorities in `ca_path`.
+    pub async fn new_tls(
+        server_addr: &SocketAddr,
+        server_name: &str,
+        ca_path: &Path,
+    ) -> Result<Self, ClientError> {
+        let connector = tls::connector(ca_path).map_err(ClientError::LoadTls)?;
+        let name = ServerName::try_from(server_name)
+            .map_err(|_| ClientError::InvalidServerName(server_name.into()))?;
+        let stream = Client::connect(server_addr).await?;
+        let stream = connector
+            .connect(name, stream)
+            .await
+            .map_err(|e| ClientError::TlsHandshake(*server_addr, e))?;
+        Ok(Self::from_stream(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    fn from_stream(stream: S) -> Self {
+        Self {
+            socket: ChatCodec::new(stream),
+            pending: VecDeque::new(),
+        }
     }
 
     /// Sends a message to the server.
@@ -46,16 +110,183 @@ impl Client {
     }
 
     /// Receives a message from the server.
+    ///
+    /// Error replies from the server are returned as [`ClientError::Server`]. Pings from the
+    /// server are answered right away, and never returned.
     pub async fn recv(&mut self) -> Result<String, ClientError> {
-        match self.socket.next().await {
-            Some(Ok(msg)) => Ok(msg),
-            Some(Err(e)) => Err(ClientError::RecvMessage(e)),
-            None => Err(ClientError::ConnectionClosed),
+        match self.pending.pop_front() {
+            Some(msg) => Ok(msg),
+            None => self.recv_line().await,
+        }
+    }
+
+    /// Asks the server for the members of `channel`, or of the current channel if `None`, in the
+    /// order they joined it.
+    pub async fn names(&mut self, channel: Option<&str>) -> Result<Vec<String>, ClientError> {
+        let cmd = match channel {
+            Some(channel) => format!("{} {}", Verb::Names, channel),
+            None => Verb::Names.to_string(),
+        };
+        self.send(&cmd).await?;
+        let replies = self.recv_reply(Verb::Names, NamesReply::parse).await?;
+        Ok(replies.into_iter().map(|reply| reply.user).collect())
+    }
+
+    /// Asks the server about `user`, getting a [`WhoReply`] for each channel they are in.
+    pub async fn who(&mut self, user: &str) -> Result<Vec<WhoReply>, ClientError> {
+        self.send(&format!("{} {}", Verb::Who, user)).await?;
+        self.recv_reply(Verb::Who, WhoReply::parse).await
+    }
+
+    /// Asks the server for the channels whose name matches the glob `pattern`, or for every
+    /// channel if `None`, ordered by name.
+    pub async fn list(&mut self, pattern: Option<&str>) -> Result<Vec<ListReply>, ClientError> {
+        let cmd = match pattern {
+            Some(pattern) => format!("{} {}", Verb::List, pattern),
+            None => Verb::List.to_string(),
+        };
+        self.send(&cmd).await?;
+        self.recv_reply(Verb::List, ListReply::parse).await
+    }
+
+    /// Registers the user's name with `password`, so nobody else can use it.
+    pub async fn register(&mut self, password: &str) -> Result<(), ClientError> {
+        self.send(&format!("{} {}", Verb::Register, password))
+            .await?;
+        self.recv_ack(Verb::Register).await
+    }
+
+    /// Identifies as the owner of the registered name `user`.
+    ///
+    /// This may be done before joining, to join under that name.
+    pub async fn identify(&mut self, user: &str, password: &str) -> Result<(), ClientError> {
+        self.send(&format!("{} {} {}", Verb::Identify, user, password))
+            .await?;
+        self.recv_ack(Verb::Identify).await
+    }
+
+    /// Receives the line acknowledging a `verb` command.
+    ///
+    /// Any other message received in the meantime is kept for [`Client::recv`].
+    async fn recv_ack(&mut self, verb: Verb) -> Result<(), ClientError> {
+        let ack = format!("{} ", verb);
+        loop {
+            let msg = self.recv_line().await?;
+            if msg.starts_with(&ack) {
+                return Ok(());
+            }
+            self.pending.push_back(msg);
+        }
+    }
+
+    /// Receives the reply to a `verb` query, parsing each of its lines with `parse`, up to its
+    /// end line.
+    ///
+    /// Any other message received in the meantime is kept for [`Client::recv`].
+    async fn recv_reply<T>(
+        &mut self,
+        verb: Verb,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<Vec<T>, ClientError> {
+        let end = reply::end_line(verb, "");
+        let mut replies = Vec::new();
+        loop {
+            let msg = self.recv_line().await?;
+            if let Some(reply) = parse(&msg) {
+                replies.push(reply);
+            } else if msg.starts_with(&end) {
+                return Ok(replies);
+            } else {
+                self.pending.push_back(msg);
+            }
+        }
+    }
+
+    /// Receives the next line from the server, answering any `PING` on the way.
+    async fn recv_line(&mut self) -> Result<String, ClientError> {
+        loop {
+            let msg = match self.socket.next().await {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => return Err(ClientError::RecvMessage(e)),
+                None => return Err(ClientError::ConnectionClosed),
+            };
+            if let Some(token) = ping_token(&msg) {
+                let pong = format!("{}{}", Verb::Pong, token);
+                self.send(&pong).await?;
+                continue;
+            }
+            return match ErrorReply::parse(&msg) {
+                Some(reply) => Err(ClientError::Server(reply)),
+                None => Ok(msg),
+            };
+        }
+    }
+
+    /// Switches to the JSON-lines protocol, see [`json`].
+    ///
+    /// This must be done before anything else is sent. From then on, the client talks with
+    /// [`Client::send_request`] and [`Client::recv_event`].
+    pub async fn use_json(&mut self) -> Result<(), ClientError> {
+        self.send(&format!("{} json", json::PROTO)).await?;
+        loop {
+            let line = self.recv_line().await?;
+            match self.parse_event(line).await? {
+                Some(Event::Proto { .. }) => return Ok(()),
+                Some(event) => self.pending.push_back(event.to_line()),
+                None => (),
+            }
+        }
+    }
+
+    /// Sends a request to the server, once switched to JSON with [`Client::use_json`].
+    pub async fn send_request(&mut self, request: &Request) -> Result<(), ClientError> {
+        self.send(&request.to_line()).await
+    }
+
+    /// Receives an event from the server, once switched to JSON with [`Client::use_json`].
+    ///
+    /// Error events are returned as [`ClientError::Server`]. Pings from the server are answered
+    /// right away, and never returned.
+    pub async fn recv_event(&mut self) -> Result<Event, ClientError> {
+        loop {
+            let line = self.recv().await?;
+            if let Some(event) = self.parse_event(line).await? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Parses an event line, answering it right away if it's a ping, in which case `None` is
+    /// returned.
+    async fn parse_event(&mut self, line: String) -> Result<Option<Event>, ClientError> {
+        let event = match Event::from_line(&line) {
+            Ok(event) => event,
+            Err(e) => return Err(ClientError::InvalidEvent(line, e)),
+        };
+        match event {
+            Event::Ping => {
+                self.send_request(&Request::Pong { token: None }).await?;
+                Ok(None)
+            }
+            Event::Error { code, text, .. } => match ErrorCode::from_code(code) {
+                Some(code) => Err(ClientError::Server(ErrorReply::new(code, text))),
+                None => {
+                    let e = de::Error::custom(format!("unknown error code {}", code));
+                    Err(ClientError::InvalidEvent(line, e))
+                }
+            },
+            event => Ok(Some(event)),
         }
     }
 
     /// Consumes the client, returning the inner [`ChatCodec`]
-    pub fn into_inner(self) -> ChatCodec<TcpStream> {
+    pub fn into_inner(self) -> ChatCodec<S> {
         self.socket
     }
 }
+
+/// The rest of `msg` after the verb, including the space, if it's a `PING` from the server.
+fn ping_token(msg: &str) -> Option<&str> {
+    let rest = msg.strip_prefix(Verb::Ping.as_str())?;
+    Some(rest).filter(|rest| rest.is_empty() || rest.starts_with(' '))
+}
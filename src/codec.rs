@@ -1,29 +1,130 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+};
 
+use bytes::{Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::codec::{Decoder, Encoder, Framed, LinesCodec};
 
 pub use tokio_util::codec::LinesCodecError as ChatCodecError;
 
-/// A wrapper around [`LinesCodec`] that enforces a `510` character limit for every line.
+/// Maximum length of a line, in bytes, unless another one is given to
+/// [`ChatCodec::with_max_length`].
+pub const DEFAULT_LENGTH_LIMIT: usize = 20_000;
+
+/// A line already encoded for the wire, trailing newline included.
+///
+/// Cloning a frame only bumps a reference count, so a message broadcast to a channel is
+/// rendered once and the same bytes are written to every member.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame(Bytes);
+
+impl Frame {
+    /// The encoded line, trailing newline included.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<String> for Frame {
+    fn from(mut line: String) -> Self {
+        line.push('\n');
+        Self(line.into())
+    }
+}
+
+impl From<&str> for Frame {
+    fn from(line: &str) -> Self {
+        let mut frame = BytesMut::with_capacity(line.len() + 1);
+        frame.extend_from_slice(line.as_bytes());
+        frame.extend_from_slice(b"\n");
+        Self(frame.freeze())
+    }
+}
+
+/// A [`LinesCodec`] that also writes [`Frame`]s, as they are.
+///
+/// Writing a line holding a line break is refused, rather than letting it pass for more lines.
+#[derive(Debug, Clone)]
+pub struct LineCodec(LinesCodec);
+
+impl LineCodec {
+    /// Creates a codec decoding lines of at most `max_length` bytes.
+    pub fn new_with_max_length(max_length: usize) -> Self {
+        Self(LinesCodec::new_with_max_length(max_length))
+    }
+}
+
+impl Decoder for LineCodec {
+    type Item = String;
+    type Error = ChatCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, ChatCodecError> {
+        self.0.decode(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, ChatCodecError> {
+        self.0.decode_eof(src)
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for LineCodec {
+    type Error = ChatCodecError;
+
+    fn encode(&mut self, line: T, dst: &mut BytesMut) -> Result<(), ChatCodecError> {
+        check_line(line.as_ref().as_bytes())?;
+        self.0.encode(line, dst)
+    }
+}
+
+impl Encoder<Frame> for LineCodec {
+    type Error = ChatCodecError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), ChatCodecError> {
+        check_line(&frame.0[..frame.0.len() - 1])?;
+        dst.extend_from_slice(&frame.0);
+        Ok(())
+    }
+}
+
+/// Fails if `line` holds a line break, which would end it early, other than the carriage return
+/// ending IRC lines.
+fn check_line(line: &[u8]) -> Result<(), ChatCodecError> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    match line.iter().any(|&b| b == b'\n' || b == b'\r') {
+        true => Err(io::Error::new(io::ErrorKind::InvalidInput, "line holds a line break").into()),
+        false => Ok(()),
+    }
+}
+
+/// A wrapper around [`LineCodec`] that enforces a length limit for every line.
 ///
 /// This is helpful to avoid DoS type attacks from users.
-pub struct ChatCodec<S>(Framed<S, LinesCodec>);
+pub struct ChatCodec<S>(Framed<S, LineCodec>);
 
 impl<S: AsyncRead + AsyncWrite> ChatCodec<S> {
-    const LENGTH_LIMIT: usize = 20_000;
-
-    /// Creates a new instace of [`ChatCodec`].
+    /// Creates a new instace of [`ChatCodec`], with the [`DEFAULT_LENGTH_LIMIT`].
     pub fn new(stream: S) -> Self {
+        Self::with_max_length(stream, DEFAULT_LENGTH_LIMIT)
+    }
+
+    /// Creates a new instace of [`ChatCodec`], limiting lines to `max_length` bytes.
+    pub fn with_max_length(stream: S, max_length: usize) -> Self {
         Self(Framed::new(
             stream,
-            LinesCodec::new_with_max_length(Self::LENGTH_LIMIT),
+            LineCodec::new_with_max_length(max_length),
         ))
     }
+
+    /// Consumes the codec, returning the [`Framed`] it wraps, e.g. to split it in halves.
+    pub fn into_framed(self) -> Framed<S, LineCodec> {
+        self.0
+    }
 }
 
 impl<S> Deref for ChatCodec<S> {
-    type Target = Framed<S, LinesCodec>;
+    type Target = Framed<S, LineCodec>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
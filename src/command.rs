@@ -0,0 +1,595 @@
+//! Commands clients send to the [`Server`](crate::server::Server).
+
+use std::fmt;
+
+use thiserror::Error;
+
+/// Maximum length of channel and user names, unless the server is configured otherwise.
+pub const DEFAULT_MAX_NAME_LENGTH: usize = 20;
+
+/// Error type for [`Command::parse`] and [`Command::parse_join`].
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("empty command")]
+    Empty,
+    #[error("unknown verb `{0}`")]
+    UnknownVerb(String),
+    #[error("expected a `JOIN` command, got `{0}`")]
+    JoinRequired(Verb),
+    #[error("name `{0}` is longer than {1} characters")]
+    NameTooLong(String, usize),
+    #[error("name `{0}` contains invalid characters")]
+    InvalidName(String),
+    #[error("text contains line breaks or other control characters")]
+    InvalidText,
+    #[error("`{0}` is not a valid number")]
+    InvalidNumber(String),
+    #[error("unknown mode `{0}`")]
+    UnknownMode(char),
+    #[error("mode `{0}` requires an argument")]
+    MissingModeArgument(char),
+    #[error("invalid JSON request: {0}")]
+    InvalidJson(String),
+    #[error("unknown wire format `{0}`")]
+    UnknownFormat(String),
+    #[error("wrong number of arguments for `{verb}`: expected {expected}, got {found}")]
+    WrongArgumentCount {
+        verb: Verb,
+        expected: &'static str,
+        found: usize,
+    },
+}
+
+/// The verb, i.e. the first term, of a [`Command`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verb {
+    Join,
+    Part,
+    Say,
+    Msg,
+    Quit,
+    Ping,
+    Pong,
+    History,
+    Names,
+    Who,
+    List,
+    Topic,
+    Nick,
+    Register,
+    Identify,
+    Kick,
+    Ban,
+    Unban,
+    Op,
+    Deop,
+    Mode,
+    Invite,
+}
+
+impl Verb {
+    /// Looks up the verb for `term`, if it is one.
+    ///
+    /// Verbs are case sensitive, so `join` is just a word, not a command.
+    pub fn from_term(term: &str) -> Option<Self> {
+        match term {
+            "JOIN" => Some(Self::Join),
+            "PART" => Some(Self::Part),
+            "SAY" => Some(Self::Say),
+            "MSG" => Some(Self::Msg),
+            "QUIT" => Some(Self::Quit),
+            "PING" => Some(Self::Ping),
+            "PONG" => Some(Self::Pong),
+            "HISTORY" => Some(Self::History),
+            "NAMES" => Some(Self::Names),
+            "WHO" => Some(Self::Who),
+            "LIST" => Some(Self::List),
+            "TOPIC" => Some(Self::Topic),
+            "NICK" => Some(Self::Nick),
+            "REGISTER" => Some(Self::Register),
+            "IDENTIFY" => Some(Self::Identify),
+            "KICK" => Some(Self::Kick),
+            "BAN" => Some(Self::Ban),
+            "UNBAN" => Some(Self::Unban),
+            "OP" => Some(Self::Op),
+            "DEOP" => Some(Self::Deop),
+            "MODE" => Some(Self::Mode),
+            "INVITE" => Some(Self::Invite),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Join => "JOIN",
+            Self::Part => "PART",
+            Self::Say => "SAY",
+            Self::Msg => "MSG",
+            Self::Quit => "QUIT",
+            Self::Ping => "PING",
+            Self::Pong => "PONG",
+            Self::History => "HISTORY",
+            Self::Names => "NAMES",
+            Self::Who => "WHO",
+            Self::List => "LIST",
+            Self::Topic => "TOPIC",
+            Self::Nick => "NICK",
+            Self::Register => "REGISTER",
+            Self::Identify => "IDENTIFY",
+            Self::Kick => "KICK",
+            Self::Ban => "BAN",
+            Self::Unban => "UNBAN",
+            Self::Op => "OP",
+            Self::Deop => "DEOP",
+            Self::Mode => "MODE",
+            Self::Invite => "INVITE",
+        }
+    }
+}
+
+impl fmt::Display for Verb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A change to the [`ChannelModes`](crate::channel::ChannelModes) of a channel, or to a member's
+/// status in it, as given to `MODE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModeChange {
+    /// `+i`/`-i`, making the channel invite-only or not.
+    InviteOnly(bool),
+    /// `+k KEY`/`-k`, setting or removing the key needed to join the channel.
+    Key(Option<String>),
+    /// `+l LIMIT`/`-l`, setting or removing the maximum number of members.
+    Limit(Option<usize>),
+    /// `+m`/`-m`, making the channel moderated or not.
+    Moderated(bool),
+    /// `+v USERNAME`/`-v USERNAME`, letting a member speak while the channel is moderated, or
+    /// not.
+    Voice(String, bool),
+}
+
+impl ModeChange {
+    /// Parses the changes in `modes`, e.g. `+ik-m`, taking the arguments of those that need one
+    /// from `args`, in order.
+    ///
+    /// Modes are added unless a `-` comes before them.
+    pub fn parse_all<'a>(
+        modes: &str,
+        args: &mut impl Iterator<Item = &'a str>,
+        max_name_length: usize,
+    ) -> Result<Vec<Self>, ParseError> {
+        let mut changes = Vec::new();
+        let mut on = true;
+        for mode in modes.chars() {
+            let mut arg = || args.next().ok_or(ParseError::MissingModeArgument(mode));
+            let change = match (mode, on) {
+                ('+', _) | ('-', _) => {
+                    on = mode == '+';
+                    continue;
+                }
+                ('i', on) => Self::InviteOnly(on),
+                ('k', true) => Self::Key(Some(validate_name(arg()?, usize::MAX)?.to_owned())),
+                ('k', false) => Self::Key(None),
+                ('l', true) => {
+                    let limit = arg()?;
+                    let limit = limit
+                        .parse()
+                        .map_err(|_| ParseError::InvalidNumber(limit.to_owned()))?;
+                    Self::Limit(Some(limit))
+                }
+                ('l', false) => Self::Limit(None),
+                ('m', on) => Self::Moderated(on),
+                ('v', on) => Self::Voice(validate_name(arg()?, max_name_length)?.to_owned(), on),
+                (mode, _) => return Err(ParseError::UnknownMode(mode)),
+            };
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+
+    /// The mode letter, along with whether it's added or removed, e.g. `+k`.
+    pub fn flag(&self) -> &'static str {
+        match self {
+            Self::InviteOnly(true) => "+i",
+            Self::InviteOnly(false) => "-i",
+            Self::Key(Some(_)) => "+k",
+            Self::Key(None) => "-k",
+            Self::Limit(Some(_)) => "+l",
+            Self::Limit(None) => "-l",
+            Self::Moderated(true) => "+m",
+            Self::Moderated(false) => "-m",
+            Self::Voice(_, true) => "+v",
+            Self::Voice(_, false) => "-v",
+        }
+    }
+
+    /// The argument that goes along with the flag, if the mode takes one.
+    pub fn argument(&self) -> Option<String> {
+        match self {
+            Self::Key(key) => key.clone(),
+            Self::Limit(limit) => limit.map(|limit| limit.to_string()),
+            Self::Voice(user, _) => Some(user.clone()),
+            Self::InviteOnly(_) | Self::Moderated(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ModeChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.flag())?;
+        match self.argument() {
+            Some(arg) => write!(f, " {}", arg),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A single line sent by a client, parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `JOIN CHANNEL [USERNAME [KEY]]` in the initial handshake, and `JOIN CHANNEL [KEY]` once
+    /// the user has a name, which may still be repeated as in `JOIN CHANNEL USERNAME KEY`.
+    Join {
+        channel: String,
+        user: Option<String>,
+        key: Option<String>,
+    },
+    /// `PART [CHANNEL]`, leaving the current channel if none is given.
+    Part { channel: Option<String> },
+    /// `SAY CHANNEL TEXT`, sending a message to a specific channel.
+    Say { channel: String, text: String },
+    /// `MSG USERNAME TEXT`, sending a message to a single user.
+    Msg { user: String, text: String },
+    /// `QUIT [REASON]`, leaving every channel and closing the connection.
+    Quit { reason: Option<String> },
+    /// `PING [TOKEN]`, which the server answers with `PONG [TOKEN]`.
+    Ping { token: Option<String> },
+    /// `PONG [TOKEN]`, answering a `PING` from the server, see [`keepalive`](crate::keepalive).
+    Pong { token: Option<String> },
+    /// `HISTORY CHANNEL COUNT`, which the server answers with up to `COUNT` of the last messages
+    /// said in the channel, followed by `END HISTORY CHANNEL`.
+    History { channel: String, count: usize },
+    /// `NAMES [CHANNEL]`, listing the members of a channel, the current one if none is given.
+    Names { channel: Option<String> },
+    /// `WHO USERNAME`, describing a user.
+    Who { user: String },
+    /// `LIST [PATTERN]`, listing the channels whose name matches the glob `PATTERN`, or every
+    /// channel if none is given.
+    List { pattern: Option<String> },
+    /// `TOPIC CHANNEL [TEXT]`, setting the topic of a channel to `TEXT`, or asking for it if no
+    /// text is given.
+    Topic {
+        channel: String,
+        text: Option<String>,
+    },
+    /// `NICK USERNAME`, changing the user's name.
+    Nick { user: String },
+    /// `REGISTER PASSWORD`, registering the user's name with a password, which the server
+    /// answers with `REGISTER USERNAME`.
+    Register { password: String },
+    /// `IDENTIFY USERNAME PASSWORD`, proving the user owns a registered name, which the server
+    /// answers with `IDENTIFY USERNAME`. This may be sent before the initial handshake, so the
+    /// user can join under that name.
+    Identify { user: String, password: String },
+    /// `KICK USERNAME [REASON]`, removing a user from the current channel.
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    /// `BAN MASK`, keeping the users matching `MASK` from joining the current channel, see
+    /// [`channel`](crate::channel) for the form masks take.
+    Ban { mask: String },
+    /// `UNBAN MASK`, lifting a ban set with `BAN`.
+    Unban { mask: String },
+    /// `OP USERNAME`, making a user an operator of the current channel.
+    Op { user: String },
+    /// `DEOP USERNAME`, taking operator status away from a user.
+    Deop { user: String },
+    /// `MODE [CHANGES [ARGS...]]`, changing the modes of the current channel, e.g.
+    /// `MODE +kl secret 10`, or asking for them if no changes are given.
+    Mode { changes: Vec<ModeChange> },
+    /// `INVITE USERNAME`, inviting a user to the current channel, which the server answers with
+    /// `INVITE USERNAME CHANNEL`.
+    Invite { user: String },
+    /// Any line that doesn't start with a verb, sent as a message to the current channel.
+    Text(String),
+}
+
+impl Command {
+    /// Parses a line sent by a client after the initial handshake.
+    ///
+    /// Lines whose first term is a [`Verb`] are parsed as that command, and must be well-formed.
+    /// Every other line is plain [`Command::Text`]. Names may be at most `max_name_length`
+    /// characters long. A line may not hold a carriage return, which would break it in two for
+    /// the IRC clients it's relayed to.
+    pub fn parse(line: &str, max_name_length: usize) -> Result<Self, ParseError> {
+        if line.contains('\r') {
+            return Err(ParseError::InvalidText);
+        }
+        let (head, rest) = split_term(line);
+        let verb = match Verb::from_term(head) {
+            Some(verb) => verb,
+            None => return Ok(Self::Text(line.to_owned())),
+        };
+
+        let cmd = match verb {
+            Verb::Join => {
+                let args: Vec<&str> = rest.split(' ').filter(|a| !a.is_empty()).collect();
+                match args[..] {
+                    // The user already has a name, so a lone argument after the channel is its
+                    // key.
+                    [channel, key] => Self::Join {
+                        channel: validate_name(channel, max_name_length)?.to_owned(),
+                        user: None,
+                        key: Some(validate_name(key, usize::MAX)?.to_owned()),
+                    },
+                    _ => {
+                        let (channel, user, key) = join_args(rest, 1, "1 to 3", max_name_length)?;
+                        Self::Join { channel, user, key }
+                    }
+                }
+            }
+            Verb::Part => Self::Part {
+                channel: names(verb, rest, 0, 1, "0 or 1", max_name_length)?.pop(),
+            },
+            Verb::Say => {
+                let (channel, text) = name_and_text(verb, rest, max_name_length)?;
+                Self::Say { channel, text }
+            }
+            Verb::Msg => {
+                let (user, text) = name_and_text(verb, rest, max_name_length)?;
+                Self::Msg { user, text }
+            }
+            Verb::Quit => Self::Quit {
+                reason: non_empty(rest),
+            },
+            Verb::Ping => Self::Ping {
+                token: non_empty(rest),
+            },
+            Verb::Pong => Self::Pong {
+                token: non_empty(rest),
+            },
+            Verb::History => {
+                let args: Vec<&str> = rest.split(' ').filter(|a| !a.is_empty()).collect();
+                if args.len() != 2 {
+                    return Err(ParseError::WrongArgumentCount {
+                        verb,
+                        expected: "2",
+                        found: args.len(),
+                    });
+                }
+                Self::History {
+                    channel: validate_name(args[0], max_name_length)?.to_owned(),
+                    count: args[1]
+                        .parse()
+                        .map_err(|_| ParseError::InvalidNumber(args[1].to_owned()))?,
+                }
+            }
+            Verb::Names => Self::Names {
+                channel: names(verb, rest, 0, 1, "0 or 1", max_name_length)?.pop(),
+            },
+            Verb::Who => Self::Who {
+                user: names(verb, rest, 1, 1, "1", max_name_length)?.remove(0),
+            },
+            Verb::Nick => Self::Nick {
+                user: names(verb, rest, 1, 1, "1", max_name_length)?.remove(0),
+            },
+            Verb::Register => match non_empty(rest) {
+                Some(password) => Self::Register { password },
+                None => {
+                    return Err(ParseError::WrongArgumentCount {
+                        verb,
+                        expected: "1",
+                        found: 0,
+                    })
+                }
+            },
+            Verb::Identify => {
+                let (user, password) = name_and_text(verb, rest, max_name_length)?;
+                Self::Identify { user, password }
+            }
+            Verb::Kick => {
+                let (user, reason) = split_term(rest);
+                if user.is_empty() {
+                    return Err(ParseError::WrongArgumentCount {
+                        verb,
+                        expected: "1 or 2",
+                        found: 0,
+                    });
+                }
+                Self::Kick {
+                    user: validate_name(user, max_name_length)?.to_owned(),
+                    reason: non_empty(reason),
+                }
+            }
+            // Masks may be longer than the names they match, e.g. `joe@127.0.0.1`.
+            Verb::Ban => Self::Ban {
+                mask: names(verb, rest, 1, 1, "1", usize::MAX)?.remove(0),
+            },
+            Verb::Unban => Self::Unban {
+                mask: names(verb, rest, 1, 1, "1", usize::MAX)?.remove(0),
+            },
+            Verb::Op => Self::Op {
+                user: names(verb, rest, 1, 1, "1", max_name_length)?.remove(0),
+            },
+            Verb::Deop => Self::Deop {
+                user: names(verb, rest, 1, 1, "1", max_name_length)?.remove(0),
+            },
+            Verb::Mode => {
+                let (modes, args) = split_term(rest);
+                let mut args = args.split(' ').filter(|a| !a.is_empty());
+                let changes = ModeChange::parse_all(modes, &mut args, max_name_length)?;
+                let extra = args.count();
+                if extra > 0 {
+                    return Err(ParseError::WrongArgumentCount {
+                        verb,
+                        expected: "one for each mode taking one",
+                        found: changes.iter().filter(|c| c.argument().is_some()).count() + extra,
+                    });
+                }
+                Self::Mode { changes }
+            }
+            Verb::Invite => Self::Invite {
+                user: names(verb, rest, 1, 1, "1", max_name_length)?.remove(0),
+            },
+            Verb::List => Self::List {
+                pattern: names(verb, rest, 0, 1, "0 or 1", max_name_length)?.pop(),
+            },
+            Verb::Topic => {
+                let (channel, text) = split_term(rest);
+                if channel.is_empty() {
+                    return Err(ParseError::WrongArgumentCount {
+                        verb,
+                        expected: "1 or 2",
+                        found: 0,
+                    });
+                }
+                Self::Topic {
+                    channel: validate_name(channel, max_name_length)?.to_owned(),
+                    text: non_empty(text),
+                }
+            }
+        };
+
+        Ok(cmd)
+    }
+
+    /// Parses and validates the join command a client must begin their connection with.
+    ///
+    /// Users are expected to begin their connection to the server with a message specifying the
+    /// channel they'd like to join, and their username. To do this, they send a command in the
+    /// form `JOIN CHANNEL USERNAME`. If user `bernardo` wanted to join channel `rust`, for
+    /// example, he would begin his connection with `JOIN rust bernardo`.
+    ///
+    /// A number of restrictions exist on this initial string, which this function sets out to
+    /// validate, namely they are:
+    /// 1. The first term of the string _must_ be "JOIN".
+    /// 2. Channel and user names are not allowed any whitespace or control characters.
+    /// 3. Channel and user names may not be longer than `max_name_length` characters.
+    /// 4. Only three terms, `JOIN`, `channel_name`, and `username` may be given, followed by the
+    ///    channel's key if it has one, and no more.
+    ///
+    /// On success this returns the channel and user names, and the key, in that order.
+    pub fn parse_join(
+        line: &str,
+        max_name_length: usize,
+    ) -> Result<(String, String, Option<String>), ParseError> {
+        let (head, rest) = split_term(line);
+        match Verb::from_term(head) {
+            Some(Verb::Join) => (),
+            Some(verb) => return Err(ParseError::JoinRequired(verb)),
+            None if head.is_empty() => return Err(ParseError::Empty),
+            None => return Err(ParseError::UnknownVerb(head.to_owned())),
+        }
+
+        let (channel, user, key) = join_args(rest, 2, "2 or 3", max_name_length)?;
+        Ok((channel, user.unwrap(), key))
+    }
+}
+
+/// Checks whether `name` is acceptable as a channel or user name, at most `max_length`
+/// characters long.
+pub fn validate_name(name: &str, max_length: usize) -> Result<&str, ParseError> {
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err(ParseError::InvalidName(name.to_owned()))
+    } else if name.chars().count() > max_length {
+        Err(ParseError::NameTooLong(name.to_owned(), max_length))
+    } else {
+        Ok(name)
+    }
+}
+
+/// Checks whether `text` is acceptable as free text, e.g. a message or a reason, which may not
+/// hold any control characters, line breaks included.
+pub fn validate_text(text: &str) -> Result<&str, ParseError> {
+    if text.chars().any(char::is_control) {
+        Err(ParseError::InvalidText)
+    } else {
+        Ok(text)
+    }
+}
+
+/// Splits the first space-separated term off of `line`, returning it and the remainder.
+fn split_term(line: &str) -> (&str, &str) {
+    let line = line.trim_start_matches(' ');
+    match line.find(' ') {
+        Some(idx) => (&line[..idx], line[idx..].trim_start_matches(' ')),
+        None => (line, ""),
+    }
+}
+
+/// Returns `text`, unless it's empty.
+fn non_empty(text: &str) -> Option<String> {
+    Some(text).filter(|t| !t.is_empty()).map(str::to_owned)
+}
+
+/// Parses a name followed by some text out of `args`, as taken by `SAY`, `MSG` and `IDENTIFY`.
+fn name_and_text(
+    verb: Verb,
+    args: &str,
+    max_length: usize,
+) -> Result<(String, String), ParseError> {
+    let (name, text) = split_term(args);
+    if name.is_empty() || text.is_empty() {
+        let found = [name, text].iter().filter(|a| !a.is_empty()).count();
+        return Err(ParseError::WrongArgumentCount {
+            verb,
+            expected: "2",
+            found,
+        });
+    }
+    Ok((validate_name(name, max_length)?.to_owned(), text.to_owned()))
+}
+
+/// Parses the channel name, user name and key given to `JOIN`, at least `min` of them.
+///
+/// Keys aren't names, so they may be longer.
+fn join_args(
+    args: &str,
+    min: usize,
+    expected: &'static str,
+    max_length: usize,
+) -> Result<(String, Option<String>, Option<String>), ParseError> {
+    let args: Vec<&str> = args.split(' ').filter(|a| !a.is_empty()).collect();
+    if args.len() < min || args.len() > 3 {
+        return Err(ParseError::WrongArgumentCount {
+            verb: Verb::Join,
+            expected,
+            found: args.len(),
+        });
+    }
+    let channel = validate_name(args[0], max_length)?.to_owned();
+    let user = match args.get(1) {
+        Some(user) => Some(validate_name(user, max_length)?.to_owned()),
+        None => None,
+    };
+    let key = match args.get(2) {
+        Some(key) => Some(validate_name(key, usize::MAX)?.to_owned()),
+        None => None,
+    };
+    Ok((channel, user, key))
+}
+
+/// Parses between `min` and `max` space-separated names out of `args`.
+fn names(
+    verb: Verb,
+    args: &str,
+    min: usize,
+    max: usize,
+    expected: &'static str,
+    max_length: usize,
+) -> Result<Vec<String>, ParseError> {
+    let args: Vec<&str> = args.split(' ').filter(|a| !a.is_empty()).collect();
+    if args.len() < min || args.len() > max {
+        return Err(ParseError::WrongArgumentCount {
+            verb,
+            expected,
+            found: args.len(),
+        });
+    }
+    args.into_iter()
+        .map(|a| validate_name(a, max_length).map(str::to_owned))
+        .collect()
+}
@@ -0,0 +1,381 @@
+//! Settings for the [`Server`](crate::server::Server).
+//!
+//! A [`ServerConfig`] is usually loaded from a TOML file with [`ServerConfig::load`], in which
+//! every field is optional and falls back to its default, e.g.
+//!
+//! ```toml
+//! bind = ["127.0.0.1:1234", "[::1]:1234"]
+//! irc_bind = ["0.0.0.0:6667"]
+//! channel_capacity = 500
+//! slow_consumer = "buffer"
+//! outbound_queue = 5000
+//! max_clients = 10000
+//! max_clients_per_ip = 20
+//! ping_interval_ms = 60000
+//! log_level = "info"
+//! admins = ["alice"]
+//!
+//! [history]
+//! replay = 50
+//! channels = 5000
+//! file = "/var/lib/chat/history.log"
+//!
+//! [accounts]
+//! file = "/var/lib/chat/accounts"
+//!
+//! [rate_limit]
+//! per_second = 5
+//! burst = 10
+//! action = "disconnect"
+//!
+//! [tls]
+//! bind = ["0.0.0.0:1235"]
+//! cert = "/etc/chat/cert.pem"
+//! key = "/etc/chat/key.pem"
+//! ```
+
+use std::{
+    fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+use tracing::Level;
+
+use crate::{codec, command};
+
+/// Error type for [`ServerConfig::load`].
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config file `{0}`")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("invalid config file `{0}`")]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[error("invalid setting `{0}`, it must be greater than 0")]
+    Invalid(&'static str),
+}
+
+/// Everything about the [`Server`](crate::server::Server) that can be tuned without recompiling.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to accept clients of the native protocol on.
+    pub bind: Vec<SocketAddr>,
+    /// Addresses to accept IRC clients on.
+    pub irc_bind: Vec<SocketAddr>,
+    /// Addresses to accept WebSocket clients on.
+    pub websocket_bind: Vec<SocketAddr>,
+    /// Addresses to accept clients of the native protocol over TLS on, if any.
+    pub tls: Option<TlsConfig>,
+    /// Number of messages each channel holds before slow clients start missing them.
+    pub channel_capacity: usize,
+    /// Number of shards the channels are spread over, each behind its own lock. More shards let
+    /// more clients join and leave channels at once.
+    pub channel_shards: usize,
+    /// What happens to clients who fall behind on their channels.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Number of lines each client may have waiting to be written to them, with
+    /// [`SlowConsumerPolicy::Buffer`].
+    pub outbound_queue: usize,
+    /// Maximum length of a line sent by a client, in bytes.
+    pub max_line_length: usize,
+    /// Maximum length of channel and user names, in characters.
+    pub max_name_length: usize,
+    /// Message history of the channels, if it should be kept.
+    pub history: Option<HistoryConfig>,
+    /// Registered user names, if users may register them.
+    pub accounts: Option<AccountsConfig>,
+    /// How fast clients may send lines, if they are limited at all.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Users who are operators of every channel, and can't be banned from any.
+    ///
+    /// If accounts are enabled, users must have identified as one of these names to count.
+    pub admins: Vec<String>,
+    /// Maximum number of clients connected at once, across every listener.
+    pub max_clients: Option<usize>,
+    /// Maximum number of clients connected at once from the same IP address.
+    pub max_clients_per_ip: Option<usize>,
+    /// How long clients have to join, or register over IRC, once connected, in milliseconds.
+    pub handshake_timeout_ms: u64,
+    /// How long clients may stay silent before they are sent a `PING`, in milliseconds.
+    pub ping_interval_ms: u64,
+    /// How long clients have to answer a `PING`, in milliseconds, after which they are
+    /// disconnected. Any line counts as an answer.
+    pub ping_timeout_ms: u64,
+    /// How long clients have to receive their pending messages once the server shuts down, in
+    /// milliseconds, after which they are disconnected regardless.
+    pub shutdown_timeout_ms: u64,
+    /// The most verbose level of log messages to print.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub log_level: Level,
+}
+
+/// Settings for the TLS listeners, see [`ServerConfig::tls`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Addresses to accept TLS clients on.
+    pub bind: Vec<SocketAddr>,
+    /// PEM encoded certificate chain.
+    pub cert: PathBuf,
+    /// PEM encoded private key.
+    pub key: PathBuf,
+}
+
+/// Settings for the message history, see [`ServerConfig::history`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Number of messages kept for each channel.
+    pub capacity: usize,
+    /// Number of channels whose history is kept, the least recently used ones being forgotten.
+    pub channels: usize,
+    /// Number of messages replayed to users when they join a channel.
+    pub replay: usize,
+    /// File the history is appended to, and loaded from on startup, if any.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            channels: 10_000,
+            replay: 20,
+            file: None,
+        }
+    }
+}
+
+/// Settings for the registered user names, see [`ServerConfig::accounts`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// File the accounts are appended to, and loaded from on startup, if any.
+    pub file: Option<PathBuf>,
+    /// Whether registered names are refused to users who haven't identified with their password.
+    pub reserve_names: bool,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            reserve_names: true,
+        }
+    }
+}
+
+/// Settings for flood protection, see [`ServerConfig::rate_limit`].
+///
+/// Every connection gets a token bucket, refilled with `per_second` tokens every second and
+/// holding at most `burst` of them. Each line sent by the client takes a token, and those sent
+/// when there are none left are dealt with according to `action`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Number of lines clients may send every second, on average.
+    pub per_second: NonZeroU32,
+    /// Number of lines clients may send in a row, faster than `per_second`.
+    pub burst: NonZeroU32,
+    /// What happens to lines sent too fast.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub action: FloodAction,
+    /// Number of lines in a row that may be dropped before the client is disconnected, with
+    /// [`FloodAction::Disconnect`].
+    pub max_violations: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_second: NonZeroU32::new(5).unwrap(),
+            burst: NonZeroU32::new(10).unwrap(),
+            action: FloodAction::Drop,
+            max_violations: 10,
+        }
+    }
+}
+
+/// What happens to the lines a client sends faster than it's allowed, see [`RateLimitConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloodAction {
+    /// The line is held back until the client may send it, `delay`.
+    Delay,
+    /// The line is dropped, and the client told so, `drop`.
+    Drop,
+    /// The line is dropped, and the client is disconnected once too many lines in a row were,
+    /// `disconnect`.
+    Disconnect,
+}
+
+impl FromStr for FloodAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delay" => Ok(Self::Delay),
+            "drop" => Ok(Self::Drop),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!(
+                "unknown flood action `{}`, expected `delay`, `drop` or `disconnect`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FloodAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Delay => "delay",
+            Self::Drop => "drop",
+            Self::Disconnect => "disconnect",
+        })
+    }
+}
+
+/// What happens to clients who fall so far behind on a channel that they miss messages, see
+/// [`ServerConfig::channel_capacity`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// The client is told how many messages they missed, and carries on, `notify`.
+    Notify,
+    /// The client is told how many messages they missed once they get to them, and disconnected,
+    /// `disconnect`.
+    Disconnect,
+    /// Messages wait in a queue of [`ServerConfig::outbound_queue`] lines for the client to
+    /// catch up, and they are only missed once it's full, in which case the client is told like
+    /// with `notify`, `buffer`.
+    Buffer,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notify" => Ok(Self::Notify),
+            "disconnect" => Ok(Self::Disconnect),
+            "buffer" => Ok(Self::Buffer),
+            _ => Err(format!(
+                "unknown slow consumer policy `{}`, expected `notify`, `disconnect` or `buffer`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SlowConsumerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Notify => "notify",
+            Self::Disconnect => "disconnect",
+            Self::Buffer => "buffer",
+        })
+    }
+}
+
+impl ServerConfig {
+    /// Default port for the native protocol.
+    pub const DEFAULT_PORT: u16 = 1234;
+
+    /// See [`ServerConfig::handshake_timeout_ms`].
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_millis(self.handshake_timeout_ms)
+    }
+
+    /// See [`ServerConfig::ping_interval_ms`].
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_millis(self.ping_interval_ms)
+    }
+
+    /// See [`ServerConfig::ping_timeout_ms`].
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_millis(self.ping_timeout_ms)
+    }
+
+    /// See [`ServerConfig::shutdown_timeout_ms`].
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+
+    /// Loads the config from the TOML file at `path`, checking it with
+    /// [`ServerConfig::validate`].
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        let config: Self =
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.into(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the settings the server can't run without, such as the sizes of its queues, aren't
+    /// zero.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let non_zero = [
+            ("channel_capacity", self.channel_capacity as u64),
+            ("channel_shards", self.channel_shards as u64),
+            ("outbound_queue", self.outbound_queue as u64),
+            ("max_line_length", self.max_line_length as u64),
+            ("max_name_length", self.max_name_length as u64),
+            ("ping_interval_ms", self.ping_interval_ms),
+            ("ping_timeout_ms", self.ping_timeout_ms),
+            (
+                "history.channels",
+                self.history.as_ref().map_or(1, |h| h.channels as u64),
+            ),
+        ];
+        match non_zero.iter().find(|(_, value)| *value == 0) {
+            Some((name, _)) => Err(ConfigError::Invalid(name)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec![SocketAddr::new(
+                Ipv4Addr::LOCALHOST.into(),
+                Self::DEFAULT_PORT,
+            )],
+            irc_bind: Vec::new(),
+            websocket_bind: Vec::new(),
+            tls: None,
+            channel_capacity: 1000,
+            channel_shards: 64,
+            slow_consumer: SlowConsumerPolicy::Notify,
+            outbound_queue: 10_000,
+            max_line_length: codec::DEFAULT_LENGTH_LIMIT,
+            max_name_length: command::DEFAULT_MAX_NAME_LENGTH,
+            history: None,
+            accounts: None,
+            rate_limit: None,
+            admins: Vec::new(),
+            max_clients: None,
+            max_clients_per_ip: None,
+            handshake_timeout_ms: 30_000,
+            ping_interval_ms: 120_000,
+            ping_timeout_ms: 60_000,
+            shutdown_timeout_ms: 5000,
+            log_level: Level::DEBUG,
+        }
+    }
+}
+
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(de::Error::custom)
+}
@@ -0,0 +1,304 @@
+//! Message history of the [`Server`](crate::server::Server)'s channels.
+//!
+//! The last messages said in each channel are kept in memory, so they can be replayed to users
+//! who join later. The history can also be appended to a file, from which it is loaded again
+//! when the server starts, in which case every line of the file is a message in the form
+//! `TIME CHANNEL USER TEXT`. The file is written by a thread of its own, so recording a message
+//! never waits on the disk.
+//!
+//! Only so many channels have their history kept in memory, the least recently used ones being
+//! forgotten to make room for others. Like the channels themselves, the histories are spread over
+//! shards, so that users talking in different channels rarely wait on each other.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{File, OpenOptions},
+    hash::{BuildHasher, Hash, Hasher},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use crate::HashMap;
+
+/// Error type for [`History`].
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("failed to open history file `{0}`")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("failed to read history file `{0}`")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("failed to start writing to history file `{0}`")]
+    Spawn(PathBuf, #[source] io::Error),
+}
+
+/// A message said in a channel, as kept in its history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: DateTime<Utc>,
+    pub from: String,
+    pub text: String,
+}
+
+impl HistoryEntry {
+    /// Creates an entry for a message `from` just said.
+    pub fn now(from: &str, text: &str) -> Self {
+        Self {
+            time: Utc::now(),
+            from: from.to_owned(),
+            text: text.to_owned(),
+        }
+    }
+
+    /// The time the message was said, as shown to users, e.g. `2021-05-01T12:30:00Z`.
+    pub fn timestamp(&self) -> String {
+        self.time.to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+
+    /// Renders the entry as a line of the history file, for the channel `chan_name`.
+    fn to_line(&self, chan_name: &str) -> String {
+        format!(
+            "{} {} {} {}",
+            self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            chan_name,
+            self.from,
+            self.text
+        )
+    }
+
+    /// Parses a line of the history file, returning the channel name along with the entry.
+    fn parse_line(line: &str) -> Option<(String, Self)> {
+        let mut terms = line.splitn(4, ' ');
+        let time = DateTime::parse_from_rfc3339(terms.next()?).ok()?;
+        let chan_name = terms.next()?.to_owned();
+        let from = terms.next()?.to_owned();
+        let text = terms.next()?.to_owned();
+        let entry = Self {
+            time: time.with_timezone(&Utc),
+            from,
+            text,
+        };
+        Some((chan_name, entry))
+    }
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.timestamp(), self.from, self.text)
+    }
+}
+
+/// What the thread writing the history file is asked to do.
+enum Append {
+    /// Append this line to the file.
+    Line(String),
+    /// Say so once every line asked for before is written.
+    Sync(oneshot::Sender<()>),
+}
+
+/// The last messages said in a channel, oldest first.
+struct ChannelHistory {
+    entries: VecDeque<HistoryEntry>,
+    /// When the history was last recorded to or read, as counted by its [`Shard`].
+    last_used: u64,
+}
+
+/// The histories of the channels whose names hash to the same shard of [`History`].
+#[derive(Default)]
+struct Shard {
+    channels: HashMap<String, ChannelHistory>,
+    /// Counts every use of the shard's histories, to tell which was used least recently.
+    uses: u64,
+}
+
+impl Shard {
+    /// The history of `chan_name`, marked as just used, if there's one.
+    fn get(&mut self, chan_name: &str) -> Option<&mut ChannelHistory> {
+        self.uses += 1;
+        let uses = self.uses;
+        let history = self.channels.get_mut(chan_name)?;
+        history.last_used = uses;
+        Some(history)
+    }
+
+    /// The history of `chan_name`, marked as just used, which is created if there's none,
+    /// forgetting the least recently used history if the shard already has `max_channels`.
+    fn get_or_create(&mut self, chan_name: &str, max_channels: usize) -> &mut ChannelHistory {
+        if !self.channels.contains_key(chan_name) && self.channels.len() >= max_channels {
+            let oldest = self
+                .channels
+                .iter()
+                .min_by_key(|(_, history)| history.last_used)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.channels.remove(&oldest);
+            }
+        }
+        self.uses += 1;
+        let history = self
+            .channels
+            .entry(chan_name.into())
+            .or_insert_with(|| ChannelHistory {
+                entries: VecDeque::new(),
+                last_used: 0,
+            });
+        history.last_used = self.uses;
+        history
+    }
+}
+
+/// The history of every channel.
+///
+/// Every channel keeps its last `capacity` messages, even once everyone has left it, as long as
+/// it's among the channels used most recently. Each of the shards keeps up to its share of
+/// `max_channels` histories.
+pub struct History {
+    capacity: usize,
+    /// The most histories each shard keeps.
+    max_channels: usize,
+    shards: Box<[Mutex<Shard>]>,
+    hasher: ahash::RandomState,
+    /// The thread appending every message to the file, if any.
+    file: Option<mpsc::UnboundedSender<Append>>,
+}
+
+impl History {
+    /// Creates a history kept in memory only, keeping up to `capacity` messages for each of
+    /// about `max_channels` channels, spread over `shards` shards.
+    pub fn new(capacity: usize, max_channels: usize, shards: usize) -> Self {
+        Self {
+            capacity,
+            max_channels: (max_channels + shards - 1) / shards,
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            hasher: ahash::RandomState::new(),
+            file: None,
+        }
+    }
+
+    /// Creates a history that is appended to the file at `path`, after loading the messages it
+    /// already holds, see [`History::new`].
+    pub fn open(
+        capacity: usize,
+        max_channels: usize,
+        shards: usize,
+        path: &Path,
+    ) -> Result<Self, HistoryError> {
+        let mut history = Self::new(capacity, max_channels, shards);
+
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(|e| HistoryError::Read(path.into(), e))?;
+                    // Lines that can't be made sense of, such as one cut short by a crash, are
+                    // simply skipped.
+                    if let Some((chan_name, entry)) = HistoryEntry::parse_line(&line) {
+                        history.push(&chan_name, entry);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(HistoryError::Open(path.into(), e)),
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| HistoryError::Open(path.into(), e))?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer_path = path.to_owned();
+        thread::Builder::new()
+            .name("history-writer".into())
+            .spawn(move || Self::write(file, &writer_path, rx))
+            .map_err(|e| HistoryError::Spawn(path.into(), e))?;
+        history.file = Some(tx);
+
+        Ok(history)
+    }
+
+    /// Appends the lines received on `rx` to `file`, until the history is dropped.
+    ///
+    /// Lines that can't be written are only missing from the file, they are still in memory.
+    fn write(mut file: File, path: &Path, mut rx: mpsc::UnboundedReceiver<Append>) {
+        while let Some(append) = rx.blocking_recv() {
+            match append {
+                Append::Line(line) => {
+                    if let Err(e) = file.write_all(line.as_bytes()) {
+                        warn!(
+                            "failed to write to history file `{}`: {}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+                Append::Sync(done) => {
+                    done.send(()).ok();
+                }
+            }
+        }
+    }
+
+    fn shard(&self, chan_name: &str) -> MutexGuard<'_, Shard> {
+        let mut hasher = self.hasher.build_hasher();
+        chan_name.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % self.shards.len()]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds `entry` to the history of `chan_name` in memory.
+    fn push(&self, chan_name: &str, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut shard = self.shard(chan_name);
+        let entries = &mut shard.get_or_create(chan_name, self.max_channels).entries;
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Adds a message said in `chan_name` to its history.
+    ///
+    /// The message is kept in memory right away, and appended to the file, if any, shortly
+    /// after.
+    pub fn record(&self, chan_name: &str, entry: HistoryEntry) {
+        let line = self.file.as_ref().map(|_| entry.to_line(chan_name) + "\n");
+        self.push(chan_name, entry);
+
+        if let (Some(file), Some(line)) = (&self.file, line) {
+            // The writer only stops once the history is dropped.
+            file.send(Append::Line(line)).ok();
+        }
+    }
+
+    /// Waits for every message recorded so far to be written to the file, if any.
+    pub async fn sync(&self) {
+        if let Some(file) = &self.file {
+            let (done, synced) = oneshot::channel();
+            if file.send(Append::Sync(done)).is_ok() {
+                synced.await.ok();
+            }
+        }
+    }
+
+    /// The last `count` messages said in `chan_name`, oldest first.
+    pub fn last(&self, chan_name: &str, count: usize) -> Vec<HistoryEntry> {
+        match self.shard(chan_name).get(chan_name) {
+            Some(history) => {
+                let skip = history.entries.len().saturating_sub(count);
+                history.entries.iter().skip(skip).cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,1198 @@
+//! An IRC (RFC 1459/2812) front-end for the [`Server`](crate::server::Server).
+//!
+//! This lets stock IRC clients, such as irssi or weechat, talk to the server. IRC channels map
+//! onto the server's channels by dropping the leading `#`, so `#rust` on IRC is the same channel
+//! as `rust` for clients of the native protocol.
+//!
+//! Only a small subset of the protocol is supported: registration with `NICK`/`USER` (and `PASS`
+//! for registered nicknames), nickname changes, `REGISTER`, `JOIN`, `PART`, `PRIVMSG`, `NOTICE`,
+//! `NAMES`, `LIST`, `TOPIC`, `KICK`, `INVITE`, `PING` and `QUIT`, the `o`, `v`, `b`, `i`, `k`,
+//! `l` and `m` channel modes, plus enough of `MODE` and `WHO` to keep clients happy.
+
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tracing::{debug, warn};
+
+use crate::{
+    accounts::AccountError,
+    codec::{ChatCodec, Frame},
+    command::validate_name,
+    command::ModeChange,
+    config::{ServerConfig, SlowConsumerPolicy},
+    keepalive::{Keepalive, Silence},
+    outbound::{Lines, Outbound},
+    ratelimit::{RateLimiter, Verdict},
+    server::ServerError,
+    session::{ChatMessage, DirectMessage, Message, Received, Rendering, Session, Shared},
+};
+
+/// The name the server uses as the prefix of its own messages.
+const SERVER_NAME: &str = "chat";
+
+const RPL_WELCOME: &str = "001";
+const RPL_YOURHOST: &str = "002";
+const RPL_CREATED: &str = "003";
+const RPL_MYINFO: &str = "004";
+const RPL_UMODEIS: &str = "221";
+const RPL_ENDOFWHO: &str = "315";
+const RPL_LIST: &str = "322";
+const RPL_LISTEND: &str = "323";
+const RPL_CHANNELMODEIS: &str = "324";
+const RPL_NOTOPIC: &str = "331";
+const RPL_TOPIC: &str = "332";
+const RPL_INVITING: &str = "341";
+const RPL_NAMREPLY: &str = "353";
+const RPL_ENDOFNAMES: &str = "366";
+const RPL_BANLIST: &str = "367";
+const RPL_ENDOFBANLIST: &str = "368";
+const ERR_NOSUCHNICK: &str = "401";
+const ERR_NOSUCHCHANNEL: &str = "403";
+const ERR_CANNOTSENDTOCHAN: &str = "404";
+const ERR_NORECIPIENT: &str = "411";
+const ERR_NOTEXTTOSEND: &str = "412";
+const ERR_UNKNOWNCOMMAND: &str = "421";
+const ERR_NOMOTD: &str = "422";
+const ERR_NONICKNAMEGIVEN: &str = "431";
+const ERR_ERRONEUSNICKNAME: &str = "432";
+const ERR_NICKNAMEINUSE: &str = "433";
+const ERR_USERNOTINCHANNEL: &str = "441";
+const ERR_NOTONCHANNEL: &str = "442";
+const ERR_USERONCHANNEL: &str = "443";
+const ERR_NOTREGISTERED: &str = "451";
+const ERR_NEEDMOREPARAMS: &str = "461";
+const ERR_ALREADYREGISTRED: &str = "462";
+const ERR_PASSWDMISMATCH: &str = "464";
+const ERR_CHANNELISFULL: &str = "471";
+const ERR_UNKNOWNMODE: &str = "472";
+const ERR_INVITEONLYCHAN: &str = "473";
+const ERR_BANNEDFROMCHAN: &str = "474";
+const ERR_BADCHANNELKEY: &str = "475";
+const ERR_CHANOPRIVSNEEDED: &str = "482";
+
+/// A single IRC protocol message, i.e. one line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrcMessage {
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    pub fn new(prefix: Option<&str>, command: &str, params: Vec<String>) -> Self {
+        Self {
+            prefix: prefix.map(str::to_owned),
+            command: command.to_owned(),
+            params,
+        }
+    }
+
+    /// Parses a line in the form `[:PREFIX] COMMAND [PARAMS...] [:TRAILING]`.
+    ///
+    /// Commands are case insensitive, so they are normalized to upper case. Lines holding a
+    /// carriage return or a NUL character, which a message may not contain, aren't parsed.
+    pub fn parse(line: &str) -> Option<Self> {
+        if line.contains(&['\r', '\0'][..]) {
+            return None;
+        }
+        let mut rest = line.trim_start_matches(' ');
+
+        let prefix = match rest.strip_prefix(':') {
+            Some(prefixed) => {
+                let (prefix, tail) = prefixed.split_once(' ')?;
+                rest = tail.trim_start_matches(' ');
+                Some(prefix.to_owned())
+            }
+            None => None,
+        };
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_owned());
+                break;
+            }
+            let (param, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_owned());
+            rest = tail;
+        }
+
+        Some(Self {
+            prefix,
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+
+    fn param(&self, idx: usize) -> Option<&str> {
+        self.params.get(idx).map(String::as_str)
+    }
+}
+
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+        f.write_str(&self.command)?;
+        if let Some((last, params)) = self.params.split_last() {
+            for param in params {
+                write!(f, " {}", param)?;
+            }
+            // The last parameter needs to be marked as trailing if it could be mistaken for more
+            // than one parameter, or for a trailing one.
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
+            } else {
+                write!(f, " {}", last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The prefix of messages originating from `nick`.
+fn user_prefix(nick: &str) -> String {
+    format!("{}!{}@{}", nick, nick, SERVER_NAME)
+}
+
+/// Encodes `msg` as a line for the codec.
+fn encode(msg: &IrcMessage) -> String {
+    // IRC lines are terminated with CRLF, the codec adds the LF.
+    format!("{}\r", msg)
+}
+
+/// Relays `msg` to the members of the channel it was broadcast to.
+fn relayed(msg: &ChatMessage) -> IrcMessage {
+    let irc_chan = format!("#{}", msg.channel);
+    let irc_chan = irc_chan.as_str();
+    let relay = |from: &str, command: &str, params: &[&str]| {
+        let params = params.iter().copied().map(str::to_owned).collect();
+        IrcMessage::new(Some(&user_prefix(from)), command, params)
+    };
+    match &msg.body {
+        Message::Text { from, text } => relay(from, "PRIVMSG", &[irc_chan, text]),
+        Message::Joined { user } => relay(user, "JOIN", &[irc_chan]),
+        // IRC has no notion of history, so replayed messages are sent as if said again, with the
+        // time they were originally said.
+        Message::History(entry) => {
+            let text = format!("[{}] {}", entry.timestamp(), entry.text);
+            relay(&entry.from, "PRIVMSG", &[irc_chan, &text])
+        }
+        Message::Left { user, reason } => {
+            let mut params = vec![irc_chan];
+            params.extend(reason.as_deref());
+            relay(user, "PART", &params)
+        }
+        Message::Renamed { old, new } => relay(old, "NICK", &[new]),
+        Message::Topic { user, topic } => relay(user, "TOPIC", &[irc_chan, topic]),
+        Message::Mode { by, change } => {
+            let argument = change.argument();
+            let mut params = vec![irc_chan, change.flag()];
+            params.extend(argument.as_deref());
+            relay(by, "MODE", &params)
+        }
+        Message::Kicked { user, by, reason } => {
+            let reason = reason.as_deref().unwrap_or(by);
+            relay(by, "KICK", &[irc_chan, user, reason])
+        }
+        Message::Operator {
+            user,
+            by,
+            is_operator,
+        } => {
+            let mode = if *is_operator { "+o" } else { "-o" };
+            relay(by, "MODE", &[irc_chan, mode, user])
+        }
+        Message::Ban {
+            mask,
+            by,
+            is_banned,
+        } => {
+            let mode = if *is_banned { "+b" } else { "-b" };
+            relay(by, "MODE", &[irc_chan, mode, mask])
+        }
+        Message::ServerClosing => {
+            let params = vec!["*".to_owned(), "Server shutting down".to_owned()];
+            IrcMessage::new(Some(SERVER_NAME), "NOTICE", params)
+        }
+    }
+}
+
+/// An IRC connection.
+struct Connection<S> {
+    /// The lines received from the client.
+    lines: Lines<S>,
+    /// The lines being written to the client.
+    out: Outbound,
+    config: Arc<ServerConfig>,
+    addr: SocketAddr,
+    /// The user's nickname, or `*` until they have picked one.
+    nick: String,
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static> Connection<S> {
+    fn new(shared: &Shared, stream: S, addr: SocketAddr) -> Self {
+        let irc = ChatCodec::with_max_length(stream, shared.config.max_line_length);
+        let (out, lines) = Outbound::spawn(irc, &shared.config);
+        Self {
+            lines,
+            out,
+            config: shared.config.clone(),
+            addr,
+            nick: "*".to_owned(),
+        }
+    }
+
+    /// Converts an IRC channel name (`#rust`) into the server's channel name (`rust`).
+    fn chan_name<'a>(&self, irc_chan: &'a str) -> Option<&'a str> {
+        irc_chan
+            .strip_prefix('#')
+            .filter(|name| validate_name(name, self.config.max_name_length).is_ok())
+    }
+
+    /// Sends `msg` to the client.
+    async fn send(&mut self, msg: IrcMessage) -> Result<(), ServerError> {
+        self.send_frame(encode(&msg).into()).await
+    }
+
+    /// Sends a line already encoded to the client.
+    async fn send_frame(&mut self, frame: Frame) -> Result<(), ServerError> {
+        self.out
+            .send(frame)
+            .await
+            .map_err(|e| ServerError::SendMessage(self.addr, e))
+    }
+
+    /// Sends a numeric reply from the server to the client.
+    async fn numeric(&mut self, code: &str, params: &[&str]) -> Result<(), ServerError> {
+        let params = std::iter::once(self.nick.as_str())
+            .chain(params.iter().copied())
+            .map(str::to_owned)
+            .collect();
+        self.send(IrcMessage::new(Some(SERVER_NAME), code, params))
+            .await
+    }
+
+    /// Sends a message to the client on behalf of the user `from`.
+    async fn relay(
+        &mut self,
+        from: &str,
+        command: &str,
+        params: &[&str],
+    ) -> Result<(), ServerError> {
+        let params = params.iter().copied().map(str::to_owned).collect();
+        self.send(IrcMessage::new(Some(&user_prefix(from)), command, params))
+            .await
+    }
+
+    async fn pong(&mut self, msg: &IrcMessage) -> Result<(), ServerError> {
+        let token = msg.param(0).unwrap_or(SERVER_NAME).to_owned();
+        self.send(IrcMessage::new(
+            Some(SERVER_NAME),
+            "PONG",
+            vec![SERVER_NAME.to_owned(), token],
+        ))
+        .await
+    }
+
+    /// Runs the `NICK`/`USER` registration, returning the user's session once it is done.
+    ///
+    /// A registered nickname is only given to clients that sent its password with `PASS`.
+    ///
+    /// Every line goes through `limiter`, so clients can't flood the server with passwords to
+    /// check before they are even registered.
+    ///
+    /// Returns `None` if the client quit before registering, or sent the wrong password.
+    async fn register(
+        &mut self,
+        shared: &Arc<Shared>,
+        limiter: &mut RateLimiter,
+    ) -> Result<Option<Session>, ServerError> {
+        let mut nick = None;
+        let mut user = None;
+        let mut password = None;
+        loop {
+            let line = match self.lines.next().await {
+                Some(Ok(line)) => line,
+                _ => return Err(ServerError::NoJoin(self.addr)),
+            };
+            if !self.throttle(limiter).await? {
+                continue;
+            }
+            let msg = match IrcMessage::parse(&line) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            match msg.command.as_str() {
+                "NICK" => match msg.param(0) {
+                    None => {
+                        self.numeric(ERR_NONICKNAMEGIVEN, &["No nickname given"])
+                            .await?
+                    }
+                    Some(name) if validate_name(name, self.config.max_name_length).is_err() => {
+                        self.numeric(ERR_ERRONEUSNICKNAME, &[name, "Erroneous nickname"])
+                            .await?
+                    }
+                    Some(name) => nick = Some(name.to_owned()),
+                },
+                "USER" if msg.params.len() < 4 => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["USER", "Not enough parameters"])
+                        .await?
+                }
+                "USER" => user = msg.param(0).map(str::to_owned),
+                "PING" => self.pong(&msg).await?,
+                "QUIT" => return Ok(None),
+                "PASS" => password = msg.param(0).map(str::to_owned),
+                // Capability negotiation isn't supported, the client carries on with
+                // registration regardless.
+                "CAP" | "PONG" => (),
+                _ => {
+                    self.numeric(ERR_NOTREGISTERED, &["You have not registered"])
+                        .await?
+                }
+            }
+
+            if let (Some(name), Some(_)) = (&nick, &user) {
+                let identified_as = match &password {
+                    Some(password) => match Session::authenticate(shared, name, password).await {
+                        Ok(()) => Some(name.clone()),
+                        // Without accounts, passwords are simply ignored.
+                        Err(ServerError::AccountsDisabled) => None,
+                        Err(ServerError::AuthenticationFailed(_)) => {
+                            self.numeric(ERR_PASSWDMISMATCH, &["Password incorrect"])
+                                .await?;
+                            self.close_link("Bad password").await?;
+                            return Ok(None);
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    None => None,
+                };
+                match Session::register(shared.clone(), self.addr, name.clone(), identified_as) {
+                    Ok(session) => return Ok(Some(session)),
+                    // The client gets to pick another nickname.
+                    Err(e @ ServerError::UserNameInUse(_))
+                    | Err(e @ ServerError::NameReserved(_)) => {
+                        self.nick_unavailable(&e).await?;
+                        nick = None;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    async fn welcome(&mut self) -> Result<(), ServerError> {
+        let nick = self.nick.clone();
+        let welcome = format!("Welcome to the chat server, {}", nick);
+        let your_host = format!("Your host is {}", SERVER_NAME);
+        self.numeric(RPL_WELCOME, &[&welcome]).await?;
+        self.numeric(RPL_YOURHOST, &[&your_host]).await?;
+        self.numeric(RPL_CREATED, &["This server was created just now"])
+            .await?;
+        self.numeric(RPL_MYINFO, &[SERVER_NAME, env!("CARGO_PKG_VERSION")])
+            .await?;
+        self.numeric(ERR_NOMOTD, &["MOTD File is missing"]).await
+    }
+
+    async fn names(&mut self, session: &Session, irc_chan: &str) -> Result<(), ServerError> {
+        if let Some(chan) = self.chan_name(irc_chan) {
+            let names = session
+                .roster(chan)
+                .into_iter()
+                .map(|(name, member)| match member.is_operator {
+                    true => format!("@{}", name),
+                    false => name,
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.numeric(RPL_NAMREPLY, &["=", irc_chan, &names]).await?;
+        }
+        self.numeric(RPL_ENDOFNAMES, &[irc_chan, "End of /NAMES list"])
+            .await
+    }
+
+    /// Sends the topic of `irc_chan` to the client.
+    ///
+    /// When `quiet` is set nothing is sent if the channel has no topic, as is the case right
+    /// after joining.
+    async fn topic(
+        &mut self,
+        session: &Session,
+        irc_chan: &str,
+        quiet: bool,
+    ) -> Result<(), ServerError> {
+        match self
+            .chan_name(irc_chan)
+            .and_then(|chan| session.topic(chan))
+        {
+            Some(topic) => self.numeric(RPL_TOPIC, &[irc_chan, &topic]).await,
+            None if quiet => Ok(()),
+            None => {
+                self.numeric(RPL_NOTOPIC, &[irc_chan, "No topic is set"])
+                    .await
+            }
+        }
+    }
+
+    async fn set_topic(
+        &mut self,
+        session: &Session,
+        irc_chan: &str,
+        topic: &str,
+    ) -> Result<(), ServerError> {
+        let result = match self.chan_name(irc_chan) {
+            Some(chan) => session.set_topic(chan, topic),
+            None => {
+                return self
+                    .numeric(ERR_NOSUCHCHANNEL, &[irc_chan, "No such channel"])
+                    .await
+            }
+        };
+        match result {
+            // The new topic is relayed to the user along with the rest of the channel.
+            Ok(()) => Ok(()),
+            Err(ServerError::NotInChannel(..)) => {
+                self.numeric(ERR_NOTONCHANNEL, &[irc_chan, "You're not on that channel"])
+                    .await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Kicks `nick` out of `irc_chan`.
+    async fn kick(
+        &mut self,
+        session: &Session,
+        irc_chan: &str,
+        nick: &str,
+        reason: Option<&str>,
+    ) -> Result<(), ServerError> {
+        let result = match self.chan_name(irc_chan) {
+            Some(chan) => session.kick(chan, nick, reason),
+            None => {
+                return self
+                    .numeric(ERR_NOSUCHCHANNEL, &[irc_chan, "No such channel"])
+                    .await
+            }
+        };
+        self.moderated(irc_chan, result).await
+    }
+
+    /// Changes the modes of `irc_chan`, or sends them if `changes` is empty.
+    ///
+    /// `changes` is a mode string such as `+o-b`, followed by the arguments of the modes that
+    /// take one, in order. The supported modes are `o` (operator), `v` (voice), `b` (ban), `i`
+    /// (invite only), `k` (key), `l` (limit) and `m` (moderated).
+    async fn channel_mode(
+        &mut self,
+        session: &Session,
+        irc_chan: &str,
+        changes: &[String],
+    ) -> Result<(), ServerError> {
+        let chan = match self.chan_name(irc_chan) {
+            Some(chan) => chan,
+            None => {
+                return self
+                    .numeric(ERR_NOSUCHCHANNEL, &[irc_chan, "No such channel"])
+                    .await
+            }
+        };
+        let (modes, mut args) = match changes.split_first() {
+            Some((modes, args)) => (modes.as_str(), args.iter()),
+            None => {
+                return match session.modes(chan) {
+                    Ok(modes) => {
+                        let modes = modes.to_string();
+                        let mut params = vec![irc_chan];
+                        params.extend(modes.split(' '));
+                        self.numeric(RPL_CHANNELMODEIS, &params).await
+                    }
+                    Err(e) => self.moderated(irc_chan, Err(e)).await,
+                };
+            }
+        };
+
+        let mut is_adding = true;
+        for mode in modes.chars() {
+            let result = match mode {
+                '+' => {
+                    is_adding = true;
+                    continue;
+                }
+                '-' => {
+                    is_adding = false;
+                    continue;
+                }
+                'o' => match args.next() {
+                    Some(nick) => session.set_operator(chan, nick, is_adding),
+                    None => continue,
+                },
+                'i' => session.set_modes(chan, &[ModeChange::InviteOnly(is_adding)]),
+                'm' => session.set_modes(chan, &[ModeChange::Moderated(is_adding)]),
+                'k' if is_adding => match args.next() {
+                    Some(key) => session.set_modes(chan, &[ModeChange::Key(Some(key.clone()))]),
+                    None => continue,
+                },
+                // Clients usually give the key when removing it too, though it's not needed.
+                'k' => {
+                    args.next();
+                    session.set_modes(chan, &[ModeChange::Key(None)])
+                }
+                'l' if is_adding => match args.next().and_then(|limit| limit.parse().ok()) {
+                    Some(limit) => session.set_modes(chan, &[ModeChange::Limit(Some(limit))]),
+                    None => continue,
+                },
+                'l' => session.set_modes(chan, &[ModeChange::Limit(None)]),
+                'v' => match args.next() {
+                    Some(nick) => {
+                        let change = ModeChange::Voice(nick.clone(), is_adding);
+                        session.set_modes(chan, &[change])
+                    }
+                    None => continue,
+                },
+                'b' => match args.next() {
+                    Some(mask) => session.set_ban(chan, mask, is_adding),
+                    // Clients ask for the ban list with `MODE #chan b`.
+                    None => {
+                        for mask in session.bans(chan) {
+                            self.numeric(RPL_BANLIST, &[irc_chan, &mask]).await?;
+                        }
+                        self.numeric(RPL_ENDOFBANLIST, &[irc_chan, "End of channel ban list"])
+                            .await?;
+                        continue;
+                    }
+                },
+                mode => {
+                    let mode = mode.to_string();
+                    self.numeric(ERR_UNKNOWNMODE, &[&mode, "is unknown mode char to me"])
+                        .await?;
+                    continue;
+                }
+            };
+            self.moderated(irc_chan, result).await?;
+        }
+        Ok(())
+    }
+
+    /// Invites `nick` to `irc_chan`.
+    async fn invite(
+        &mut self,
+        session: &Session,
+        nick: &str,
+        irc_chan: &str,
+    ) -> Result<(), ServerError> {
+        let result = match self.chan_name(irc_chan) {
+            Some(chan) => session.invite(chan, nick),
+            None => {
+                return self
+                    .numeric(ERR_NOSUCHCHANNEL, &[irc_chan, "No such channel"])
+                    .await
+            }
+        };
+        match result {
+            Ok(()) => self.numeric(RPL_INVITING, &[nick, irc_chan]).await,
+            Err(ServerError::NoSuchUser(_)) => {
+                self.numeric(ERR_NOSUCHNICK, &[nick, "No such nick/channel"])
+                    .await
+            }
+            Err(ServerError::UserOnChannel(..)) => {
+                let text = "is already on channel";
+                self.numeric(ERR_USERONCHANNEL, &[nick, irc_chan, text])
+                    .await
+            }
+            result => self.moderated(irc_chan, result).await,
+        }
+    }
+
+    /// Tells the client why the moderation of `irc_chan` they attempted failed, if it did.
+    async fn moderated(
+        &mut self,
+        irc_chan: &str,
+        result: Result<(), ServerError>,
+    ) -> Result<(), ServerError> {
+        match result {
+            // The change is relayed to the user along with the rest of the channel.
+            Ok(()) => Ok(()),
+            Err(ServerError::NotInChannel(..)) => {
+                self.numeric(ERR_NOTONCHANNEL, &[irc_chan, "You're not on that channel"])
+                    .await
+            }
+            Err(ServerError::NotOperator(..)) => {
+                let text = "You're not channel operator";
+                self.numeric(ERR_CHANOPRIVSNEEDED, &[irc_chan, text]).await
+            }
+            Err(ServerError::UserNotInChannel(nick, _)) => {
+                let text = "They aren't on that channel";
+                self.numeric(ERR_USERNOTINCHANNEL, &[&nick, irc_chan, text])
+                    .await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lists the channels matching `mask`, a comma separated list of globs, or every channel.
+    async fn list(&mut self, session: &Session, mask: Option<&str>) -> Result<(), ServerError> {
+        let patterns: Vec<Option<&str>> = match mask {
+            Some(mask) => mask
+                .split(',')
+                .map(|irc_chan| Some(irc_chan.strip_prefix('#').unwrap_or(irc_chan)))
+                .collect(),
+            None => vec![None],
+        };
+        for pattern in patterns {
+            for reply in session.list(pattern) {
+                let irc_chan = format!("#{}", reply.channel);
+                let members = reply.members.to_string();
+                let topic = reply.topic.unwrap_or_default();
+                self.numeric(RPL_LIST, &[&irc_chan, &members, &topic])
+                    .await?;
+            }
+        }
+        self.numeric(RPL_LISTEND, &["End of /LIST"]).await
+    }
+
+    /// Passes a message received by the user's session along to the client.
+    async fn deliver(&mut self, received: Received) -> Result<(), ServerError> {
+        let (chan, result) = match received {
+            Received::Direct(DirectMessage::Text { from, text }) => {
+                let nick = self.nick.clone();
+                return self.relay(&from, "PRIVMSG", &[&nick, &text]).await;
+            }
+            Received::Direct(DirectMessage::Invite { from, channel }) => {
+                let (nick, irc_chan) = (self.nick.clone(), format!("#{}", channel));
+                return self.relay(&from, "INVITE", &[&nick, &irc_chan]).await;
+            }
+            Received::Channel(chan, result) => (chan, result),
+        };
+        let irc_chan = format!("#{}", chan);
+        let broadcast = match result {
+            Ok(broadcast) => broadcast,
+            Err(BroadcastStreamRecvError::Lagged(num_skipped)) => {
+                warn!(
+                    "user `{}@{}` is lagging on channel `{}`. {} messages skipped",
+                    self.nick, self.addr, chan, num_skipped
+                );
+                let notice = format!("{} messages dropped from {}", num_skipped, irc_chan);
+                let params = vec![self.nick.clone(), notice];
+                self.send(IrcMessage::new(Some(SERVER_NAME), "NOTICE", params))
+                    .await?;
+                return match self.config.slow_consumer {
+                    SlowConsumerPolicy::Disconnect => {
+                        self.close_link("Too slow").await?;
+                        Err(ServerError::Lagging(chan.to_owned(), num_skipped))
+                    }
+                    SlowConsumerPolicy::Notify | SlowConsumerPolicy::Buffer => Ok(()),
+                };
+            }
+        };
+        match broadcast.message() {
+            // The user's own messages, joins and name changes are echoed back as soon as they
+            // happen.
+            Message::Text { from, .. }
+            | Message::Joined { user: from }
+            | Message::Renamed { new: from, .. }
+                if from == &self.nick =>
+            {
+                Ok(())
+            }
+            // The link is closed once the server shuts down, see `close`.
+            Message::ServerClosing => Ok(()),
+            _ => {
+                let frame = broadcast.frame(Rendering::Irc, |msg| encode(&relayed(msg)));
+                self.send_frame(frame).await
+            }
+        }
+    }
+
+    /// Runs the line the client just sent past their rate limiter, returning whether it should
+    /// be handled.
+    async fn throttle(&mut self, limiter: &mut RateLimiter) -> Result<bool, ServerError> {
+        match limiter.check().await {
+            Verdict::Allow => Ok(true),
+            Verdict::Drop { notify } => {
+                if notify {
+                    debug!("dropping lines from user `{}@{}`", self.nick, self.addr);
+                    let text = "You are sending too fast, messages are being dropped".to_owned();
+                    let params = vec![self.nick.clone(), text];
+                    self.send(IrcMessage::new(Some(SERVER_NAME), "NOTICE", params))
+                        .await?;
+                }
+                Ok(false)
+            }
+            Verdict::Disconnect => {
+                self.close_link("Excess Flood").await.ok();
+                Err(ServerError::Flooding(self.addr))
+            }
+        }
+    }
+
+    /// Tells the client the server is closing their link, for `reason`.
+    async fn close_link(&mut self, reason: &str) -> Result<(), ServerError> {
+        let text = format!("Closing link: {}", reason);
+        self.send(IrcMessage::new(None, "ERROR", vec![text])).await
+    }
+
+    /// Sends the client every message already waiting for them, then closes the link
+    /// because the server is shutting down.
+    async fn close(&mut self, session: &mut Session) -> Result<(), ServerError> {
+        debug!(
+            "disconnecting user `{}@{}` for shutdown",
+            self.nick, self.addr
+        );
+        while let Some(received) = session.try_recv() {
+            self.deliver(received).await?;
+        }
+        self.close_link("Server shutting down").await
+    }
+
+    /// Joins each of the comma separated `irc_chans`, with the matching one of the comma
+    /// separated `keys`, if any.
+    async fn join(
+        &mut self,
+        session: &mut Session,
+        irc_chans: &str,
+        keys: Option<&str>,
+    ) -> Result<(), ServerError> {
+        // `JOIN 0` is a request to leave every channel.
+        if irc_chans == "0" {
+            return self.part_all(session, None).await;
+        }
+
+        let mut keys = keys.unwrap_or_default().split(',');
+        for irc_chan in irc_chans.split(',') {
+            let key = keys.next().filter(|key| !key.is_empty());
+            let chan = match self.chan_name(irc_chan) {
+                Some(chan) => chan,
+                None => {
+                    self.numeric(ERR_NOSUCHCHANNEL, &[irc_chan, "No such channel"])
+                        .await?;
+                    continue;
+                }
+            };
+            if session.is_member(chan) {
+                continue;
+            }
+            match session.join(chan, key) {
+                Ok(()) => {
+                    let nick = self.nick.clone();
+                    self.relay(&nick, "JOIN", &[irc_chan]).await?;
+                    self.topic(session, irc_chan, true).await?;
+                    self.names(session, irc_chan).await?;
+                }
+                Err(ServerError::UserAlreadyInChannel(nick)) => {
+                    let text = format!("Nickname is already in use on {}", irc_chan);
+                    self.numeric(ERR_NICKNAMEINUSE, &[&nick, &text]).await?;
+                }
+                Err(ServerError::Banned(..)) => {
+                    let text = "Cannot join channel (+b)";
+                    self.numeric(ERR_BANNEDFROMCHAN, &[irc_chan, text]).await?;
+                }
+                Err(ServerError::InviteOnly(..)) => {
+                    let text = "Cannot join channel (+i)";
+                    self.numeric(ERR_INVITEONLYCHAN, &[irc_chan, text]).await?;
+                }
+                Err(ServerError::BadChannelKey(..)) => {
+                    let text = "Cannot join channel (+k)";
+                    self.numeric(ERR_BADCHANNELKEY, &[irc_chan, text]).await?;
+                }
+                Err(ServerError::ChannelFull(..)) => {
+                    let text = "Cannot join channel (+l)";
+                    self.numeric(ERR_CHANNELISFULL, &[irc_chan, text]).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn nick(&mut self, session: &mut Session, name: &str) -> Result<(), ServerError> {
+        if validate_name(name, self.config.max_name_length).is_err() {
+            return self
+                .numeric(ERR_ERRONEUSNICKNAME, &[name, "Erroneous nickname"])
+                .await;
+        }
+        match session.rename(name) {
+            Ok(()) => {
+                let old = std::mem::replace(&mut self.nick, name.to_owned());
+                self.relay(&old, "NICK", &[name]).await
+            }
+            Err(e @ ServerError::UserNameInUse(_)) | Err(e @ ServerError::NameReserved(_)) => {
+                self.nick_unavailable(&e).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Tells the client they can't have the nickname they asked for, because of `err`.
+    async fn nick_unavailable(&mut self, err: &ServerError) -> Result<(), ServerError> {
+        match err {
+            ServerError::UserNameInUse(name) => {
+                self.numeric(ERR_NICKNAMEINUSE, &[name, "Nickname is already in use"])
+                    .await
+            }
+            ServerError::NameReserved(name) => {
+                let text = "Nickname is registered, send its password with PASS";
+                self.numeric(ERR_NICKNAMEINUSE, &[name, text]).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Registers the user's nickname with `password`.
+    async fn register_account(
+        &mut self,
+        session: &mut Session,
+        password: &str,
+    ) -> Result<(), ServerError> {
+        let text = match session.register_account(password).await {
+            Ok(()) => format!("Nickname {} is now registered", self.nick),
+            Err(ServerError::Account(AccountError::AlreadyRegistered(_))) => {
+                format!("Nickname {} is already registered", self.nick)
+            }
+            Err(ServerError::AccountsDisabled) => "Nicknames can't be registered".to_owned(),
+            Err(e) => return Err(e),
+        };
+        let params = vec![self.nick.clone(), text];
+        self.send(IrcMessage::new(Some(SERVER_NAME), "NOTICE", params))
+            .await
+    }
+
+    async fn part(
+        &mut self,
+        session: &mut Session,
+        irc_chans: &str,
+        reason: Option<&str>,
+    ) -> Result<(), ServerError> {
+        for irc_chan in irc_chans.split(',') {
+            let chan = self
+                .chan_name(irc_chan)
+                .filter(|chan| session.is_member(chan));
+            match chan {
+                Some(chan) => {
+                    session.part(chan, reason)?;
+                    let nick = self.nick.clone();
+                    let mut params = vec![irc_chan];
+                    params.extend(reason);
+                    self.relay(&nick, "PART", &params).await?;
+                }
+                None => {
+                    self.numeric(ERR_NOTONCHANNEL, &[irc_chan, "You're not on that channel"])
+                        .await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn part_all(
+        &mut self,
+        session: &mut Session,
+        reason: Option<&str>,
+    ) -> Result<(), ServerError> {
+        let irc_chans = session
+            .channel_names()
+            .map(|chan| format!("#{}", chan))
+            .collect::<Vec<_>>()
+            .join(",");
+        if irc_chans.is_empty() {
+            return Ok(());
+        }
+        self.part(session, &irc_chans, reason).await
+    }
+
+    async fn privmsg(&mut self, session: &Session, msg: &IrcMessage) -> Result<(), ServerError> {
+        // Errors are never sent in response to a NOTICE.
+        let is_notice = msg.command == "NOTICE";
+        let (target, text) = match (msg.param(0), msg.param(1)) {
+            (Some(target), Some(text)) if !text.is_empty() => (target, text),
+            _ if is_notice => return Ok(()),
+            (None, _) => {
+                let text = format!("No recipient given ({})", msg.command);
+                return self.numeric(ERR_NORECIPIENT, &[&text]).await;
+            }
+            (Some(_), _) => return self.numeric(ERR_NOTEXTTOSEND, &["No text to send"]).await,
+        };
+
+        for target in target.split(',') {
+            let result = match self.chan_name(target) {
+                Some(chan) => session.say(chan, text),
+                None if !target.starts_with('#') => session.message(target, text),
+                None => {
+                    if !is_notice {
+                        self.numeric(ERR_NOSUCHNICK, &[target, "No such nick/channel"])
+                            .await?;
+                    }
+                    continue;
+                }
+            };
+            match result {
+                Ok(()) => (),
+                Err(ServerError::NotInChannel(..)) | Err(ServerError::CannotSpeak(..))
+                    if !is_notice =>
+                {
+                    self.numeric(ERR_CANNOTSENDTOCHAN, &[target, "Cannot send to channel"])
+                        .await?
+                }
+                Err(ServerError::NoSuchUser(_)) if !is_notice => {
+                    self.numeric(ERR_NOSUCHNICK, &[target, "No such nick/channel"])
+                        .await?
+                }
+                // IRC has no reply for a message the recipient was too slow to take.
+                Err(ServerError::NotInChannel(..))
+                | Err(ServerError::CannotSpeak(..))
+                | Err(ServerError::NoSuchUser(_))
+                | Err(ServerError::UserLagging(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a message sent by a registered client.
+    ///
+    /// Returns `false` once the client has quit.
+    async fn handle_message(
+        &mut self,
+        session: &mut Session,
+        msg: IrcMessage,
+    ) -> Result<bool, ServerError> {
+        match msg.command.as_str() {
+            "JOIN" => match msg.param(0) {
+                Some(chans) => self.join(session, chans, msg.param(1)).await?,
+                None => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["JOIN", "Not enough parameters"])
+                        .await?
+                }
+            },
+            "PART" => match msg.param(0) {
+                Some(chans) => self.part(session, chans, msg.param(1)).await?,
+                None => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["PART", "Not enough parameters"])
+                        .await?
+                }
+            },
+            "NICK" => match msg.param(0) {
+                Some(name) => self.nick(session, name).await?,
+                None => {
+                    self.numeric(ERR_NONICKNAMEGIVEN, &["No nickname given"])
+                        .await?
+                }
+            },
+            "REGISTER" => match msg.param(0) {
+                Some(password) => self.register_account(session, password).await?,
+                None => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["REGISTER", "Not enough parameters"])
+                        .await?
+                }
+            },
+            "PRIVMSG" | "NOTICE" => self.privmsg(session, &msg).await?,
+            "NAMES" => {
+                for irc_chan in msg.param(0).unwrap_or_default().split(',') {
+                    if !irc_chan.is_empty() {
+                        self.names(session, irc_chan).await?;
+                    }
+                }
+            }
+            "LIST" => self.list(session, msg.param(0)).await?,
+            "TOPIC" => match (msg.param(0), msg.param(1)) {
+                (Some(irc_chan), Some(topic)) => self.set_topic(session, irc_chan, topic).await?,
+                (Some(irc_chan), None) => self.topic(session, irc_chan, false).await?,
+                (None, _) => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["TOPIC", "Not enough parameters"])
+                        .await?
+                }
+            },
+            "KICK" => match (msg.param(0), msg.param(1)) {
+                (Some(irc_chan), Some(nick)) => {
+                    self.kick(session, irc_chan, nick, msg.param(2)).await?
+                }
+                _ => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["KICK", "Not enough parameters"])
+                        .await?
+                }
+            },
+            "INVITE" => match (msg.param(0), msg.param(1)) {
+                (Some(nick), Some(irc_chan)) => self.invite(session, nick, irc_chan).await?,
+                _ => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["INVITE", "Not enough parameters"])
+                        .await?
+                }
+            },
+            "MODE" => match msg.param(0) {
+                Some(target) if target.starts_with('#') => {
+                    self.channel_mode(session, target, &msg.params[1..]).await?
+                }
+                Some(_) => self.numeric(RPL_UMODEIS, &["+"]).await?,
+                None => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &["MODE", "Not enough parameters"])
+                        .await?
+                }
+            },
+            "WHO" => {
+                let mask = msg.param(0).unwrap_or("*").to_owned();
+                self.numeric(RPL_ENDOFWHO, &[&mask, "End of WHO list"])
+                    .await?
+            }
+            "PING" => self.pong(&msg).await?,
+            "PONG" | "CAP" => (),
+            "USER" | "PASS" => {
+                self.numeric(ERR_ALREADYREGISTRED, &["You may not reregister"])
+                    .await?
+            }
+            "QUIT" => {
+                let reason = msg.param(0);
+                session.part_all(reason)?;
+                self.close_link(reason.unwrap_or("Client quit")).await?;
+                return Ok(false);
+            }
+            command => {
+                let command = command.to_owned();
+                self.numeric(ERR_UNKNOWNCOMMAND, &[&command, "Unknown command"])
+                    .await?
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Tells an IRC client the server refused their connection, see [`ServerError::ServerFull`] and
+/// [`ServerError::TooManyConnections`].
+pub(crate) async fn refuse<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    shared: &Shared,
+    stream: S,
+    addr: SocketAddr,
+    refusal: &ServerError,
+) -> Result<(), ServerError> {
+    let mut conn = Connection::new(shared, stream, addr);
+    let reason = match refusal {
+        ServerError::TooManyConnections(_) => "Too many connections from your host",
+        _ => "Server full",
+    };
+    conn.close_link(reason).await?;
+    conn.out
+        .finish()
+        .await
+        .map_err(|e| ServerError::SendMessage(addr, e))
+}
+
+/// Handle the connection to a single IRC client.
+///
+/// This function remains running for as long as the connection to the client is unbroken, or
+/// until the server shuts down.
+#[tracing::instrument(skip(shared, stream))]
+pub async fn handle_client<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    shared: Arc<Shared>,
+    stream: S,
+    addr: SocketAddr,
+) -> Result<(), ServerError> {
+    debug!("handling irc client");
+    let mut conn = Connection::new(&shared, stream, addr);
+    let result = serve(shared, &mut conn).await;
+    // However the connection ended, the lines already queued still go out.
+    if let Err(e) = conn.out.finish().await {
+        debug!(
+            "failed to write last lines to client at address `{}`: {}",
+            addr, e
+        );
+    }
+    result
+}
+
+/// Runs the connection to an IRC client, see [`handle_client`].
+async fn serve<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    shared: Arc<Shared>,
+    conn: &mut Connection<S>,
+) -> Result<(), ServerError> {
+    let addr = conn.addr;
+    let disconnect = shared.disconnect.clone();
+
+    let mut limiter = RateLimiter::new(
+        shared.config.rate_limit.as_ref(),
+        shared.flood_stats.clone(),
+    );
+    let registered = tokio::select! {
+        result = conn.register(&shared, &mut limiter) => Some(result?),
+        _ = disconnect.cancelled() => None,
+        _ = time::sleep(shared.config.handshake_timeout()) => {
+            conn.close_link("Registration timed out").await.ok();
+            return Err(ServerError::NoJoin(addr));
+        }
+    };
+    let mut session = match registered {
+        Some(Some(session)) => session,
+        Some(None) => return Ok(()),
+        None => return conn.close_link("Server shutting down").await,
+    };
+    conn.nick = session.user_name.clone();
+    conn.welcome().await?;
+    let mut keepalive = Keepalive::new(&shared.config);
+
+    loop {
+        let out = &conn.out;
+        tokio::select! {
+            // While the client's socket is too slow, messages wait in their channels instead.
+            received = async {
+                out.ready().await;
+                session.recv().await
+            } => match received {
+                received if received.is_server_closing() => {
+                    return conn.close(&mut session).await
+                }
+                received => conn.deliver(received).await?,
+            },
+            _ = disconnect.cancelled() => return conn.close(&mut session).await,
+            silence = keepalive.silence() => match silence {
+                Silence::Ping => {
+                    let params = vec![SERVER_NAME.to_owned()];
+                    conn.send(IrcMessage::new(None, "PING", params)).await?
+                }
+                Silence::TimedOut => {
+                    conn.close_link("Ping timeout").await.ok();
+                    return Err(ServerError::PingTimeout(addr));
+                }
+            },
+            result = conn.lines.next() => match result {
+                Some(Ok(line)) => {
+                    keepalive.heard();
+                    if !conn.throttle(&mut limiter).await? {
+                        continue;
+                    }
+                    let msg = match IrcMessage::parse(&line) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    if !conn.handle_message(&mut session, msg).await? {
+                        debug!("user `{}@{}` quit", conn.nick, addr);
+                        return Ok(());
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!("error while processing message from user `{}@{}`: {}", conn.nick, addr, e);
+                }
+                None => {
+                    debug!("user `{}@{}` disconnected", conn.nick, addr);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Dropping the session has the user leave every channel they were in.
+    Ok(())
+}
@@ -0,0 +1,575 @@
+//! The JSON-lines wire protocol of the [`Server`](crate::server::Server).
+//!
+//! Native clients opt into it by sending `PROTO json` as their very first line, which the server
+//! acknowledges with `{"event":"proto","format":"json"}`. From then on, every line the client
+//! sends is a [`Request`] tagged by `cmd`, and every line the server sends is an [`Event`] tagged
+//! by `event`, e.g.:
+//!
+//! ```text
+//! {"cmd":"join","channel":"rust","user":"joe"}
+//! {"event":"message","id":1,"time":"2021-05-01T12:30:00Z","channel":"rust","sender":"joe","kind":"join","body":{"type":"joined"}}
+//! {"cmd":"say","text":"hi"}
+//! ```
+//!
+//! The session goes just like it does with the line protocol: it starts with a `join` giving the
+//! user name, which may be preceded by `identify`, and the requests mirror the [`Command`]s.
+//! Queries are answered with a single event holding every result, rather than with a line for
+//! each followed by an end line.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{validate_name, validate_text, Command, ModeChange, ParseError, Verb},
+    history::HistoryEntry,
+    reply::{ErrorReply, ListReply, ModeReply, TopicReply},
+    session::{ChatMessage, DirectMessage, Message, MessageKind},
+};
+
+/// The first term of the line negotiating the [`WireFormat`], e.g. `PROTO json`.
+pub const PROTO: &str = "PROTO";
+
+/// The format of the lines exchanged with a native client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// The line protocol, see [`Command`].
+    Text,
+    /// JSON lines, see [`Request`] and [`Event`].
+    Json,
+}
+
+impl WireFormat {
+    /// Parses the line negotiating the format, e.g. `PROTO json`.
+    ///
+    /// Returns `None` if the line isn't one.
+    pub fn parse_negotiation(line: &str) -> Option<Result<Self, ParseError>> {
+        let format = line.strip_prefix(PROTO)?.strip_prefix(' ')?.trim();
+        Some(match format {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(ParseError::UnknownFormat(format.to_owned())),
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        }
+    }
+
+    /// Parses a line sent by a client after the initial handshake, see [`Command::parse`].
+    pub fn parse(&self, line: &str, max_name_length: usize) -> Result<Command, ParseError> {
+        match self {
+            Self::Text => Command::parse(line, max_name_length),
+            Self::Json => Request::from_line(line)?.into_command(max_name_length),
+        }
+    }
+
+    /// Parses the join command a client must begin their connection with, see
+    /// [`Command::parse_join`].
+    pub fn parse_join(
+        &self,
+        line: &str,
+        max_name_length: usize,
+    ) -> Result<(String, String, Option<String>), ParseError> {
+        match self {
+            Self::Text => Command::parse_join(line, max_name_length),
+            Self::Json => {
+                let request = Request::from_line(line)?;
+                let verb = request.verb();
+                match request.into_command(max_name_length)? {
+                    Command::Join {
+                        channel,
+                        user: Some(user),
+                        key,
+                    } => Ok((channel, user, key)),
+                    Command::Join { user: None, .. } => Err(ParseError::WrongArgumentCount {
+                        verb,
+                        expected: "2 or 3",
+                        found: 1,
+                    }),
+                    _ => Err(ParseError::JoinRequired(verb)),
+                }
+            }
+        }
+    }
+}
+
+/// A line sent by a client speaking JSON, the counterpart of a [`Command`].
+///
+/// Fields that are optional for the command may be left out, or be `null`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    Join {
+        channel: String,
+        user: Option<String>,
+        key: Option<String>,
+    },
+    Part {
+        channel: Option<String>,
+    },
+    /// Sends `text` to `channel`, or to the current channel if none is given.
+    Say {
+        channel: Option<String>,
+        text: String,
+    },
+    Msg {
+        user: String,
+        text: String,
+    },
+    Quit {
+        reason: Option<String>,
+    },
+    Ping {
+        token: Option<String>,
+    },
+    Pong {
+        token: Option<String>,
+    },
+    History {
+        channel: String,
+        count: usize,
+    },
+    Names {
+        channel: Option<String>,
+    },
+    Who {
+        user: String,
+    },
+    List {
+        pattern: Option<String>,
+    },
+    Topic {
+        channel: String,
+        text: Option<String>,
+    },
+    Nick {
+        user: String,
+    },
+    Register {
+        password: String,
+    },
+    Identify {
+        user: String,
+        password: String,
+    },
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    Ban {
+        mask: String,
+    },
+    Unban {
+        mask: String,
+    },
+    Op {
+        user: String,
+    },
+    Deop {
+        user: String,
+    },
+    /// Changes the modes of the current channel, e.g. `+kl` with the arguments `secret` and
+    /// `10`, or asks for them if no modes are given.
+    Mode {
+        modes: Option<String>,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    Invite {
+        user: String,
+    },
+}
+
+impl Request {
+    /// Parses a line sent by a client.
+    pub fn from_line(line: &str) -> Result<Self, ParseError> {
+        serde_json::from_str(line).map_err(|e| ParseError::InvalidJson(e.to_string()))
+    }
+
+    /// Renders the request as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("requests always serialize")
+    }
+
+    /// The verb of the command the request stands for.
+    pub fn verb(&self) -> Verb {
+        match self {
+            Self::Join { .. } => Verb::Join,
+            Self::Part { .. } => Verb::Part,
+            Self::Say { .. } => Verb::Say,
+            Self::Msg { .. } => Verb::Msg,
+            Self::Quit { .. } => Verb::Quit,
+            Self::Ping { .. } => Verb::Ping,
+            Self::Pong { .. } => Verb::Pong,
+            Self::History { .. } => Verb::History,
+            Self::Names { .. } => Verb::Names,
+            Self::Who { .. } => Verb::Who,
+            Self::List { .. } => Verb::List,
+            Self::Topic { .. } => Verb::Topic,
+            Self::Nick { .. } => Verb::Nick,
+            Self::Register { .. } => Verb::Register,
+            Self::Identify { .. } => Verb::Identify,
+            Self::Kick { .. } => Verb::Kick,
+            Self::Ban { .. } => Verb::Ban,
+            Self::Unban { .. } => Verb::Unban,
+            Self::Op { .. } => Verb::Op,
+            Self::Deop { .. } => Verb::Deop,
+            Self::Mode { .. } => Verb::Mode,
+            Self::Invite { .. } => Verb::Invite,
+        }
+    }
+
+    /// Validates the request as [`Command::parse`] would the same command, names being at most
+    /// `max_name_length` characters long.
+    ///
+    /// Unlike a line of text, a JSON string may hold line breaks, so every bit of free text is
+    /// checked for control characters too.
+    pub fn into_command(self, max_name_length: usize) -> Result<Command, ParseError> {
+        let name = |name: String| -> Result<String, ParseError> {
+            validate_name(&name, max_name_length)?;
+            Ok(name)
+        };
+        let maybe_name = |name_or_none: Option<String>| name_or_none.map(name).transpose();
+        // Masks may be longer than the names they match, e.g. `joe@127.0.0.1`, and keys aren't
+        // names at all.
+        let long_name = |name: String| -> Result<String, ParseError> {
+            validate_name(&name, usize::MAX)?;
+            Ok(name)
+        };
+        let free_text = |text: String| -> Result<String, ParseError> {
+            validate_text(&text)?;
+            Ok(text)
+        };
+        let maybe_text = |text_or_none: Option<String>| text_or_none.map(free_text).transpose();
+        let verb = self.verb();
+        let non_empty = |text: String| match text.is_empty() {
+            true => Err(ParseError::WrongArgumentCount {
+                verb,
+                expected: "2",
+                found: 1,
+            }),
+            false => free_text(text),
+        };
+
+        let cmd = match self {
+            Self::Join { channel, user, key } => Command::Join {
+                channel: name(channel)?,
+                user: maybe_name(user)?,
+                key: key.map(long_name).transpose()?,
+            },
+            Self::Part { channel } => Command::Part {
+                channel: maybe_name(channel)?,
+            },
+            Self::Say {
+                channel: Some(channel),
+                text,
+            } => Command::Say {
+                channel: name(channel)?,
+                text: non_empty(text)?,
+            },
+            // An empty line is no message either.
+            Self::Say {
+                channel: None,
+                text,
+            } if text.is_empty() => return Err(ParseError::Empty),
+            Self::Say {
+                channel: None,
+                text,
+            } => Command::Text(free_text(text)?),
+            Self::Msg { user, text } => Command::Msg {
+                user: name(user)?,
+                text: non_empty(text)?,
+            },
+            Self::Quit { reason } => Command::Quit {
+                reason: maybe_text(reason)?,
+            },
+            Self::Ping { token } => Command::Ping {
+                token: maybe_text(token)?,
+            },
+            Self::Pong { token } => Command::Pong {
+                token: maybe_text(token)?,
+            },
+            Self::History { channel, count } => Command::History {
+                channel: name(channel)?,
+                count,
+            },
+            Self::Names { channel } => Command::Names {
+                channel: maybe_name(channel)?,
+            },
+            Self::Who { user } => Command::Who { user: name(user)? },
+            Self::List { pattern } => Command::List {
+                pattern: maybe_name(pattern)?,
+            },
+            Self::Topic { channel, text } => Command::Topic {
+                channel: name(channel)?,
+                text: maybe_text(text.filter(|t| !t.is_empty()))?,
+            },
+            Self::Nick { user } => Command::Nick { user: name(user)? },
+            Self::Register { password } if password.is_empty() => {
+                return Err(ParseError::WrongArgumentCount {
+                    verb,
+                    expected: "1",
+                    found: 0,
+                })
+            }
+            Self::Register { password } => Command::Register {
+                password: free_text(password)?,
+            },
+            Self::Identify { user, password } => Command::Identify {
+                user: name(user)?,
+                password: non_empty(password)?,
+            },
+            Self::Kick { user, reason } => Command::Kick {
+                user: name(user)?,
+                reason: maybe_text(reason)?,
+            },
+            Self::Ban { mask } => Command::Ban {
+                mask: long_name(mask)?,
+            },
+            Self::Unban { mask } => Command::Unban {
+                mask: long_name(mask)?,
+            },
+            Self::Op { user } => Command::Op { user: name(user)? },
+            Self::Deop { user } => Command::Deop { user: name(user)? },
+            Self::Mode { modes, args } => {
+                let mut terms = args.iter().map(String::as_str);
+                let modes = modes.unwrap_or_default();
+                let changes = ModeChange::parse_all(&modes, &mut terms, max_name_length)?;
+                let extra = terms.count();
+                if extra > 0 {
+                    return Err(ParseError::WrongArgumentCount {
+                        verb,
+                        expected: "one for each mode taking one",
+                        found: args.len(),
+                    });
+                }
+                Command::Mode { changes }
+            }
+            Self::Invite { user } => Command::Invite { user: name(user)? },
+        };
+        Ok(cmd)
+    }
+}
+
+/// A message broadcast to a channel, as sent to clients speaking JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelMessage {
+    /// Unique across the server, and increasing in the order the channel's messages are
+    /// received. Messages replayed from the history get a new id.
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    pub channel: String,
+    pub sender: Option<String>,
+    pub kind: MessageKind,
+    pub body: MessageBody,
+}
+
+impl From<&ChatMessage> for ChannelMessage {
+    fn from(msg: &ChatMessage) -> Self {
+        Self {
+            id: msg.id,
+            time: msg.time,
+            channel: msg.channel.clone(),
+            sender: msg.sender().map(str::to_owned),
+            kind: msg.kind(),
+            body: MessageBody::from(&msg.body),
+        }
+    }
+}
+
+/// What a [`ChannelMessage`] says happened, tagged by `type`, e.g.
+/// `{"type":"kicked","target":"bob","by":"alice","reason":null}`.
+///
+/// The user it's about, when there's only one, is the message's `sender`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageBody {
+    /// The sender said `text`.
+    Text { text: String },
+    /// The sender joined the channel.
+    Joined,
+    /// The sender left the channel, optionally saying why.
+    Left { reason: Option<String> },
+    /// The user `old` changed their name to `new`.
+    Renamed { old: String, new: String },
+    /// `by` kicked `target` out of the channel, optionally saying why.
+    Kicked {
+        target: String,
+        by: String,
+        reason: Option<String>,
+    },
+    /// `by` made `target` an operator of the channel, or took it away.
+    Operator {
+        target: String,
+        by: String,
+        is_operator: bool,
+    },
+    /// `by` banned the users matching `mask` from the channel, or lifted the ban.
+    Ban {
+        mask: String,
+        by: String,
+        is_banned: bool,
+    },
+    /// `by` changed the mode `mode` of the channel, e.g. `+k`, along with its argument if it
+    /// takes one.
+    Mode {
+        by: String,
+        mode: String,
+        arg: Option<String>,
+    },
+    /// The sender set the topic of the channel.
+    Topic { topic: String },
+    /// The sender said `text` before, and it's replayed from the channel's history.
+    History { text: String },
+    /// The server is shutting down.
+    Closing,
+}
+
+impl From<&Message> for MessageBody {
+    fn from(msg: &Message) -> Self {
+        match msg.clone() {
+            Message::Text { text, .. } => Self::Text { text },
+            Message::Joined { .. } => Self::Joined,
+            Message::Left { reason, .. } => Self::Left { reason },
+            Message::Renamed { old, new } => Self::Renamed { old, new },
+            Message::Kicked { user, by, reason } => Self::Kicked {
+                target: user,
+                by,
+                reason,
+            },
+            Message::Operator {
+                user,
+                by,
+                is_operator,
+            } => Self::Operator {
+                target: user,
+                by,
+                is_operator,
+            },
+            Message::Ban {
+                mask,
+                by,
+                is_banned,
+            } => Self::Ban {
+                mask,
+                by,
+                is_banned,
+            },
+            Message::Mode { by, change } => Self::Mode {
+                by,
+                mode: change.flag().to_owned(),
+                arg: change.argument(),
+            },
+            Message::Topic { topic, .. } => Self::Topic { topic },
+            Message::History(entry) => Self::History { text: entry.text },
+            Message::ServerClosing => Self::Closing,
+        }
+    }
+}
+
+/// A line sent by the server to a client speaking JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The wire format was switched to `format`, in answer to `PROTO`.
+    Proto {
+        format: String,
+    },
+    /// A message broadcast to one of the user's channels.
+    Message(ChannelMessage),
+    /// `from` said `text` to the user alone.
+    Direct {
+        from: String,
+        text: String,
+    },
+    /// `from` invited the user to join `channel`.
+    Invite {
+        from: String,
+        channel: String,
+    },
+    /// The last request failed, or something went wrong with the connection.
+    Error {
+        code: u16,
+        name: String,
+        text: String,
+    },
+    /// The user identified as the owner of the registered name `user`.
+    Identified {
+        user: String,
+    },
+    /// The user registered their name `user`.
+    Registered {
+        user: String,
+    },
+    /// The user invited `user` to `channel`.
+    Invited {
+        user: String,
+        channel: String,
+    },
+    /// The server checks the client is still there, which must answer with a `pong` request.
+    Ping,
+    /// The answer to a `ping` request.
+    Pong {
+        token: Option<String>,
+    },
+    /// The last messages said in `channel`, oldest first.
+    History {
+        channel: String,
+        messages: Vec<HistoryEntry>,
+    },
+    /// The members of `channel`, in the order they joined it.
+    Names {
+        channel: String,
+        users: Vec<String>,
+    },
+    /// Describes `user`, along with the channels they are a member of.
+    Who {
+        user: String,
+        channels: Vec<String>,
+        connected_since: DateTime<Utc>,
+        /// The number of seconds since the user last said something.
+        idle: u64,
+    },
+    /// The channels matching the pattern, ordered by name.
+    List {
+        channels: Vec<ListReply>,
+    },
+    Topic(TopicReply),
+    Mode(ModeReply),
+    /// The server is shutting down, and the connection is about to be closed.
+    Closing,
+}
+
+impl Event {
+    /// Parses a line sent by the server.
+    pub fn from_line(line: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    /// Renders the event as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("events always serialize")
+    }
+}
+
+impl From<&ErrorReply> for Event {
+    fn from(reply: &ErrorReply) -> Self {
+        Self::Error {
+            code: reply.code.code(),
+            name: reply.code.name().to_owned(),
+            text: reply.text.clone(),
+        }
+    }
+}
+
+impl From<DirectMessage> for Event {
+    fn from(msg: DirectMessage) -> Self {
+        match msg {
+            DirectMessage::Text { from, text } => Self::Direct { from, text },
+            DirectMessage::Invite { from, channel } => Self::Invite { from, channel },
+        }
+    }
+}
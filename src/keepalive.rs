@@ -0,0 +1,63 @@
+//! Detection of clients that went away without closing their connection.
+//!
+//! A client that stays silent for [`ServerConfig::ping_interval`] is sent a `PING`, and is
+//! considered gone if it then stays silent for [`ServerConfig::ping_timeout`] as well. Any line
+//! the client sends counts as a sign of life, not only the `PONG` answering the ping.
+
+use std::{pin::Pin, time::Duration};
+
+use tokio::time::{self, Instant, Sleep};
+
+use crate::config::ServerConfig;
+
+/// What a [`Keepalive`] found once the client was silent for too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Silence {
+    /// The client should be sent a `PING`.
+    Ping,
+    /// The client didn't answer the last `PING`, and should be disconnected.
+    TimedOut,
+}
+
+/// Keeps track of how long the client of a single connection has been silent.
+pub(crate) struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    /// Completes once the client was silent for too long.
+    deadline: Pin<Box<Sleep>>,
+    /// Whether the client was sent a `PING` it hasn't answered yet.
+    pinged: bool,
+}
+
+impl Keepalive {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        let interval = config.ping_interval();
+        Self {
+            interval,
+            timeout: config.ping_timeout(),
+            deadline: Box::pin(time::sleep(interval)),
+            pinged: false,
+        }
+    }
+
+    /// Notes that the client just sent a line.
+    pub(crate) fn heard(&mut self) {
+        self.pinged = false;
+        let interval = self.interval;
+        self.deadline.as_mut().reset(Instant::now() + interval);
+    }
+
+    /// Waits until the client has been silent for too long.
+    ///
+    /// This is cancel safe, so it can be used in `tokio::select!` along with receiving lines.
+    pub(crate) async fn silence(&mut self) -> Silence {
+        self.deadline.as_mut().await;
+        if self.pinged {
+            return Silence::TimedOut;
+        }
+        self.pinged = true;
+        let timeout = self.timeout;
+        self.deadline.as_mut().reset(Instant::now() + timeout);
+        Silence::Ping
+    }
+}
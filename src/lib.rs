@@ -1,6 +1,20 @@
+pub mod accounts;
+pub mod channel;
 pub mod client;
 pub mod codec;
+pub mod command;
+pub mod config;
+pub mod history;
+pub mod irc;
+pub mod json;
+pub mod keepalive;
+pub mod outbound;
+pub mod ratelimit;
+pub mod reply;
 pub mod server;
+pub mod session;
+pub mod tls;
+pub mod websocket;
 
 /// A [`HashMap`](std::collections::HashMap) using [`ahash`] to hash items.
 ///
@@ -9,4 +23,8 @@ pub mod server;
 pub type HashMap<K, V> = ahash::AHashMap<K, V>;
 
 /// A [`HashMap`] wrapped to be safely sharable across threads.
-pub type ConcurrentMap<K, V> = std::sync::Arc<tokio::sync::Mutex<HashMap<K, V>>>;
+///
+/// The lock is only ever held for short, synchronous, updates, so it's a plain
+/// [`Mutex`](std::sync::Mutex) rather than an async one. This also lets it be taken from `Drop`
+/// implementations.
+pub type ConcurrentMap<K, V> = std::sync::Arc<std::sync::Mutex<HashMap<K, V>>>;
@@ -1,10 +1,16 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::{io, net::SocketAddr, num::NonZeroU32, path::PathBuf};
 
 use anyhow::{Context, Error};
 use structopt::StructOpt;
-use tracing::{info, Level};
+use tracing::{error, info, Level};
 
-use chat::server::Server;
+use chat::{
+    config::{
+        AccountsConfig, FloodAction, HistoryConfig, RateLimitConfig, ServerConfig,
+        SlowConsumerPolicy, TlsConfig,
+    },
+    server::Server,
+};
 use tracing_subscriber::fmt::time::ChronoUtc;
 
 #[derive(Debug, StructOpt)]
@@ -14,32 +20,248 @@ use tracing_subscriber::fmt::time::ChronoUtc;
     about = "Simple chat server"
 )]
 struct Opt {
-    #[structopt(default_value = "1234")]
-    port: u16,
+    /// Port to accept clients on, on every address they are accepted on otherwise (localhost by
+    /// default).
+    #[structopt(conflicts_with = "bind")]
+    port: Option<u16>,
+    /// TOML file to read settings from, which the other options override.
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Accept clients on this address, may be given more than once.
+    #[structopt(short, long)]
+    bind: Vec<SocketAddr>,
+    /// Also accept IRC clients on this address, may be given more than once.
+    #[structopt(long)]
+    irc_bind: Vec<SocketAddr>,
+    /// Also accept WebSocket clients on this address, may be given more than once.
+    #[structopt(long)]
+    ws_bind: Vec<SocketAddr>,
+    /// Also accept TLS clients on this address, may be given more than once.
+    #[structopt(long, requires_all = &["tls-cert", "tls-key"])]
+    tls_bind: Vec<SocketAddr>,
+    /// PEM encoded certificate chain for the TLS listener.
+    #[structopt(long, parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+    /// PEM encoded private key for the TLS listener.
+    #[structopt(long, parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+    /// Number of messages each channel holds before slow clients start missing them.
+    #[structopt(long)]
+    channel_capacity: Option<usize>,
+    /// Number of shards the channels are spread over, each behind its own lock.
+    #[structopt(long)]
+    channel_shards: Option<usize>,
+    /// What happens to clients who fall behind: `notify`, `disconnect` or `buffer`.
+    #[structopt(long)]
+    slow_consumer: Option<SlowConsumerPolicy>,
+    /// Number of lines waiting to be written to each client, with the `buffer` policy.
+    #[structopt(long)]
+    outbound_queue: Option<usize>,
+    /// Maximum length of a line sent by a client, in bytes.
+    #[structopt(long)]
+    max_line_length: Option<usize>,
+    /// Maximum length of channel and user names.
+    #[structopt(long)]
+    max_name_length: Option<usize>,
+    /// Keep the history of each channel, up to this many messages.
+    #[structopt(long)]
+    history_capacity: Option<usize>,
+    /// Keep the history of this many channels, forgetting the least recently used ones.
+    #[structopt(long)]
+    history_channels: Option<usize>,
+    /// Replay this many messages of a channel's history to users joining it.
+    #[structopt(long)]
+    history_replay: Option<usize>,
+    /// Append the history to this file, loading it from there on startup.
+    #[structopt(long, parse(from_os_str))]
+    history_file: Option<PathBuf>,
+    /// Let users register their names with a password.
+    #[structopt(long)]
+    accounts: bool,
+    /// Append registered accounts to this file, loading them from there on startup.
+    #[structopt(long, parse(from_os_str))]
+    accounts_file: Option<PathBuf>,
+    /// Limit clients to sending this many lines per second, on average.
+    #[structopt(long)]
+    rate_limit: Option<NonZeroU32>,
+    /// Number of lines clients may send in a row before being held to the rate limit.
+    #[structopt(long)]
+    rate_limit_burst: Option<NonZeroU32>,
+    /// What happens to lines sent too fast: `delay`, `drop` or `disconnect`.
+    #[structopt(long)]
+    flood_action: Option<FloodAction>,
+    /// Number of lines in a row that may be dropped before the client is disconnected, with the
+    /// `disconnect` flood action.
+    #[structopt(long)]
+    max_violations: Option<u32>,
+    /// Maximum number of clients connected at once.
+    #[structopt(long)]
+    max_clients: Option<usize>,
+    /// Maximum number of clients connected at once from the same IP address.
+    #[structopt(long)]
+    max_clients_per_ip: Option<usize>,
+    /// How long clients have to join once connected, in milliseconds.
+    #[structopt(long)]
+    handshake_timeout_ms: Option<u64>,
+    /// How long clients may stay silent before being pinged, in milliseconds.
+    #[structopt(long)]
+    ping_interval_ms: Option<u64>,
+    /// How long clients have to answer a ping before being disconnected, in milliseconds.
+    #[structopt(long)]
+    ping_timeout_ms: Option<u64>,
+    /// How long clients have to receive their pending messages on shutdown, in milliseconds.
+    #[structopt(long)]
+    shutdown_timeout_ms: Option<u64>,
+    /// The most verbose level of log messages to print.
+    #[structopt(long)]
+    log_level: Option<Level>,
+}
+
+impl Opt {
+    /// Loads the config file, if any, and applies the options given on the command line to it.
+    fn into_config(self) -> Result<ServerConfig, Error> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+
+        if let Some(port) = self.port {
+            for addr in &mut config.bind {
+                addr.set_port(port);
+            }
+        }
+        if !self.bind.is_empty() {
+            config.bind = self.bind;
+        }
+        if !self.irc_bind.is_empty() {
+            config.irc_bind = self.irc_bind;
+        }
+        if !self.ws_bind.is_empty() {
+            config.websocket_bind = self.ws_bind;
+        }
+        if !self.tls_bind.is_empty() {
+            // structopt makes sure the certificate and key are given along with the address.
+            config.tls = Some(TlsConfig {
+                bind: self.tls_bind,
+                cert: self.tls_cert.unwrap_or_default(),
+                key: self.tls_key.unwrap_or_default(),
+            });
+        }
+        config.channel_capacity = self.channel_capacity.unwrap_or(config.channel_capacity);
+        config.channel_shards = self.channel_shards.unwrap_or(config.channel_shards);
+        config.slow_consumer = self.slow_consumer.unwrap_or(config.slow_consumer);
+        config.outbound_queue = self.outbound_queue.unwrap_or(config.outbound_queue);
+        config.max_line_length = self.max_line_length.unwrap_or(config.max_line_length);
+        config.max_name_length = self.max_name_length.unwrap_or(config.max_name_length);
+        // Any of the history options turns the history on.
+        if self.history_capacity.is_some()
+            || self.history_channels.is_some()
+            || self.history_replay.is_some()
+            || self.history_file.is_some()
+        {
+            let history = config.history.get_or_insert_with(HistoryConfig::default);
+            history.capacity = self.history_capacity.unwrap_or(history.capacity);
+            history.channels = self.history_channels.unwrap_or(history.channels);
+            history.replay = self.history_replay.unwrap_or(history.replay);
+            history.file = self.history_file.or_else(|| history.file.take());
+        }
+        if self.accounts || self.accounts_file.is_some() {
+            let accounts = config.accounts.get_or_insert_with(AccountsConfig::default);
+            accounts.file = self.accounts_file.or_else(|| accounts.file.take());
+        }
+        // Any of the rate limit options turns the limit on.
+        if self.rate_limit.is_some()
+            || self.rate_limit_burst.is_some()
+            || self.flood_action.is_some()
+            || self.max_violations.is_some()
+        {
+            let rate_limit = config
+                .rate_limit
+                .get_or_insert_with(RateLimitConfig::default);
+            rate_limit.per_second = self.rate_limit.unwrap_or(rate_limit.per_second);
+            rate_limit.burst = self.rate_limit_burst.unwrap_or(rate_limit.burst);
+            rate_limit.action = self.flood_action.unwrap_or(rate_limit.action);
+            rate_limit.max_violations = self.max_violations.unwrap_or(rate_limit.max_violations);
+        }
+        config.max_clients = self.max_clients.or(config.max_clients);
+        config.max_clients_per_ip = self.max_clients_per_ip.or(config.max_clients_per_ip);
+        config.handshake_timeout_ms = self
+            .handshake_timeout_ms
+            .unwrap_or(config.handshake_timeout_ms);
+        config.ping_interval_ms = self.ping_interval_ms.unwrap_or(config.ping_interval_ms);
+        config.ping_timeout_ms = self.ping_timeout_ms.unwrap_or(config.ping_timeout_ms);
+        config.shutdown_timeout_ms = self
+            .shutdown_timeout_ms
+            .unwrap_or(config.shutdown_timeout_ms);
+        config.log_level = self.log_level.unwrap_or(config.log_level);
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Waits for the process to be asked to stop, with SIGINT or SIGTERM.
+#[cfg(unix)]
+async fn stop_signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Waits for the process to be asked to stop, with Ctrl-C.
+#[cfg(not(unix))]
+async fn stop_signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Error> {
+    // Parse CLI args, along with the config file they may point to
+    let config = Opt::from_args()
+        .into_config()
+        .with_context(|| "failed to load config")?;
+
     // Configures a `tracing` subscriber using UTC time.
     tracing_subscriber::fmt()
         .with_ansi(true)
         .with_timer(ChronoUtc::default())
-        .with_max_level(Level::DEBUG)
+        .with_max_level(config.log_level)
         .init();
 
-    // Parse CLI args
-    let opt = Opt::from_args();
-
-    // Construct bind address for server
-    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), opt.port);
-
-    // Create and bind the server to the address
-    let mut server = Server::new(&addr)
+    // Create and bind the server to the configured addresses
+    let mut server = Server::new(config)
         .await
         .with_context(|| "failed to create chat server")?;
-    info!("created server at {}", addr);
+    if let Some(addr) = server.local_addr()? {
+        info!("created server at {}", addr);
+    }
+    if let Some(addr) = server.tls_local_addr()? {
+        info!("accepting tls clients at {}", addr);
+    }
+    if let Some(addr) = server.irc_local_addr()? {
+        info!("accepting irc clients at {}", addr);
+    }
+    if let Some(addr) = server.websocket_local_addr()? {
+        info!("accepting websocket clients at {}", addr);
+    }
+
+    // Shut down gracefully once we're asked to stop
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        match stop_signal().await {
+            Ok(()) => {
+                info!("received stop signal");
+                shutdown.shutdown();
+            }
+            Err(e) => error!("failed to listen for stop signals: {}", e),
+        }
+    });
 
-    // Start listening for clients
+    // Start listening for clients, until the server shuts down
     server
         .listen()
         .await
@@ -0,0 +1,108 @@
+//! Writing lines to clients in the background.
+//!
+//! Each connection queues the lines for its client in an [`Outbound`], which a task of its own
+//! writes to the socket. The connection keeps taking messages from its channels and commands
+//! from its client while the socket is slow, for as long as the queue has room, which is how
+//! [`SlowConsumerPolicy::Buffer`] lets clients catch up without missing messages.
+
+use std::{io, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::codec::Framed;
+
+use crate::{
+    codec::{ChatCodec, ChatCodecError, Frame, LineCodec},
+    config::{ServerConfig, SlowConsumerPolicy},
+};
+
+/// How long the lines still queued once a connection is done have to be written.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The lines received from a client, once its [`ChatCodec`] was split by [`Outbound::spawn`].
+pub(crate) type Lines<S> = futures::stream::SplitStream<Framed<S, LineCodec>>;
+
+/// A queue of lines being written to a client, already encoded so that lines shared with other
+/// clients are written without being copied first.
+pub(crate) struct Outbound {
+    tx: mpsc::Sender<Frame>,
+    writer: Writer,
+}
+
+/// The task writing the lines queued in an [`Outbound`], which is aborted once dropped so a
+/// stuck socket can't outlive its connection.
+struct Writer(JoinHandle<Result<(), ChatCodecError>>);
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Outbound {
+    /// Splits `chat` into the lines received from the client, and a queue of the lines to write
+    /// to them.
+    ///
+    /// The queue holds [`ServerConfig::outbound_queue`] lines with
+    /// [`SlowConsumerPolicy::Buffer`], and a single one otherwise.
+    pub(crate) fn spawn<S>(chat: ChatCodec<S>, config: &ServerConfig) -> (Self, Lines<S>)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let capacity = match config.slow_consumer {
+            SlowConsumerPolicy::Buffer => config.outbound_queue,
+            SlowConsumerPolicy::Notify | SlowConsumerPolicy::Disconnect => 1,
+        };
+        let (mut sink, lines) = chat.into_framed().split();
+        let (tx, rx) = mpsc::channel::<Frame>(capacity);
+        let writer = tokio::spawn(async move {
+            // Lines are flushed whenever the queue runs dry, so bursts are written together.
+            sink.send_all(&mut ReceiverStream::new(rx).map(Ok)).await?;
+            sink.close().await
+        });
+        (
+            Self {
+                tx,
+                writer: Writer(writer),
+            },
+            lines,
+        )
+    }
+
+    /// Waits until a line can be queued without waiting for the client to catch up.
+    ///
+    /// This is cancel safe, and returns right away once the connection is closed, so the next
+    /// [`Outbound::send`] fails.
+    pub(crate) async fn ready(&self) {
+        // Lines are only ever queued by the connection itself, so the room is still there once
+        // the permit is given back.
+        self.tx.reserve().await.ok();
+    }
+
+    /// Queues `line`, waiting for room if the queue is full.
+    pub(crate) async fn send(&self, line: impl Into<Frame>) -> Result<(), ChatCodecError> {
+        self.tx
+            .send(line.into())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+
+    /// Waits for every queued line to be written, then closes the connection.
+    ///
+    /// The client only gets a little while to take the lines, after which they are dropped.
+    pub(crate) async fn finish(self) -> Result<(), ChatCodecError> {
+        let Self { tx, mut writer } = self;
+        drop(tx);
+        match time::timeout(FLUSH_TIMEOUT, &mut writer.0).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e).into()),
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        }
+    }
+}
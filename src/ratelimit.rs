@@ -0,0 +1,176 @@
+//! Flood protection for the clients of a [`Server`](crate::server::Server).
+//!
+//! Without it a single client can send lines as fast as its connection allows, filling the
+//! channels it's in faster than the other members can keep up. Each connection gets a
+//! [`RateLimiter`], configured by [`RateLimitConfig`], and every connection's limiter reports to
+//! the same [`FloodStats`].
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::time::{self, Instant};
+
+use crate::config::{FloodAction, RateLimitConfig};
+
+/// Counts of what the rate limiters of every connection did, since the server started.
+#[derive(Debug, Default)]
+pub struct FloodStats {
+    throttled_users: AtomicU64,
+    delayed_lines: AtomicU64,
+    dropped_lines: AtomicU64,
+    disconnected_users: AtomicU64,
+}
+
+impl FloodStats {
+    /// Number of connections that sent lines too fast at least once.
+    pub fn throttled_users(&self) -> u64 {
+        self.throttled_users.load(Ordering::Relaxed)
+    }
+
+    /// Number of lines held back with [`FloodAction::Delay`].
+    pub fn delayed_lines(&self) -> u64 {
+        self.delayed_lines.load(Ordering::Relaxed)
+    }
+
+    /// Number of lines dropped with [`FloodAction::Drop`] or [`FloodAction::Disconnect`].
+    pub fn dropped_lines(&self) -> u64 {
+        self.dropped_lines.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections closed with [`FloodAction::Disconnect`].
+    pub fn disconnected_users(&self) -> u64 {
+        self.disconnected_users.load(Ordering::Relaxed)
+    }
+
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// What to do with a line the client sent, see [`RateLimiter::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// Handle the line.
+    Allow,
+    /// Ignore the line, telling the client why if `notify` is set.
+    Drop { notify: bool },
+    /// Ignore the line, and close the connection.
+    Disconnect,
+}
+
+/// Holds up to `capacity` tokens, refilled at a steady rate.
+struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    fn new(config: &RateLimitConfig) -> Self {
+        let capacity = f64::from(config.burst.get());
+        Self {
+            capacity,
+            per_second: f64::from(config.per_second.get()),
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long until there is one.
+    fn take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.per_second;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+/// Limits how fast the client of a single connection may send lines.
+pub(crate) struct RateLimiter {
+    /// The tokens left for the client, unless they aren't limited.
+    bucket: Option<TokenBucket>,
+    action: FloodAction,
+    max_violations: u32,
+    /// Number of lines in a row that were sent too fast.
+    violations: u32,
+    /// Whether the client ever sent lines too fast.
+    throttled: bool,
+    stats: Arc<FloodStats>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter following `config`, or one letting every line through if there is
+    /// none.
+    pub(crate) fn new(config: Option<&RateLimitConfig>, stats: Arc<FloodStats>) -> Self {
+        let defaults = RateLimitConfig::default();
+        Self {
+            bucket: config.map(TokenBucket::new),
+            action: config.map_or(defaults.action, |c| c.action),
+            max_violations: config.map_or(defaults.max_violations, |c| c.max_violations),
+            violations: 0,
+            throttled: false,
+            stats,
+        }
+    }
+
+    /// Decides what to do with the line the client just sent.
+    ///
+    /// With [`FloodAction::Delay`], this waits until the client may send the line. With
+    /// [`FloodAction::Drop`] and [`FloodAction::Disconnect`], the client is only notified of the
+    /// first line dropped in a row, so the notices can't flood them in turn.
+    pub(crate) async fn check(&mut self) -> Verdict {
+        let bucket = match &mut self.bucket {
+            Some(bucket) => bucket,
+            None => return Verdict::Allow,
+        };
+        let wait = match bucket.take() {
+            Ok(()) => {
+                self.violations = 0;
+                return Verdict::Allow;
+            }
+            Err(wait) => wait,
+        };
+
+        if !self.throttled {
+            self.throttled = true;
+            FloodStats::count(&self.stats.throttled_users);
+        }
+        match self.action {
+            FloodAction::Delay => {
+                FloodStats::count(&self.stats.delayed_lines);
+                time::sleep(wait).await;
+                // Rounding may leave the bucket a hair short of a token.
+                while let Err(wait) = bucket.take() {
+                    time::sleep(wait).await;
+                }
+                Verdict::Allow
+            }
+            FloodAction::Disconnect if self.violations >= self.max_violations => {
+                FloodStats::count(&self.stats.disconnected_users);
+                Verdict::Disconnect
+            }
+            FloodAction::Drop | FloodAction::Disconnect => {
+                FloodStats::count(&self.stats.dropped_lines);
+                self.violations += 1;
+                Verdict::Drop {
+                    notify: self.violations == 1,
+                }
+            }
+        }
+    }
+}
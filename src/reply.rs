@@ -0,0 +1,390 @@
+//! Replies sent by the [`Server`](crate::server::Server) to its clients.
+//!
+//! Every error reply is a single line in the form `ERR CODE NAME TEXT`, where `CODE` is a stable
+//! three digit number, `NAME` its upper case mnemonic, and `TEXT` a human readable description of
+//! what went wrong, e.g. `ERR 433 NICK_IN_USE foo`.
+//!
+//! Queries such as `NAMES` are answered with any number of lines starting with the command's
+//! verb, followed by an [`end_line`], e.g. `END NAMES rust`. Queries about a single thing, such
+//! as `TOPIC`, are answered with a single line and no end line.
+
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    channel::ChannelModes,
+    command::{ModeChange, Verb},
+};
+
+/// The kind of error an [`ErrorReply`] refers to.
+///
+/// Each code corresponds to one of the [`ServerError`](crate::server::ServerError) variants that
+/// can be caused by a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The user isn't in any channel to send messages to.
+    NoChannel,
+    /// The command sent by the user is malformed.
+    InvalidCommand,
+    /// The username is already in use on the server.
+    NickInUse,
+    /// The user isn't a member of the channel they referred to.
+    NotInChannel,
+    /// The join command the connection must begin with is malformed.
+    InvalidJoin,
+    /// The user attempted to pick a username a second time.
+    AlreadyJoined,
+    /// The user was too slow to read messages, and some of them were dropped.
+    MessagesDropped,
+    /// No user by the given name is connected.
+    NoSuchUser,
+    /// The username is registered, and the user hasn't identified as its owner.
+    NameReserved,
+    /// The user attempted to register a username a second time.
+    AlreadyRegistered,
+    /// The password doesn't match the one the username was registered with.
+    PasswordMismatch,
+    /// The command requires the user to be an operator of the channel.
+    NotOperator,
+    /// The user the command refers to isn't a member of the channel.
+    UserNotInChannel,
+    /// The user is banned from the channel they attempted to join.
+    Banned,
+    /// The channel the user attempted to join is invite-only, and they weren't invited.
+    InviteOnly,
+    /// The channel the user attempted to join has a key, and they didn't give it.
+    BadChannelKey,
+    /// The channel the user attempted to join has as many members as it's allowed.
+    ChannelFull,
+    /// The channel is moderated, and the user may not speak in it.
+    CannotSpeak,
+    /// The user the command refers to is already a member of the channel.
+    UserOnChannel,
+    /// The user is sending lines faster than they are allowed to.
+    RateLimited,
+    /// The server has as many clients as it's allowed, and refused the connection.
+    ServerFull,
+    /// There are as many clients from the same IP address as allowed, and the server refused
+    /// the connection.
+    TooManyConnections,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 22] = [
+        ErrorCode::NoChannel,
+        ErrorCode::InvalidCommand,
+        ErrorCode::NickInUse,
+        ErrorCode::NotInChannel,
+        ErrorCode::InvalidJoin,
+        ErrorCode::AlreadyJoined,
+        ErrorCode::MessagesDropped,
+        ErrorCode::NoSuchUser,
+        ErrorCode::NameReserved,
+        ErrorCode::AlreadyRegistered,
+        ErrorCode::PasswordMismatch,
+        ErrorCode::NotOperator,
+        ErrorCode::UserNotInChannel,
+        ErrorCode::Banned,
+        ErrorCode::InviteOnly,
+        ErrorCode::BadChannelKey,
+        ErrorCode::ChannelFull,
+        ErrorCode::CannotSpeak,
+        ErrorCode::UserOnChannel,
+        ErrorCode::RateLimited,
+        ErrorCode::ServerFull,
+        ErrorCode::TooManyConnections,
+    ];
+
+    /// The numeric code sent on the wire.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::NoChannel => 404,
+            Self::InvalidCommand => 421,
+            Self::NickInUse => 433,
+            Self::NotInChannel => 442,
+            Self::InvalidJoin => 451,
+            Self::AlreadyJoined => 462,
+            Self::MessagesDropped => 490,
+            Self::NoSuchUser => 401,
+            Self::NameReserved => 435,
+            Self::AlreadyRegistered => 436,
+            Self::PasswordMismatch => 464,
+            Self::NotOperator => 482,
+            Self::UserNotInChannel => 441,
+            Self::Banned => 474,
+            Self::InviteOnly => 473,
+            Self::BadChannelKey => 475,
+            Self::ChannelFull => 471,
+            Self::CannotSpeak => 489,
+            Self::UserOnChannel => 443,
+            Self::RateLimited => 439,
+            Self::ServerFull => 491,
+            Self::TooManyConnections => 492,
+        }
+    }
+
+    /// The mnemonic sent on the wire alongside the numeric code.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NoChannel => "NO_CHANNEL",
+            Self::InvalidCommand => "INVALID_COMMAND",
+            Self::NickInUse => "NICK_IN_USE",
+            Self::NotInChannel => "NOT_IN_CHANNEL",
+            Self::InvalidJoin => "INVALID_JOIN",
+            Self::AlreadyJoined => "ALREADY_JOINED",
+            Self::MessagesDropped => "MESSAGES_DROPPED",
+            Self::NoSuchUser => "NO_SUCH_USER",
+            Self::NameReserved => "NAME_RESERVED",
+            Self::AlreadyRegistered => "ALREADY_REGISTERED",
+            Self::PasswordMismatch => "PASSWORD_MISMATCH",
+            Self::NotOperator => "NOT_OPERATOR",
+            Self::UserNotInChannel => "USER_NOT_IN_CHANNEL",
+            Self::Banned => "BANNED",
+            Self::InviteOnly => "INVITE_ONLY",
+            Self::BadChannelKey => "BAD_CHANNEL_KEY",
+            Self::ChannelFull => "CHANNEL_FULL",
+            Self::CannotSpeak => "CANNOT_SPEAK",
+            Self::UserOnChannel => "USER_ON_CHANNEL",
+            Self::RateLimited => "RATE_LIMITED",
+            Self::ServerFull => "SERVER_FULL",
+            Self::TooManyConnections => "TOO_MANY_CONNECTIONS",
+        }
+    }
+
+    /// Looks up the [`ErrorCode`] for a numeric `code`.
+    pub fn from_code(code: u16) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.code() == code)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.name())
+    }
+}
+
+/// An error reply, as sent by the server.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{code}: {text}")]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+
+    /// Parses an error reply out of a line sent by the server.
+    ///
+    /// Returns `None` if the line isn't an error reply.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut terms = line.splitn(4, ' ');
+        let _header = terms.next().filter(|&h| h == "ERR")?;
+        let code = terms.next()?.parse().ok().and_then(ErrorCode::from_code)?;
+        let _name = terms.next().filter(|&n| n == code.name())?;
+        let text = terms.next().unwrap_or_default();
+        Some(Self::new(code, text))
+    }
+
+    /// Renders the reply as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        format!("ERR {} {}", self.code, self.text)
+    }
+}
+
+/// The line ending the reply to `verb`, about `subject`.
+pub fn end_line(verb: Verb, subject: &str) -> String {
+    format!("END {} {}", verb, subject)
+}
+
+/// A line of the reply to `NAMES`, naming a member of the channel.
+///
+/// The line is in the form `NAMES CHANNEL USER`, and there is one for each member of the channel,
+/// in the order they joined it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamesReply {
+    pub channel: String,
+    pub user: String,
+}
+
+impl NamesReply {
+    /// Parses a line of the reply to `NAMES`.
+    ///
+    /// Returns `None` if the line isn't one.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut terms = line.split(' ');
+        let _verb = terms.next().filter(|&v| v == Verb::Names.as_str())?;
+        let channel = terms.next()?.to_owned();
+        let user = terms.next()?.to_owned();
+        match terms.next() {
+            Some(_) => None,
+            None => Some(Self { channel, user }),
+        }
+    }
+
+    /// Renders the reply as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", Verb::Names, self.channel, self.user)
+    }
+}
+
+/// A line of the reply to `WHO`, describing a channel the user is a member of.
+///
+/// The line is in the form `WHO USER CHANNEL CONNECTED_SINCE IDLE`, where `CONNECTED_SINCE` is
+/// when the user connected, e.g. `2021-05-01T12:30:00Z`, and `IDLE` the number of seconds since
+/// they last said something. There is one for each channel the user is a member of, or a single
+/// one with `*` as the channel if they are in none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoReply {
+    pub user: String,
+    pub channel: Option<String>,
+    pub connected_since: DateTime<Utc>,
+    pub idle: Duration,
+}
+
+impl WhoReply {
+    /// Parses a line of the reply to `WHO`.
+    ///
+    /// Returns `None` if the line isn't one.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut terms = line.split(' ');
+        let _verb = terms.next().filter(|&v| v == Verb::Who.as_str())?;
+        let user = terms.next()?.to_owned();
+        let channel = Some(terms.next()?).filter(|&c| c != "*").map(str::to_owned);
+        let connected_since = DateTime::parse_from_rfc3339(terms.next()?).ok()?;
+        let idle = Duration::from_secs(terms.next()?.parse().ok()?);
+        match terms.next() {
+            Some(_) => None,
+            None => Some(Self {
+                user,
+                channel,
+                connected_since: connected_since.with_timezone(&Utc),
+                idle,
+            }),
+        }
+    }
+
+    /// Renders the reply as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            Verb::Who,
+            self.user,
+            self.channel.as_deref().unwrap_or("*"),
+            self.connected_since
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.idle.as_secs()
+        )
+    }
+}
+
+/// A line of the reply to `LIST`, describing a channel.
+///
+/// The line is in the form `LIST CHANNEL MEMBERS [TOPIC]`, where `MEMBERS` is the number of
+/// members of the channel. There is one for each channel matching the pattern, by name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListReply {
+    pub channel: String,
+    pub members: usize,
+    pub topic: Option<String>,
+}
+
+impl ListReply {
+    /// Parses a line of the reply to `LIST`.
+    ///
+    /// Returns `None` if the line isn't one.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut terms = line.splitn(4, ' ');
+        let _verb = terms.next().filter(|&v| v == Verb::List.as_str())?;
+        let channel = terms.next()?.to_owned();
+        let members = terms.next()?.parse().ok()?;
+        let topic = terms.next().map(str::to_owned);
+        Some(Self {
+            channel,
+            members,
+            topic,
+        })
+    }
+
+    /// Renders the reply as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        let line = format!("{} {} {}", Verb::List, self.channel, self.members);
+        match &self.topic {
+            Some(topic) => format!("{} {}", line, topic),
+            None => line,
+        }
+    }
+}
+
+/// The reply to `TOPIC` without any text, giving the topic of a channel.
+///
+/// The line is in the form `TOPIC CHANNEL [TOPIC]`, without a topic if none was set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicReply {
+    pub channel: String,
+    pub topic: Option<String>,
+}
+
+impl TopicReply {
+    /// Parses the reply to `TOPIC`.
+    ///
+    /// Returns `None` if the line isn't one.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut terms = line.splitn(3, ' ');
+        let _verb = terms.next().filter(|&v| v == Verb::Topic.as_str())?;
+        let channel = terms.next().filter(|c| !c.is_empty())?.to_owned();
+        let topic = terms.next().map(str::to_owned);
+        Some(Self { channel, topic })
+    }
+
+    /// Renders the reply as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        match &self.topic {
+            Some(topic) => format!("{} {} {}", Verb::Topic, self.channel, topic),
+            None => format!("{} {}", Verb::Topic, self.channel),
+        }
+    }
+}
+
+/// The reply to `MODE` without any changes, giving the modes of a channel.
+///
+/// The line is in the form `MODE CHANNEL MODES`, where `MODES` is rendered as by
+/// [`ChannelModes`]'s `Display` implementation, e.g. `MODE rust +kl secret 10`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeReply {
+    pub channel: String,
+    pub modes: ChannelModes,
+}
+
+impl ModeReply {
+    /// Parses the reply to `MODE`.
+    ///
+    /// Returns `None` if the line isn't one.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut terms = line.split(' ');
+        let _verb = terms.next().filter(|&v| v == Verb::Mode.as_str())?;
+        let channel = terms.next().filter(|c| !c.is_empty())?.to_owned();
+        let flags = terms.next()?;
+        let mut modes = ChannelModes::default();
+        for change in ModeChange::parse_all(flags, &mut terms, usize::MAX).ok()? {
+            modes.apply(&change);
+        }
+        match terms.next() {
+            Some(_) => None,
+            None => Some(Self { channel, modes }),
+        }
+    }
+
+    /// Renders the reply as the line sent over the wire.
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", Verb::Mode, self.channel, self.modes)
+    }
+}
@@ -1,86 +1,464 @@
 //! Simple chat server
 
-use std::{io, net::SocketAddr};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use futures::{stream::StreamExt, SinkExt};
+use futures::{future, stream::StreamExt};
 use thiserror::Error;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpListener,
-    sync::broadcast::{
-        self,
-        error::{RecvError, SendError},
-    },
+    net::{TcpListener, TcpStream},
+    sync::{broadcast::error::SendError, mpsc, OwnedSemaphorePermit, Semaphore},
+    time,
 };
-use tracing::{debug, error, warn};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 use crate::{
+    accounts::{AccountError, Accounts},
+    channel::Channels,
     codec::{ChatCodec, ChatCodecError},
+    command::{Command, ParseError, Verb},
+    config::{ConfigError, ServerConfig, SlowConsumerPolicy},
+    history::{History, HistoryEntry, HistoryError},
+    irc,
+    json::{self, Event, WireFormat},
+    keepalive::{Keepalive, Silence},
+    outbound::{Lines, Outbound},
+    ratelimit::{FloodStats, RateLimiter, Verdict},
+    reply::{self, ErrorCode, ErrorReply, ListReply, ModeReply, NamesReply, TopicReply, WhoReply},
+    session::{Message, Received, Rendering, Session, Shared},
+    tls::{self, TlsError},
+    websocket::WsStream,
     ConcurrentMap,
 };
 
-/// Utility alias for the transmission portion of the message channel.
-type Tx = broadcast::Sender<String>;
-
-/// Utility alias for the map from channel name to (Vec<Users>, Transmitter).
-type Channels = ConcurrentMap<String, (Vec<String>, Tx)>;
+/// How long a refused client has to receive the reason, before the connection is closed anyway.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Error type for `Server` and associated methods.
 #[derive(Debug, Error)]
 pub enum ServerError {
+    #[error("invalid server config")]
+    Config(#[source] ConfigError),
     #[error("failed to bind to address `{0}`")]
     Bind(SocketAddr, #[source] io::Error),
+    #[error("failed to load tls certificate and key")]
+    LoadTls(#[source] TlsError),
+    #[error("failed to load message history")]
+    History(#[source] HistoryError),
+    #[error("failed to access registered accounts")]
+    Account(#[source] AccountError),
+    #[error("failed tls handshake with client at address `{0}`")]
+    TlsHandshake(SocketAddr, #[source] io::Error),
+    #[error("failed websocket handshake with client at address `{0}`")]
+    WebSocketHandshake(
+        SocketAddr,
+        #[source] Box<tokio_tungstenite::tungstenite::Error>,
+    ),
     #[error("never received join command from user at address `{0}`")]
     NoJoin(SocketAddr),
     #[error("invalid join command from user at address `{0}`")]
-    InvalidJoin(SocketAddr),
+    InvalidJoin(SocketAddr, #[source] ParseError),
+    #[error("invalid command from user at address `{0}`")]
+    InvalidCommand(SocketAddr, #[source] ParseError),
+    #[error("user `{0}` has already chosen a username")]
+    AlreadyJoined(String),
+    #[error("user `{0}` is not in any channel")]
+    NoChannel(String),
+    #[error("user `{0}` is not a member of channel `{1}`")]
+    NotInChannel(String, String),
+    #[error("user `{0}` is not an operator of channel `{1}`")]
+    NotOperator(String, String),
+    #[error("target user `{0}` is not a member of channel `{1}`")]
+    UserNotInChannel(String, String),
+    #[error("user `{0}` is banned from channel `{1}`")]
+    Banned(String, String),
+    #[error("user `{0}` was not invited to invite-only channel `{1}`")]
+    InviteOnly(String, String),
+    #[error("user `{0}` gave the wrong key for channel `{1}`")]
+    BadChannelKey(String, String),
+    #[error("user `{0}` cannot join channel `{1}`, it is full")]
+    ChannelFull(String, String),
+    #[error("user `{0}` may not speak in moderated channel `{1}`")]
+    CannotSpeak(String, String),
+    #[error("user `{0}` is already a member of channel `{1}`")]
+    UserOnChannel(String, String),
+    #[error("client at address `{0}` is sending lines too fast")]
+    RateLimited(SocketAddr),
+    #[error("client at address `{0}` was disconnected for flooding")]
+    Flooding(SocketAddr),
+    #[error("refused client at address `{0}`, the server is full")]
+    ServerFull(SocketAddr),
+    #[error("refused client at address `{0}`, too many clients from the same IP address")]
+    TooManyConnections(SocketAddr),
     #[error("failed to broadcast message")]
-    BroadcastMessage(#[source] SendError<String>),
+    BroadcastMessage(#[source] SendError<Message>),
     #[error("failed to send message to user at address `{0}`")]
     SendMessage(SocketAddr, #[source] ChatCodecError),
     #[error("failed to add user `{0}` to channel, username in use")]
     UserAlreadyInChannel(String),
+    #[error("failed to register user `{0}`, username in use")]
+    UserNameInUse(String),
+    #[error("no user named `{0}` is connected")]
+    NoSuchUser(String),
+    #[error("user `{0}` is too far behind to receive direct messages")]
+    UserLagging(String),
+    #[error("user name `{0}` is registered to someone else")]
+    NameReserved(String),
+    #[error("wrong password for user name `{0}`")]
+    AuthenticationFailed(String),
+    #[error("accounts are not enabled on this server")]
+    AccountsDisabled,
     #[error("failed to get local address of the server listener")]
     GetLocalAddress(#[source] io::Error),
+    #[error("user fell behind on channel `{0}`, {1} messages were dropped")]
+    Lagging(String, u64),
+    #[error("client at address `{0}` did not answer a ping in time")]
+    PingTimeout(SocketAddr),
+    #[error("client at address `{0}` was still connected when the shutdown deadline passed")]
+    ShutdownTimeout(SocketAddr),
+}
+
+impl ServerError {
+    /// The reply to send to the user, for errors caused by them.
+    pub fn reply(&self) -> Option<ErrorReply> {
+        let reply = match self {
+            Self::InvalidJoin(_, e) => ErrorReply::new(ErrorCode::InvalidJoin, e.to_string()),
+            Self::InvalidCommand(_, e) => ErrorReply::new(ErrorCode::InvalidCommand, e.to_string()),
+            Self::AlreadyJoined(user) => ErrorReply::new(ErrorCode::AlreadyJoined, user),
+            Self::NoChannel(_) => ErrorReply::new(ErrorCode::NoChannel, "not in any channel"),
+            Self::NotInChannel(_, chan) => ErrorReply::new(ErrorCode::NotInChannel, chan),
+            Self::UserAlreadyInChannel(user) | Self::UserNameInUse(user) => {
+                ErrorReply::new(ErrorCode::NickInUse, user)
+            }
+            Self::NoSuchUser(user) => ErrorReply::new(ErrorCode::NoSuchUser, user),
+            Self::NotOperator(_, chan) => ErrorReply::new(ErrorCode::NotOperator, chan),
+            Self::UserNotInChannel(user, chan) => ErrorReply::new(
+                ErrorCode::UserNotInChannel,
+                format!("{} is not in {}", user, chan),
+            ),
+            Self::Banned(_, chan) => ErrorReply::new(ErrorCode::Banned, chan),
+            Self::InviteOnly(_, chan) => ErrorReply::new(ErrorCode::InviteOnly, chan),
+            Self::BadChannelKey(_, chan) => ErrorReply::new(ErrorCode::BadChannelKey, chan),
+            Self::ChannelFull(_, chan) => ErrorReply::new(ErrorCode::ChannelFull, chan),
+            Self::CannotSpeak(_, chan) => ErrorReply::new(ErrorCode::CannotSpeak, chan),
+            Self::UserOnChannel(user, chan) => ErrorReply::new(
+                ErrorCode::UserOnChannel,
+                format!("{} is already in {}", user, chan),
+            ),
+            Self::UserLagging(user) => ErrorReply::new(
+                ErrorCode::MessagesDropped,
+                format!("message to {} dropped", user),
+            ),
+            Self::NameReserved(user) => ErrorReply::new(ErrorCode::NameReserved, user),
+            Self::Account(AccountError::AlreadyRegistered(user)) => {
+                ErrorReply::new(ErrorCode::AlreadyRegistered, user)
+            }
+            Self::AuthenticationFailed(user) => ErrorReply::new(ErrorCode::PasswordMismatch, user),
+            Self::AccountsDisabled => ErrorReply::new(ErrorCode::InvalidCommand, self.to_string()),
+            Self::RateLimited(_) => ErrorReply::new(
+                ErrorCode::RateLimited,
+                "sending too fast, lines are being dropped",
+            ),
+            Self::Flooding(_) => {
+                ErrorReply::new(ErrorCode::RateLimited, "disconnected for flooding")
+            }
+            Self::ServerFull(_) => {
+                ErrorReply::new(ErrorCode::ServerFull, "the server is full, try again later")
+            }
+            Self::TooManyConnections(addr) => ErrorReply::new(
+                ErrorCode::TooManyConnections,
+                format!("too many clients from {}", addr.ip()),
+            ),
+            Self::Lagging(chan, num_skipped) => ErrorReply::new(
+                ErrorCode::MessagesDropped,
+                format!("{} messages dropped from {}", num_skipped, chan),
+            ),
+            Self::Config(_)
+            | Self::Bind(..)
+            | Self::LoadTls(_)
+            | Self::History(_)
+            | Self::Account(_)
+            | Self::TlsHandshake(..)
+            | Self::WebSocketHandshake(..)
+            | Self::NoJoin(_)
+            | Self::BroadcastMessage(_)
+            | Self::SendMessage(..)
+            | Self::GetLocalAddress(_)
+            | Self::PingTimeout(_)
+            | Self::ShutdownTimeout(_) => return None,
+        };
+        Some(reply)
+    }
+}
+
+/// How the clients of a listener talk to the server.
+#[derive(Clone)]
+enum Frontend {
+    /// The native line protocol, see [`Server::handle_client`].
+    Native,
+    /// The native line protocol, over TLS.
+    Tls(TlsAcceptor),
+    /// IRC, see [`irc::handle_client`].
+    Irc,
+    /// The native line protocol, over WebSocket text frames, see [`WsStream`].
+    WebSocket,
+}
+
+/// Counts the connected clients, so new ones can be refused past the limits in the
+/// [`ServerConfig`].
+struct ConnectionLimits {
+    /// A permit for each client that may connect, if their number is limited.
+    permits: Option<Arc<Semaphore>>,
+    max_per_ip: Option<usize>,
+    /// The number of clients connected from each IP address, if that is limited.
+    per_ip: ConcurrentMap<IpAddr, usize>,
+}
+
+impl ConnectionLimits {
+    fn new(config: &ServerConfig) -> Self {
+        Self {
+            permits: config.max_clients.map(|n| Arc::new(Semaphore::new(n))),
+            max_per_ip: config.max_clients_per_ip,
+            per_ip: Default::default(),
+        }
+    }
+
+    /// Counts a new client connected from `addr`, unless that would exceed a limit.
+    fn admit(&self, addr: SocketAddr) -> Result<ConnectionSlot, ServerError> {
+        let mut slot = ConnectionSlot {
+            permit: None,
+            per_ip: None,
+        };
+        if let Some(max) = self.max_per_ip {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(addr.ip()).or_default();
+            if *count >= max {
+                return Err(ServerError::TooManyConnections(addr));
+            }
+            *count += 1;
+            slot.per_ip = Some((self.per_ip.clone(), addr.ip()));
+        }
+        // Should the server be full, dropping the slot gives back what it already counted.
+        if let Some(permits) = &self.permits {
+            let permit = permits.clone().try_acquire_owned();
+            slot.permit = Some(permit.map_err(|_| ServerError::ServerFull(addr))?);
+        }
+        Ok(slot)
+    }
+}
+
+/// A client's place in the [`ConnectionLimits`], which is given back once dropped.
+struct ConnectionSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    per_ip: Option<(ConcurrentMap<IpAddr, usize>, IpAddr)>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Some((per_ip, ip)) = &self.per_ip {
+            let mut per_ip = per_ip.lock().unwrap();
+            if let Some(count) = per_ip.get_mut(ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(ip);
+                }
+            }
+        }
+    }
 }
 
-/// This listens on the specified address for new clients, and then spawns tasks with
-/// `Server::handle_client` which deal with the receiving and sending of messages.
+/// A handle to stop a running [`Server`], see [`Server::shutdown_handle`].
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(CancellationToken);
+
+impl ShutdownHandle {
+    /// Asks the server to shut down.
+    ///
+    /// The server stops accepting clients right away, and [`Server::listen`] returns once every
+    /// client has been sent their pending messages and disconnected.
+    pub fn shutdown(&self) {
+        self.0.cancel();
+    }
+}
+
+/// This listens on the addresses in its [`ServerConfig`] for new clients, and then spawns tasks
+/// with `Server::handle_client` which deal with the receiving and sending of messages.
+///
+/// Depending on the config, the server also accepts TLS, IRC and WebSocket clients on other
+/// addresses. All clients share the same channels.
 pub struct Server {
-    listener: TcpListener,
-    channels: Channels,
+    listeners: Vec<(Frontend, TcpListener)>,
+    shared: Arc<Shared>,
+    limits: ConnectionLimits,
+    /// Cancelled once someone asks the server to shut down.
+    shutdown: CancellationToken,
 }
 
 impl Server {
-    /// Maximum number of messages to hold before we start dropping them from slow clients.
-    const MAX_MESSAGES: usize = 1000;
+    /// Construct a new [`Server`], binding it to every address in `config`.
+    #[tracing::instrument(skip(config))]
+    pub async fn new(config: ServerConfig) -> Result<Server, ServerError> {
+        config.validate().map_err(ServerError::Config)?;
+        let mut listeners = Vec::new();
+        for addr in &config.bind {
+            listeners.push((Frontend::Native, Self::bind(addr).await?));
+        }
+        if let Some(tls) = &config.tls {
+            let acceptor = tls::acceptor(&tls.cert, &tls.key).map_err(ServerError::LoadTls)?;
+            for addr in &tls.bind {
+                listeners.push((Frontend::Tls(acceptor.clone()), Self::bind(addr).await?));
+            }
+        }
+        for addr in &config.irc_bind {
+            listeners.push((Frontend::Irc, Self::bind(addr).await?));
+        }
+        for addr in &config.websocket_bind {
+            listeners.push((Frontend::WebSocket, Self::bind(addr).await?));
+        }
 
-    /// Construct a new [`Server`], binding it to the provided [`SocketAddr`].
-    #[tracing::instrument]
-    pub async fn new(addr: &SocketAddr) -> Result<Server, ServerError> {
-        let listener = TcpListener::bind(addr)
+        let history = match &config.history {
+            Some(history) => {
+                let (capacity, channels) = (history.capacity, history.channels);
+                Some(match &history.file {
+                    Some(path) => History::open(capacity, channels, config.channel_shards, path)
+                        .map_err(ServerError::History)?,
+                    None => History::new(capacity, channels, config.channel_shards),
+                })
+            }
+            None => None,
+        };
+        let accounts = match &config.accounts {
+            Some(accounts) => Some(Arc::new(match &accounts.file {
+                Some(path) => Accounts::open(path).map_err(ServerError::Account)?,
+                None => Accounts::new(),
+            })),
+            None => None,
+        };
+        let limits = ConnectionLimits::new(&config);
+
+        let shared = Shared {
+            channels: Channels::new(config.channel_shards),
+            users: Default::default(),
+            config: Arc::new(config),
+            history,
+            accounts,
+            flood_stats: Default::default(),
+            disconnect: CancellationToken::new(),
+        };
+        Ok(Self {
+            listeners,
+            shared: Arc::new(shared),
+            limits,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    async fn bind(addr: &SocketAddr) -> Result<TcpListener, ServerError> {
+        TcpListener::bind(addr)
             .await
-            .map_err(|e| ServerError::Bind(*addr, e))?;
+            .map_err(|e| ServerError::Bind(*addr, e))
+    }
+
+    /// Provide the address the [`Server`] is listening for native clients on, if any.
+    pub fn local_addr(&self) -> Result<Option<SocketAddr>, ServerError> {
+        self.frontend_addr(|f| matches!(f, Frontend::Native))
+    }
+
+    /// Provide the address the [`Server`] is listening for TLS clients on, if any.
+    pub fn tls_local_addr(&self) -> Result<Option<SocketAddr>, ServerError> {
+        self.frontend_addr(|f| matches!(f, Frontend::Tls(_)))
+    }
 
-        let channels = Default::default();
+    /// Provide the address the [`Server`] is listening for IRC clients on, if any.
+    pub fn irc_local_addr(&self) -> Result<Option<SocketAddr>, ServerError> {
+        self.frontend_addr(|f| matches!(f, Frontend::Irc))
+    }
 
-        Ok(Self { listener, channels })
+    /// Provide the address the [`Server`] is listening for WebSocket clients on, if any.
+    pub fn websocket_local_addr(&self) -> Result<Option<SocketAddr>, ServerError> {
+        self.frontend_addr(|f| matches!(f, Frontend::WebSocket))
     }
 
-    /// Provide the address the [`Server`] is listening on.
-    pub fn local_addr(&self) -> Result<SocketAddr, ServerError> {
-        self.listener
-            .local_addr()
-            .map_err(ServerError::GetLocalAddress)
+    fn frontend_addr(
+        &self,
+        is_frontend: impl Fn(&Frontend) -> bool,
+    ) -> Result<Option<SocketAddr>, ServerError> {
+        self.listeners
+            .iter()
+            .find(|(f, _)| is_frontend(f))
+            .map(|(_, l)| l.local_addr().map_err(ServerError::GetLocalAddress))
+            .transpose()
+    }
+
+    /// Provide the counts of what the rate limiters did, which keep being updated once the
+    /// [`Server`] is listening.
+    pub fn flood_stats(&self) -> Arc<FloodStats> {
+        self.shared.flood_stats.clone()
+    }
+
+    /// Provide the state every client of the [`Server`] shares, to serve connections that didn't
+    /// come through one of its listeners with [`Server::handle_client`].
+    pub fn shared(&self) -> Arc<Shared> {
+        self.shared.clone()
+    }
+
+    /// Provide a handle that can be used to shut the [`Server`] down, once it's listening.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
     }
 
     /// Start listening for new clients.
+    ///
+    /// This only returns after the server was asked to shut down, through a [`ShutdownHandle`],
+    /// and every client has disconnected.
     #[tracing::instrument(skip(self))]
     pub async fn listen(&mut self) -> Result<(), ServerError> {
-        tracing::info!("server listening");
+        info!("server listening");
+        // Every client task holds on to a clone of `done_tx`, so once they are all finished
+        // `done_rx` is closed.
+        let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+        let accept_all = future::join_all(
+            self.listeners
+                .iter()
+                .map(|(frontend, listener)| self.accept(frontend, listener, done_tx.clone())),
+        );
+        tokio::select! {
+            _ = accept_all => (),
+            _ = self.shutdown.cancelled() => (),
+        }
+        drop(done_tx);
+
+        info!("server shutting down");
+        // Let every channel know the server is closing, then have every client disconnect.
+        // Clients get to drain the messages that are already in their channels before they go.
+        self.shared.channels.for_each(|_, channel| {
+            channel.tx().send(Message::ServerClosing).ok();
+        });
+        self.shared.disconnect.cancel();
+
+        done_rx.recv().await;
+        info!("every client has disconnected");
+        // Whatever was said last still makes it to the history file.
+        if let Some(history) = &self.shared.history {
+            history.sync().await;
+        }
+        Ok(())
+    }
+
+    /// Accepts clients on `listener` until the server shuts down, spawning a task to handle each
+    /// of them.
+    async fn accept(&self, frontend: &Frontend, listener: &TcpListener, done: mpsc::Sender<()>) {
         loop {
             // wait for a new TcpStream.
-            let (socket, addr) = match self.listener.accept().await {
+            let (socket, addr) = match listener.accept().await {
                 Ok(x) => x,
                 Err(e) => {
                     error!("failed to accept new connection: {}", e);
@@ -88,170 +466,742 @@ impl Server {
                 }
             };
 
-            // clone the channels map. It's a [`ConcurrentMap`], so the clone is just the (cheap)
-            // clone of an [`Arc`].
-            let channels = self.channels.clone();
+            let admission = self.limits.admit(addr);
+
+            // Chat lines are tiny and latency sensitive, so we don't want Nagle's algorithm
+            // holding them back while waiting on delayed ACKs from the client.
+            if let Err(e) = socket.set_nodelay(true) {
+                warn!(
+                    "failed to set TCP_NODELAY on connection from `{}`: {}",
+                    addr, e
+                );
+            }
+
+            // The state shared by every client is behind an [`Arc`], so the clone is cheap.
+            let shared = self.shared.clone();
+            let frontend = frontend.clone();
+            let done = done.clone();
 
             // Spawn the client handler asynchronously.
             tokio::spawn(async move {
                 tracing::debug!("accepted connection");
-                if let Err(e) = Self::handle_client(channels, socket, addr).await {
+                let shutdown_timeout = shared.config.shutdown_timeout();
+                let disconnect = shared.disconnect.clone();
+                let handle = async move {
+                    // The slot lives as long as this future, so it's given back however the
+                    // client goes, even when the future is dropped at the shutdown deadline.
+                    let _slot = match admission {
+                        Ok(slot) => slot,
+                        Err(e) => return Self::refuse(shared, frontend, socket, addr, e).await,
+                    };
+                    match frontend {
+                        Frontend::Native => Self::handle_client(shared, socket, addr).await,
+                        Frontend::Tls(acceptor) => match acceptor.accept(socket).await {
+                            Ok(tls) => Self::handle_client(shared, tls, addr).await,
+                            Err(e) => Err(ServerError::TlsHandshake(addr, e)),
+                        },
+                        Frontend::Irc => irc::handle_client(shared, socket, addr).await,
+                        Frontend::WebSocket => {
+                            match tokio_tungstenite::accept_async(socket).await {
+                                Ok(ws) => {
+                                    Self::handle_client(shared, WsStream::new(ws), addr).await
+                                }
+                                Err(e) => Err(ServerError::WebSocketHandshake(addr, Box::new(e))),
+                            }
+                        }
+                    }
+                };
+                // Clients are given a little while to receive their last messages once the
+                // server shuts down, but no longer.
+                let deadline = async {
+                    disconnect.cancelled().await;
+                    time::sleep(shutdown_timeout).await;
+                };
+                let result = tokio::select! {
+                    result = handle => result,
+                    _ = deadline => Err(ServerError::ShutdownTimeout(addr)),
+                };
+                drop(done);
+                if let Err(e) = result {
                     warn!("failed to handle client conection: {}", e);
                 }
             });
         }
     }
 
-    /// Parses and validates the join command from an user.
+    /// Tells a client refused by the [`ConnectionLimits`] why, then closes the connection.
     ///
-    /// Users are expected to begin their connection to the server with a message specifying the
-    /// channel they'd like to join, and their username. To do this, they send a command in the
-    /// form `JOIN CHANNEL USERNAME`. If user `bernardo` wanted to join channel `rust`, for
-    /// example, he would begin his connection with `JOIN rust bernardo`.
-    ///
-    /// A number of restrictions exist on this initial string, which this function sets out to
-    /// validate, namely they are:
-    /// 1. The first term of the string _must_ be "JOIN".
-    /// 2. Channel and user names are not allowed any whitespace.
-    /// 3. Channel and user names may not be longer than 20 characters.
-    /// 4. Only three terms, `JOIN`, `channel_name`, and `username` may be given, and no more.
-    pub fn parse_join_command(join_cmd: &str) -> Option<(&str, &str)> {
-        let mut cmd_terms = join_cmd
-            .split(' ')
-            .filter(|term| term.len() <= 20)
-            .filter(|term| !term.chars().any(|c| c.is_whitespace()));
-
-        let _header = cmd_terms.next().filter(|&h| h == "JOIN")?;
-        let chan_name = cmd_terms.next()?;
-        let user_name = cmd_terms.next()?;
-
-        if let Some(_canary) = cmd_terms.next() {
-            // if we find a canary, the join command has too many arguments and is invalid
-            return None;
+    /// Always returns `refusal`, once the client was told about it or failed to be.
+    async fn refuse(
+        shared: Arc<Shared>,
+        frontend: Frontend,
+        socket: TcpStream,
+        addr: SocketAddr,
+        refusal: ServerError,
+    ) -> Result<(), ServerError> {
+        let tell = async {
+            match frontend {
+                Frontend::Native => Self::send_refusal(&shared, socket, addr, &refusal).await,
+                Frontend::Tls(acceptor) => match acceptor.accept(socket).await {
+                    Ok(tls) => Self::send_refusal(&shared, tls, addr, &refusal).await,
+                    Err(e) => Err(ServerError::TlsHandshake(addr, e)),
+                },
+                Frontend::Irc => irc::refuse(&shared, socket, addr, &refusal).await,
+                Frontend::WebSocket => match tokio_tungstenite::accept_async(socket).await {
+                    Ok(ws) => Self::send_refusal(&shared, WsStream::new(ws), addr, &refusal).await,
+                    Err(e) => Err(ServerError::WebSocketHandshake(addr, Box::new(e))),
+                },
+            }
+        };
+        // A client too slow to take the reason is simply disconnected.
+        if let Ok(Err(e)) = time::timeout(REFUSAL_TIMEOUT, tell).await {
+            debug!(
+                "failed to tell client at `{}` why it was refused: {}",
+                addr, e
+            );
         }
+        Err(refusal)
+    }
 
-        Some((chan_name, user_name))
+    /// Sends the reply for `refusal` to a native client, then closes the connection.
+    async fn send_refusal<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        shared: &Shared,
+        stream: S,
+        addr: SocketAddr,
+        refusal: &ServerError,
+    ) -> Result<(), ServerError> {
+        let (out, _) = Outbound::spawn(ChatCodec::new(stream), &shared.config);
+        // The client is refused before it could pick another wire format.
+        Self::send_error(&out, WireFormat::Text, addr, refusal).await?;
+        out.finish()
+            .await
+            .map_err(|e| ServerError::SendMessage(addr, e))
     }
 
     /// Handle the connection to a single client.
     ///
     /// This function remains running for as long as the connection to the client is unbroken.
-    #[tracing::instrument(skip(channels, stream))]
-    pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
-        channels: Channels,
+    ///
+    /// After the initial `JOIN CHANNEL USERNAME` handshake (see [`Command::parse_join`]), which
+    /// may be preceded by `IDENTIFY`, every line is parsed as a [`Command`]. Plain text is sent
+    /// to the client's current channel, which is the channel they most recently joined or
+    /// switched to with `JOIN`.
+    ///
+    /// Clients sending `PROTO json` as their very first line speak JSON lines instead, see
+    /// [`json`](crate::json).
+    ///
+    /// Once the server shuts down the client is sent the messages still waiting in their
+    /// channels, along with a notice that the server is closing, and the connection is closed.
+    #[tracing::instrument(skip(shared, stream))]
+    pub async fn handle_client<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        shared: Arc<Shared>,
         stream: S,
         addr: SocketAddr,
     ) -> Result<(), ServerError> {
         tracing::debug!("handling client");
         // Wrap the TcpStream in a ChatCodec. This makes it easy for us to write and read lines
-        // from the stream.
-        let mut chat = ChatCodec::new(stream);
+        // from the stream. Lines are written in the background, so a slow socket doesn't keep
+        // us from reading the client's commands.
+        let chat = ChatCodec::with_max_length(stream, shared.config.max_line_length);
+        let (out, mut lines) = Outbound::spawn(chat, &shared.config);
+        let result = Self::serve(shared, &out, &mut lines, addr).await;
+        // However the connection ended, the lines already queued still go out, such as the
+        // reply to the error that ended it.
+        if let Err(e) = out.finish().await {
+            debug!(
+                "failed to write last lines to client at address `{}`: {}",
+                addr, e
+            );
+        }
+        result
+    }
+
+    /// Runs the connection to a client, see [`Server::handle_client`].
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        shared: Arc<Shared>,
+        out: &Outbound,
+        lines: &mut Lines<S>,
+        addr: SocketAddr,
+    ) -> Result<(), ServerError> {
+        let disconnect = shared.disconnect.clone();
+        // Every line counts, the handshake included, so identifying can't be spammed either.
+        let mut limiter = RateLimiter::new(
+            shared.config.rate_limit.as_ref(),
+            shared.flood_stats.clone(),
+        );
 
-        // A join command must be provided by the user, else we don't know what to do with them.
-        let join_cmd = match chat.next().await {
-            Some(Ok(line)) => line,
-            _ => {
-                return Err(ServerError::NoJoin(addr));
+        // A join command must be provided by the user, in time, else we don't know what to do
+        // with them. The only thing they may do beforehand is identify, so they can join under a
+        // registered name.
+        let handshake_deadline = time::sleep(shared.config.handshake_timeout());
+        tokio::pin!(handshake_deadline);
+        let mut wire = WireFormat::Text;
+        let mut negotiable = true;
+        let mut identified_as = None;
+        let (chan_name, user_name, key) = loop {
+            let line = tokio::select! {
+                result = lines.next() => match result {
+                    Some(Ok(line)) => line,
+                    _ => {
+                        return Err(ServerError::NoJoin(addr));
+                    }
+                },
+                _ = &mut handshake_deadline => return Err(ServerError::NoJoin(addr)),
+                _ = disconnect.cancelled() => {
+                    return out
+                        .send(Self::closing_line(wire))
+                        .await
+                        .map_err(|e| ServerError::SendMessage(addr, e));
+                }
+            };
+
+            if !Self::throttle(out, wire, addr, &mut limiter).await? {
+                continue;
             }
-        };
 
-        // Validate the join command.
-        let (chan_name, user_name) = match Self::parse_join_command(&join_cmd) {
-            Some(x) => x,
-            None => {
-                chat.send("ERROR").await.ok();
-                return Err(ServerError::InvalidJoin(addr));
+            // The wire format may only be picked with the very first line.
+            if std::mem::take(&mut negotiable) {
+                if let Some(format) = WireFormat::parse_negotiation(&line) {
+                    match format {
+                        Ok(format) => {
+                            wire = format;
+                            Self::send_reply(out, wire, addr, Reply::Proto(format)).await?;
+                        }
+                        Err(e) => {
+                            let e = ServerError::InvalidCommand(addr, e);
+                            Self::send_error(out, wire, addr, &e).await?;
+                        }
+                    }
+                    continue;
+                }
             }
-        };
 
-        // We get a reference to the channel the user asked to join, or create a new channel
-        // if there is none under that name.
-        // Here we also take care to check that the name the user chose is unique, to avoid
-        // confusion.
-        let channel_tx = {
-            let mut channels = channels.lock().await;
-            let (users, tx) = channels
-                .entry(chan_name.into())
-                .or_insert((Vec::new(), broadcast::channel(Self::MAX_MESSAGES).0));
-            let user_name = user_name.to_owned();
-            if users.contains(&user_name) {
-                debug!(
-                    "user `{}@{}` attempted to join channel with unavailable username",
-                    user_name, addr
-                );
-                chat.send("ERROR").await.ok();
-                Err(ServerError::UserAlreadyInChannel(user_name))
-            } else {
-                users.push(user_name);
-                Ok(tx.clone())
+            let max_name_length = shared.config.max_name_length;
+            if let Ok(Command::Identify { user, password }) = wire.parse(&line, max_name_length) {
+                match Session::authenticate(&shared, &user, &password).await {
+                    Ok(()) => {
+                        Self::send_reply(out, wire, addr, Reply::Identified(user.clone())).await?;
+                        identified_as = Some(user);
+                    }
+                    Err(e) => Self::send_error(out, wire, addr, &e).await?,
+                }
+                continue;
             }
-        }?;
 
-        // Create a receiver for the user, this will allow them to read messages from the broadcast
-        // channel.
-        let mut channel_rx = channel_tx.subscribe();
+            // Validate the join command.
+            match wire.parse_join(&line, max_name_length) {
+                Ok(x) => break x,
+                Err(e) => {
+                    let e = ServerError::InvalidJoin(addr, e);
+                    Self::send_error(out, wire, addr, &e).await.ok();
+                    return Err(e);
+                }
+            }
+        };
 
-        // Broadcast to the channel that a new user has joined.
-        let join_msg = format!("{} has joined", user_name);
-        channel_tx
-            .send(join_msg)
-            .map_err(ServerError::BroadcastMessage)?;
+        let mut keepalive = Keepalive::new(&shared.config);
+        let mut session = match Session::register(shared, addr, user_name, identified_as) {
+            Ok(session) => session,
+            Err(e) => {
+                Self::send_error(out, wire, addr, &e).await.ok();
+                return Err(e);
+            }
+        };
+        if let Err(e) = session.join(&chan_name, key.as_deref()) {
+            Self::send_error(out, wire, addr, &e).await.ok();
+            return Err(e);
+        }
 
         // Process incoming messages until we disconnected (or fail.)
         loop {
             tokio::select! {
-                // A message was received in our channel, we pass it to the user over TCP.
-                result = channel_rx.recv() => match result {
-                    Ok(msg) => chat.send(&msg).await.map_err(|e| ServerError::SendMessage(addr, e))?,
-                    Err(RecvError::Closed) => {
-                        // The channel has no more senders. This should be impossible as a sender
-                        // is always kept by the Server, until there are no receivers when it is
-                        // dropped.
-                        unreachable!()
-                    },
-                    Err(RecvError::Lagged(num_skipped)) => {
-                        // The receiver is lagging, most likely due to this client being too slow.
-                        // We report this to the client, but attempt to keep going.
-                        chat.send("ERROR".to_owned()).await.map_err(|e| ServerError::SendMessage(addr, e))?;
-                        warn!("user `{}@{}` is lagging. {} messages skipped", user_name, addr, num_skipped);
-                    },
+                // A message was sent to the user, we pass it to them over TCP. While their
+                // socket is too slow to take it, the message waits in its channel instead.
+                received = async {
+                    out.ready().await;
+                    session.recv().await
+                } => match received {
+                    // The server is going away, there's no point in carrying on.
+                    received if received.is_server_closing() => {
+                        return Self::close(out, wire, &mut session).await
+                    }
+                    received => Self::deliver(out, wire, &session, received).await?,
+                },
+                _ = disconnect.cancelled() => return Self::close(out, wire, &mut session).await,
+                // The user was silent for a while, we check they are still there.
+                silence = keepalive.silence() => match silence {
+                    Silence::Ping => {
+                        let ping = match wire {
+                            WireFormat::Text => Verb::Ping.to_string(),
+                            WireFormat::Json => Event::Ping.to_line(),
+                        };
+                        out.send(ping)
+                            .await
+                            .map_err(|e| ServerError::SendMessage(addr, e))?
+                    }
+                    Silence::TimedOut => return Err(ServerError::PingTimeout(addr)),
                 },
                 // An event on the user's TCP socket has occured
-                result = chat.next() => match result {
-                    // A message was received, we broadcast it to the channel.
-                    Some(Ok(msg)) => {
-                        let msg = format!("{}: {}", user_name, msg);
-                        // channel.broadcast(&addr, &msg).await;
-                        channel_tx.send(msg).map_err(ServerError::BroadcastMessage)?;
+                result = lines.next() => match result {
+                    // A line was received, we run the command it contains.
+                    Some(Ok(line)) => {
+                        keepalive.heard();
+                        if !Self::throttle(out, wire, addr, &mut limiter).await? {
+                            continue;
+                        }
+                        match Self::handle_line(&mut session, wire, &line).await {
+                            Ok(Outcome::Continue) => (),
+                            Ok(Outcome::Reply(reply)) => {
+                                Self::send_reply(out, wire, addr, reply).await?
+                            }
+                            Ok(Outcome::Quit) => {
+                                debug!("user `{}@{}` quit", session.user_name, addr);
+                                return Ok(());
+                            }
+                            Err(e) => {
+                                debug!(
+                                    "failed to handle line from user `{}@{}`: {}",
+                                    session.user_name, addr, e
+                                );
+                                Self::send_error(out, wire, addr, &e).await?;
+                            }
+                        }
                     }
                     // Some form of error occured
                     Some(Err(e)) => {
-                        warn!("error while processing message from user `{}@{}` on channel `{}`: {}", user_name, addr, chan_name, e);
+                        warn!("error while processing message from user `{}@{}`: {}", session.user_name, addr, e);
                     }
                     // The stream is over, we are done!
                     None => {
-                        debug!("user `{}@{}` disconnected", user_name, addr);
+                        debug!("user `{}@{}` disconnected", session.user_name, addr);
                         break;
                     }
                 }
             }
         }
 
-        // If this line is reached the client is disconnected, therefore we must notify the channel
-        // and drop their receiver.
-        let leave_msg = format!("{} has left", user_name);
-        channel_tx
-            .send(leave_msg)
-            .map_err(ServerError::BroadcastMessage)?;
+        // If this line is reached the client is disconnected. The session is dropped on the way
+        // out, which notifies every channel they were in, the same as on any error path.
+        Ok(())
+    }
+
+    /// Passes a message received by `session` along to its user.
+    async fn deliver(
+        out: &Outbound,
+        wire: WireFormat,
+        session: &Session,
+        received: Received,
+    ) -> Result<(), ServerError> {
+        let addr = session.addr;
+        let (chan_name, result) = match received {
+            // Direct messages are never prefixed, they don't belong to any channel.
+            Received::Direct(msg) => {
+                let line = match wire {
+                    WireFormat::Text => msg.to_string(),
+                    WireFormat::Json => Event::from(msg).to_line(),
+                };
+                return out
+                    .send(line)
+                    .await
+                    .map_err(|e| ServerError::SendMessage(addr, e));
+            }
+            Received::Channel(chan_name, result) => (chan_name, result),
+        };
+        match result {
+            Ok(broadcast) => {
+                // Every member rendering the message the same way shares the same line.
+                let rendering = match (wire, Self::is_prefixed(session, &chan_name)) {
+                    (WireFormat::Json, _) => Rendering::Json,
+                    (WireFormat::Text, true) => Rendering::NativePrefixed,
+                    (WireFormat::Text, false) => Rendering::Native,
+                };
+                let frame = broadcast.frame(rendering, |msg| match rendering {
+                    Rendering::Json => Event::Message(msg.into()).to_line(),
+                    rendering => {
+                        let prefixed = rendering == Rendering::NativePrefixed;
+                        Self::render(prefixed, &chan_name, &msg.body)
+                    }
+                });
+                out.send(frame)
+                    .await
+                    .map_err(|e| ServerError::SendMessage(addr, e))
+            }
+            Err(BroadcastStreamRecvError::Lagged(num_skipped)) => {
+                // The receiver is lagging, most likely due to this client being too slow.
+                // We report this to the client, and keep going unless the policy says otherwise.
+                warn!(
+                    "user `{}@{}` is lagging on channel `{}`. {} messages skipped",
+                    session.user_name, addr, chan_name, num_skipped
+                );
+                let err = ServerError::Lagging(chan_name, num_skipped);
+                Self::send_error(out, wire, addr, &err).await?;
+                match session.config().slow_consumer {
+                    SlowConsumerPolicy::Disconnect => Err(err),
+                    SlowConsumerPolicy::Notify | SlowConsumerPolicy::Buffer => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Sends the user of `session` every message already waiting in their channels, followed by
+    /// a single notice that the server is closing.
+    ///
+    /// The user is not parted from their channels, as those are going away with the server.
+    async fn close(
+        out: &Outbound,
+        wire: WireFormat,
+        session: &mut Session,
+    ) -> Result<(), ServerError> {
+        debug!(
+            "disconnecting user `{}@{}` for shutdown",
+            session.user_name, session.addr
+        );
+        while let Some(received) = session.try_recv() {
+            if !received.is_server_closing() {
+                Self::deliver(out, wire, session, received).await?;
+            }
+        }
+        out.send(Self::closing_line(wire))
+            .await
+            .map_err(|e| ServerError::SendMessage(session.addr, e))
+    }
+
+    /// The notice that the server is closing, in `wire`.
+    fn closing_line(wire: WireFormat) -> String {
+        match wire {
+            WireFormat::Text => Message::ServerClosing.to_string(),
+            WireFormat::Json => Event::Closing.to_line(),
+        }
+    }
+
+    /// Formats a message received on `chan_name`, `prefixed` with the channel name or not.
+    ///
+    /// Users in a single channel get messages verbatim, while users in many channels get them
+    /// prefixed with the channel name so they can tell them apart, see [`Server::is_prefixed`].
+    fn render(prefixed: bool, chan_name: &str, msg: &Message) -> String {
+        if prefixed {
+            format!("[{}] {}", chan_name, msg)
+        } else {
+            msg.to_string()
+        }
+    }
+
+    /// Whether messages received on `chan_name` are prefixed with its name for the user of
+    /// `session`.
+    fn is_prefixed(session: &Session, chan_name: &str) -> bool {
+        // The notice of the user being kicked out comes from a channel they are no longer in.
+        session.channel_count() > 1 || !session.is_member(chan_name)
+    }
 
-        drop(channel_rx);
+    /// Parses and runs a line sent by the user of `session`, in `wire`.
+    async fn handle_line(
+        session: &mut Session,
+        wire: WireFormat,
+        line: &str,
+    ) -> Result<Outcome, ServerError> {
+        let cmd = wire
+            .parse(line, session.config().max_name_length)
+            .map_err(|e| ServerError::InvalidCommand(session.addr, e))?;
+        match cmd {
+            Command::Join {
+                user: Some(user), ..
+            } if user != session.user_name => {
+                return Err(ServerError::AlreadyJoined(session.user_name.clone()))
+            }
+            Command::Join { channel, key, .. } => session.join(&channel, key.as_deref())?,
+            Command::Part { channel } => {
+                let chan_name = match channel {
+                    Some(chan_name) => chan_name,
+                    None => session.current_channel()?.to_owned(),
+                };
+                session.part(&chan_name, None)?
+            }
+            Command::Say { channel, text } => session.say(&channel, &text)?,
+            Command::Msg { user, text } => session.message(&user, &text)?,
+            Command::Nick { user } => session.rename(&user)?,
+            Command::Kick { user, reason } => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.kick(&chan_name, &user, reason.as_deref())?
+            }
+            Command::Ban { mask } => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.set_ban(&chan_name, &mask, true)?
+            }
+            Command::Unban { mask } => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.set_ban(&chan_name, &mask, false)?
+            }
+            Command::Op { user } => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.set_operator(&chan_name, &user, true)?
+            }
+            Command::Deop { user } => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.set_operator(&chan_name, &user, false)?
+            }
+            Command::Mode { changes } if changes.is_empty() => {
+                let channel = session.current_channel()?.to_owned();
+                let modes = session.modes(&channel)?;
+                return Ok(Outcome::Reply(Reply::Mode(ModeReply { channel, modes })));
+            }
+            Command::Mode { changes } => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.set_modes(&chan_name, &changes)?
+            }
+            Command::Invite { user } => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.invite(&chan_name, &user)?;
+                let channel = chan_name;
+                return Ok(Outcome::Reply(Reply::Invited { user, channel }));
+            }
+            Command::Register { password } => {
+                session.register_account(&password).await?;
+                let user = session.user_name.clone();
+                return Ok(Outcome::Reply(Reply::Registered(user)));
+            }
+            Command::Identify { user, password } => {
+                session.identify(&user, &password).await?;
+                return Ok(Outcome::Reply(Reply::Identified(user)));
+            }
+            Command::Text(text) => {
+                let chan_name = session.current_channel()?.to_owned();
+                session.say(&chan_name, &text)?
+            }
+            Command::Quit { reason } => {
+                session.part_all(reason.as_deref())?;
+                return Ok(Outcome::Quit);
+            }
+            // Any line shows the user is still there, the keepalive already took note of it.
+            Command::Pong { .. } => (),
+            Command::Ping { token } => return Ok(Outcome::Reply(Reply::Pong(token))),
+            Command::History { channel, count } => {
+                let entries = session.history(&channel, count)?;
+                let prefixed = Self::is_prefixed(session, &channel);
+                return Ok(Outcome::Reply(Reply::History {
+                    channel,
+                    entries,
+                    prefixed,
+                }));
+            }
+            Command::Names { channel } => {
+                let channel = match channel {
+                    Some(chan_name) => chan_name,
+                    None => session.current_channel()?.to_owned(),
+                };
+                let users = session.roster(&channel).into_iter();
+                let users = users.map(|(user, _)| user).collect();
+                return Ok(Outcome::Reply(Reply::Names { channel, users }));
+            }
+            Command::Who { user } => {
+                let replies = session.who(&user)?;
+                return Ok(Outcome::Reply(Reply::Who { user, replies }));
+            }
+            Command::List { pattern } => {
+                let replies = session.list(pattern.as_deref());
+                return Ok(Outcome::Reply(Reply::List { pattern, replies }));
+            }
+            Command::Topic {
+                channel,
+                text: Some(text),
+            } => session.set_topic(&channel, &text)?,
+            Command::Topic {
+                channel,
+                text: None,
+            } => {
+                let topic = session.topic(&channel);
+                return Ok(Outcome::Reply(Reply::Topic(TopicReply { channel, topic })));
+            }
+        }
+        Ok(Outcome::Continue)
+    }
 
-        // Finally, if the channel is now empty, we can drop it.
-        if channel_tx.receiver_count() == 0 {
-            debug!("channel `{}` is now empty and will be deleted.", chan_name);
-            channels.lock().await.remove(chan_name);
+    /// Runs the line the user just sent past their rate limiter, returning whether it should be
+    /// handled.
+    ///
+    /// The user is told when their lines start being dropped, and an error is returned once
+    /// they should be disconnected.
+    async fn throttle(
+        out: &Outbound,
+        wire: WireFormat,
+        addr: SocketAddr,
+        limiter: &mut RateLimiter,
+    ) -> Result<bool, ServerError> {
+        match limiter.check().await {
+            Verdict::Allow => Ok(true),
+            Verdict::Drop { notify } => {
+                if notify {
+                    debug!("dropping lines from client at address `{}`", addr);
+                    Self::send_error(out, wire, addr, &ServerError::RateLimited(addr)).await?;
+                }
+                Ok(false)
+            }
+            Verdict::Disconnect => {
+                let e = ServerError::Flooding(addr);
+                Self::send_error(out, wire, addr, &e).await.ok();
+                Err(e)
+            }
         }
+    }
 
+    /// Sends the reply for `err` to the user, in `wire`, if it has one.
+    async fn send_error(
+        out: &Outbound,
+        wire: WireFormat,
+        addr: SocketAddr,
+        err: &ServerError,
+    ) -> Result<(), ServerError> {
+        let reply = match err.reply() {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        let line = match wire {
+            WireFormat::Text => reply.to_line(),
+            WireFormat::Json => Event::from(&reply).to_line(),
+        };
+        out.send(line)
+            .await
+            .map_err(|e| ServerError::SendMessage(addr, e))
+    }
+
+    /// Sends `reply` to the user, in `wire`.
+    async fn send_reply(
+        out: &Outbound,
+        wire: WireFormat,
+        addr: SocketAddr,
+        reply: Reply,
+    ) -> Result<(), ServerError> {
+        let lines = match wire {
+            WireFormat::Text => reply.into_lines(),
+            WireFormat::Json => vec![reply.into_event().to_line()],
+        };
+        for line in lines {
+            out.send(line)
+                .await
+                .map_err(|e| ServerError::SendMessage(addr, e))?;
+        }
         Ok(())
     }
 }
+
+/// What the connection loop should do once a line from the user was handled.
+enum Outcome {
+    /// Nothing, carry on.
+    Continue,
+    /// Send this reply back to the user.
+    Reply(Reply),
+    /// The user has left every channel, close the connection.
+    Quit,
+}
+
+/// The reply to a command, sent in the user's [`WireFormat`].
+enum Reply {
+    /// The wire format was switched, in answer to `PROTO`.
+    Proto(WireFormat),
+    Identified(String),
+    Registered(String),
+    Invited {
+        user: String,
+        channel: String,
+    },
+    Pong(Option<String>),
+    /// The last messages said in `channel`, `prefixed` with its name or not in the line
+    /// protocol, see [`Server::render`].
+    History {
+        channel: String,
+        entries: Vec<HistoryEntry>,
+        prefixed: bool,
+    },
+    Names {
+        channel: String,
+        users: Vec<String>,
+    },
+    Who {
+        user: String,
+        replies: Vec<WhoReply>,
+    },
+    List {
+        pattern: Option<String>,
+        replies: Vec<ListReply>,
+    },
+    Topic(TopicReply),
+    Mode(ModeReply),
+}
+
+impl Reply {
+    /// Renders the reply as the lines sent over the wire in the line protocol.
+    fn into_lines(self) -> Vec<String> {
+        let (mut lines, end): (Vec<String>, _) = match self {
+            Self::Proto(wire) => return vec![format!("{} {}", json::PROTO, wire.as_str())],
+            Self::Identified(user) => return vec![format!("{} {}", Verb::Identify, user)],
+            Self::Registered(user) => return vec![format!("{} {}", Verb::Register, user)],
+            Self::Invited { user, channel } => {
+                return vec![format!("{} {} {}", Verb::Invite, user, channel)]
+            }
+            Self::Pong(Some(token)) => return vec![format!("{} {}", Verb::Pong, token)],
+            Self::Pong(None) => return vec![Verb::Pong.to_string()],
+            Self::Topic(reply) => return vec![reply.to_line()],
+            Self::Mode(reply) => return vec![reply.to_line()],
+            Self::History {
+                channel,
+                entries,
+                prefixed,
+            } => {
+                let lines = entries
+                    .into_iter()
+                    .map(|entry| Server::render(prefixed, &channel, &Message::History(entry)));
+                (lines.collect(), reply::end_line(Verb::History, &channel))
+            }
+            Self::Names { channel, users } => {
+                let lines = users.into_iter().map(|user| {
+                    let channel = channel.clone();
+                    NamesReply { channel, user }.to_line()
+                });
+                (lines.collect(), reply::end_line(Verb::Names, &channel))
+            }
+            Self::Who { user, replies } => {
+                let lines = replies.iter().map(WhoReply::to_line);
+                (lines.collect(), reply::end_line(Verb::Who, &user))
+            }
+            Self::List { pattern, replies } => {
+                let lines = replies.iter().map(ListReply::to_line);
+                let end = reply::end_line(Verb::List, pattern.as_deref().unwrap_or("*"));
+                (lines.collect(), end)
+            }
+        };
+        lines.push(end);
+        lines
+    }
+
+    /// Renders the reply as a single event, for the JSON-lines protocol.
+    fn into_event(self) -> Event {
+        match self {
+            Self::Proto(wire) => Event::Proto {
+                format: wire.as_str().to_owned(),
+            },
+            Self::Identified(user) => Event::Identified { user },
+            Self::Registered(user) => Event::Registered { user },
+            Self::Invited { user, channel } => Event::Invited { user, channel },
+            Self::Pong(token) => Event::Pong { token },
+            Self::History {
+                channel, entries, ..
+            } => Event::History {
+                channel,
+                messages: entries,
+            },
+            Self::Names { channel, users } => Event::Names { channel, users },
+            Self::Who { user, replies } => {
+                // Users are described by at least one reply, see `Session::who`.
+                let (connected_since, idle) = (replies[0].connected_since, replies[0].idle);
+                Event::Who {
+                    user,
+                    channels: replies.into_iter().filter_map(|r| r.channel).collect(),
+                    connected_since,
+                    idle: idle.as_secs(),
+                }
+            }
+            Self::List { replies, .. } => Event::List { channels: replies },
+            Self::Topic(reply) => Event::Topic(reply),
+            Self::Mode(reply) => Event::Mode(reply),
+        }
+    }
+}
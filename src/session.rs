@@ -0,0 +1,1144 @@
+//! Channel membership of a single connection, independent of the protocol it speaks.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+
+use futures::{FutureExt, StreamExt};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamMap,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::{
+    accounts::Accounts,
+    channel::{self, Channel, ChannelModes, Channels, Member, Refusal, Tx},
+    codec::Frame,
+    command::ModeChange,
+    config::ServerConfig,
+    history::{History, HistoryEntry},
+    ratelimit::FloodStats,
+    reply::{ListReply, WhoReply},
+    server::ServerError,
+    ConcurrentMap, HashMap,
+};
+
+/// Utility alias for the map from user name to their [`Registration`].
+pub(crate) type Users = ConcurrentMap<String, Arc<Registration>>;
+
+/// A user connected to the server, see [`Session::register`].
+pub(crate) struct Registration {
+    /// The sender of the user's direct messages.
+    tx: mpsc::Sender<DirectMessage>,
+    connected_at: DateTime<Utc>,
+    /// When the user last said something, in a channel or directly to someone.
+    last_active: Mutex<Instant>,
+}
+
+/// A message broadcast to every member of a channel.
+///
+/// Each protocol renders these in its own way, the [`Display`](fmt::Display) implementation is
+/// the rendering used by the native line protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// `from` said `text`.
+    Text { from: String, text: String },
+    /// `user` joined the channel.
+    Joined { user: String },
+    /// `user` left the channel, optionally saying why.
+    Left {
+        user: String,
+        reason: Option<String>,
+    },
+    /// The user `old` changed their name to `new`.
+    Renamed { old: String, new: String },
+    /// `by` kicked `user` out of the channel, optionally saying why.
+    Kicked {
+        user: String,
+        by: String,
+        reason: Option<String>,
+    },
+    /// `by` made `user` an operator of the channel, or took it away if `is_operator` is false.
+    Operator {
+        user: String,
+        by: String,
+        is_operator: bool,
+    },
+    /// `by` banned the users matching `mask` from the channel, or lifted the ban if `is_banned`
+    /// is false.
+    Ban {
+        mask: String,
+        by: String,
+        is_banned: bool,
+    },
+    /// `by` changed the modes of the channel.
+    Mode { by: String, change: ModeChange },
+    /// `user` set the topic of the channel to `topic`.
+    Topic { user: String, topic: String },
+    /// A message said in the channel before, replayed from its history.
+    History(HistoryEntry),
+    /// The server is shutting down, and the connection is about to be closed.
+    ServerClosing,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text { from, text } => write!(f, "{}: {}", from, text),
+            Self::Joined { user } => write!(f, "{} has joined", user),
+            Self::Left { user, reason: None } => write!(f, "{} has left", user),
+            Self::Left {
+                user,
+                reason: Some(reason),
+            } => write!(f, "{} has left ({})", user, reason),
+            Self::Renamed { old, new } => write!(f, "{} is now known as {}", old, new),
+            Self::Kicked {
+                user,
+                by,
+                reason: None,
+            } => write!(f, "{} was kicked by {}", user, by),
+            Self::Kicked {
+                user,
+                by,
+                reason: Some(reason),
+            } => write!(f, "{} was kicked by {} ({})", user, by, reason),
+            Self::Operator {
+                user,
+                by,
+                is_operator: true,
+            } => write!(f, "{} made {} an operator", by, user),
+            Self::Operator {
+                user,
+                by,
+                is_operator: false,
+            } => write!(f, "{} took operator status from {}", by, user),
+            Self::Ban {
+                mask,
+                by,
+                is_banned: true,
+            } => write!(f, "{} banned {}", by, mask),
+            Self::Ban {
+                mask,
+                by,
+                is_banned: false,
+            } => write!(f, "{} lifted the ban on {}", by, mask),
+            Self::Mode { by, change } => write!(f, "{} set mode {}", by, change),
+            Self::Topic { user, topic } => write!(f, "{} set the topic to: {}", user, topic),
+            Self::History(entry) => entry.fmt(f),
+            Self::ServerClosing => f.write_str("server is shutting down"),
+        }
+    }
+}
+
+/// The broad kind of a [`ChatMessage`], for clients that don't care about every [`Message`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// Something a user said, live or replayed from the history.
+    Text,
+    /// A user joined the channel.
+    Join,
+    /// A user left the channel, or was kicked out of it.
+    Leave,
+    /// A change to the channel or its members, or a notice from the server.
+    System,
+}
+
+/// The id given to the next [`ChatMessage`].
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A [`Message`] broadcast to a channel, along with its envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    /// Unique across the server, and increasing in the order messages are received on each
+    /// channel.
+    pub id: u64,
+    /// When the message was sent, or originally said for replayed ones.
+    pub time: DateTime<Utc>,
+    /// The name of the channel the message was sent to.
+    pub channel: String,
+    pub body: Message,
+}
+
+impl ChatMessage {
+    /// Stamps `body`, sent to `chan_name` just now, with the next id.
+    pub(crate) fn new(chan_name: &str, body: Message) -> Self {
+        let time = match &body {
+            Message::History(entry) => entry.time,
+            _ => Utc::now(),
+        };
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            time,
+            channel: chan_name.to_owned(),
+            body,
+        }
+    }
+
+    /// The user the message comes from, if any.
+    pub fn sender(&self) -> Option<&str> {
+        match &self.body {
+            Message::Text { from, .. } => Some(from),
+            Message::Joined { user } | Message::Left { user, .. } | Message::Topic { user, .. } => {
+                Some(user)
+            }
+            Message::Renamed { old, .. } => Some(old),
+            Message::Kicked { by, .. }
+            | Message::Operator { by, .. }
+            | Message::Ban { by, .. }
+            | Message::Mode { by, .. } => Some(by),
+            Message::History(entry) => Some(&entry.from),
+            Message::ServerClosing => None,
+        }
+    }
+
+    /// The broad kind of the message.
+    pub fn kind(&self) -> MessageKind {
+        match &self.body {
+            Message::Text { .. } | Message::History(_) => MessageKind::Text,
+            Message::Joined { .. } => MessageKind::Join,
+            Message::Left { .. } | Message::Kicked { .. } => MessageKind::Leave,
+            Message::Renamed { .. }
+            | Message::Operator { .. }
+            | Message::Ban { .. }
+            | Message::Mode { .. }
+            | Message::Topic { .. }
+            | Message::ServerClosing => MessageKind::System,
+        }
+    }
+}
+
+/// The ways a [`Broadcast`] is rendered, each made once and shared by every connection that
+/// renders it that way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rendering {
+    /// As native clients in a single channel get it.
+    Native,
+    /// As native clients in many channels get it, prefixed with the channel name.
+    NativePrefixed,
+    /// As an IRC line.
+    Irc,
+    /// As a JSON [`Event`](crate::json::Event).
+    Json,
+}
+
+/// A [`ChatMessage`] broadcast to a channel, along with its renderings.
+///
+/// Every member receives a clone of the same broadcast, which only bumps a reference count.
+#[derive(Debug, Clone)]
+pub(crate) struct Broadcast(Arc<Rendered>);
+
+#[derive(Debug)]
+struct Rendered {
+    msg: ChatMessage,
+    /// The frames for each [`Rendering`], by discriminant.
+    frames: [OnceCell<Frame>; 4],
+}
+
+impl Broadcast {
+    pub(crate) fn message(&self) -> &Message {
+        &self.0.msg.body
+    }
+
+    /// The message rendered as `rendering`, which `render` makes unless another connection
+    /// already did.
+    pub(crate) fn frame(
+        &self,
+        rendering: Rendering,
+        render: impl FnOnce(&ChatMessage) -> String,
+    ) -> Frame {
+        self.0.frames[rendering as usize]
+            .get_or_init(|| render(&self.0.msg).into())
+            .clone()
+    }
+}
+
+impl From<ChatMessage> for Broadcast {
+    fn from(msg: ChatMessage) -> Self {
+        Self(Arc::new(Rendered {
+            msg,
+            frames: Default::default(),
+        }))
+    }
+}
+
+/// A message sent to a single user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectMessage {
+    /// `from` said `text` to the user alone, see [`Session::message`].
+    Text { from: String, text: String },
+    /// `from` invited the user to join `channel`, see [`Session::invite`].
+    Invite { from: String, channel: String },
+}
+
+impl fmt::Display for DirectMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text { from, text } => write!(f, "{} (private): {}", from, text),
+            Self::Invite { from, channel } => write!(f, "{} invited you to {}", from, channel),
+        }
+    }
+}
+
+/// Something received by a [`Session`], see [`Session::recv`].
+#[derive(Debug)]
+pub(crate) enum Received {
+    /// A message from the channel with the given name, or the number of messages the user missed
+    /// on it.
+    Channel(String, Result<Broadcast, BroadcastStreamRecvError>),
+    /// A message sent to the user alone.
+    Direct(DirectMessage),
+}
+
+impl Received {
+    /// Whether this is the notice that the server is shutting down.
+    pub(crate) fn is_server_closing(&self) -> bool {
+        matches!(self, Self::Channel(_, Ok(broadcast)) if *broadcast.message() == Message::ServerClosing)
+    }
+}
+
+/// The state of a [`Server`](crate::server::Server) every connection to it shares.
+pub struct Shared {
+    pub(crate) channels: Channels,
+    /// Every user connected to the server.
+    pub(crate) users: Users,
+    pub(crate) config: Arc<ServerConfig>,
+    /// The history of every channel, if it is kept.
+    pub(crate) history: Option<History>,
+    /// The registered user names, if users may register them.
+    pub(crate) accounts: Option<Arc<Accounts>>,
+    /// What the rate limiters of every connection did.
+    pub(crate) flood_stats: Arc<FloodStats>,
+    /// Cancelled once every client should disconnect, during shutdown.
+    pub(crate) disconnect: CancellationToken,
+}
+
+/// A channel a [`Session`] is a member of.
+struct Membership {
+    chan_name: String,
+    tx: Tx,
+}
+
+/// The state of a single client connection, namely who the user is and which channels they are
+/// a member of.
+pub(crate) struct Session {
+    shared: Arc<Shared>,
+    pub(crate) addr: SocketAddr,
+    pub(crate) user_name: String,
+    /// The channels this user is a member of, in the order they were joined (or switched to).
+    /// The last one is the current channel.
+    memberships: Vec<Membership>,
+    /// A receiver for each of the channels in `memberships`, keyed by channel name.
+    receivers: StreamMap<String, BroadcastStream<Broadcast>>,
+    /// The history of the channels just joined, to be replayed once the user is told they have
+    /// joined them.
+    pending_replay: HashMap<String, Vec<HistoryEntry>>,
+    /// History messages ready to be received, ahead of those from the channels.
+    replay: VecDeque<(String, HistoryEntry)>,
+    /// The registered name the user proved they own, if any, see [`Session::authenticate`].
+    identified_as: Option<String>,
+    /// The user's entry in [`Shared::users`].
+    registration: Arc<Registration>,
+    /// The receiver for the sender in `registration`.
+    direct_rx: mpsc::Receiver<DirectMessage>,
+}
+
+impl Session {
+    /// Registers `user_name` on the server, so that others can send them direct messages.
+    ///
+    /// User names are unique across the server, the name is free again once the session is
+    /// dropped. If registered names are reserved, the user must have identified as `user_name`
+    /// beforehand, see [`Session::authenticate`].
+    pub(crate) fn register(
+        shared: Arc<Shared>,
+        addr: SocketAddr,
+        user_name: String,
+        identified_as: Option<String>,
+    ) -> Result<Self, ServerError> {
+        Self::check_reserved(&shared, &user_name, identified_as.as_deref())?;
+        let (tx, direct_rx) = mpsc::channel(shared.config.channel_capacity);
+        let registration = Arc::new(Registration {
+            tx,
+            connected_at: Utc::now(),
+            last_active: Mutex::new(Instant::now()),
+        });
+        {
+            let mut users = shared.users.lock().unwrap();
+            if users.contains_key(&user_name) {
+                debug!(
+                    "user `{}@{}` attempted to register unavailable username",
+                    user_name, addr
+                );
+                return Err(ServerError::UserNameInUse(user_name));
+            }
+            users.insert(user_name.clone(), registration.clone());
+        }
+
+        Ok(Self {
+            shared,
+            addr,
+            user_name,
+            memberships: Vec::new(),
+            receivers: StreamMap::new(),
+            pending_replay: HashMap::default(),
+            replay: VecDeque::new(),
+            identified_as,
+            registration,
+            direct_rx,
+        })
+    }
+
+    /// Checks that `user_name` may be used by someone who identified as `identified_as`.
+    fn check_reserved(
+        shared: &Shared,
+        user_name: &str,
+        identified_as: Option<&str>,
+    ) -> Result<(), ServerError> {
+        let reserve_names = matches!(&shared.config.accounts, Some(config) if config.reserve_names);
+        match &shared.accounts {
+            Some(accounts)
+                if reserve_names
+                    && identified_as != Some(user_name)
+                    && accounts.is_registered(user_name) =>
+            {
+                Err(ServerError::NameReserved(user_name.into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that `password` is the one `user_name` was registered with.
+    pub(crate) async fn authenticate(
+        shared: &Shared,
+        user_name: &str,
+        password: &str,
+    ) -> Result<(), ServerError> {
+        let accounts = shared
+            .accounts
+            .as_ref()
+            .ok_or(ServerError::AccountsDisabled)?;
+        if accounts.verify(user_name, password).await {
+            Ok(())
+        } else {
+            debug!("failed attempt to identify as user `{}`", user_name);
+            Err(ServerError::AuthenticationFailed(user_name.into()))
+        }
+    }
+
+    /// Identifies the user as the owner of the registered name `user_name`, which lets them
+    /// change to it.
+    pub(crate) async fn identify(
+        &mut self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<(), ServerError> {
+        Self::authenticate(&self.shared, user_name, password).await?;
+        self.identified_as = Some(user_name.to_owned());
+        Ok(())
+    }
+
+    /// Registers the user's name with `password`, so that only they may use it from then on.
+    pub(crate) async fn register_account(&mut self, password: &str) -> Result<(), ServerError> {
+        let accounts = self
+            .shared
+            .accounts
+            .as_ref()
+            .ok_or(ServerError::AccountsDisabled)?;
+        accounts
+            .register(&self.user_name, password)
+            .await
+            .map_err(ServerError::Account)?;
+        self.identified_as = Some(self.user_name.clone());
+        Ok(())
+    }
+
+    /// Receives the next message sent to the user, either directly or in one of their channels.
+    ///
+    /// Right after the user's own `has joined` message, this yields the channel's recent history
+    /// as [`Message::History`]. Once it yields the user being [kicked](Message::Kicked) out of a
+    /// channel, they are no longer a member of it.
+    pub(crate) async fn recv(&mut self) -> Received {
+        if let Some((chan_name, entry)) = self.replay.pop_front() {
+            let msg = ChatMessage::new(&chan_name, Message::History(entry));
+            return Received::Channel(chan_name, Ok(msg.into()));
+        }
+        // The direct messages never run dry, their sender is registered for as long as the
+        // session lives.
+        let (chan_name, result) = tokio::select! {
+            Some(msg) = self.direct_rx.recv() => return Received::Direct(msg),
+            Some(x) = self.receivers.next() => x,
+        };
+        match result.as_ref().map(Broadcast::message) {
+            Ok(Message::Joined { user }) if user == &self.user_name => {
+                if let Some(entries) = self.pending_replay.remove(&chan_name) {
+                    let replay = entries.into_iter().map(|e| (chan_name.clone(), e));
+                    self.replay.extend(replay);
+                }
+            }
+            // The channel already let go of the user, this is the last they hear of it.
+            Ok(Message::Kicked { user, .. }) if user == &self.user_name => {
+                self.forget(&chan_name);
+            }
+            _ => (),
+        }
+        Received::Channel(chan_name, result)
+    }
+
+    /// Receives the next message sent to the user, if one is ready right now.
+    pub(crate) fn try_recv(&mut self) -> Option<Received> {
+        self.recv().now_or_never()
+    }
+
+    /// The settings of the server the user is connected to.
+    pub(crate) fn config(&self) -> &ServerConfig {
+        &self.shared.config
+    }
+
+    /// Whether the user is one of the server's admins, who are operators of every channel.
+    fn is_admin(&self) -> bool {
+        let is_identified =
+            self.shared.accounts.is_none() || self.identified_as.as_ref() == Some(&self.user_name);
+        is_identified && self.config().admins.contains(&self.user_name)
+    }
+
+    /// The number of channels the user is a member of.
+    pub(crate) fn channel_count(&self) -> usize {
+        self.memberships.len()
+    }
+
+    /// The names of the channels the user is a member of.
+    pub(crate) fn channel_names(&self) -> impl Iterator<Item = &str> {
+        self.memberships.iter().map(|m| m.chan_name.as_str())
+    }
+
+    /// Whether the user is a member of `chan_name`.
+    pub(crate) fn is_member(&self, chan_name: &str) -> bool {
+        self.membership(chan_name).is_some()
+    }
+
+    fn membership(&self, chan_name: &str) -> Option<&Membership> {
+        self.memberships.iter().find(|m| m.chan_name == chan_name)
+    }
+
+    /// The name of the channel plain messages from the user are sent to, which is the channel
+    /// they most recently joined or switched to.
+    pub(crate) fn current_channel(&self) -> Result<&str, ServerError> {
+        self.memberships
+            .last()
+            .map(|m| m.chan_name.as_str())
+            .ok_or_else(|| ServerError::NoChannel(self.user_name.clone()))
+    }
+
+    /// Every user in `chan_name`, in the order they joined it.
+    pub(crate) fn roster(&self, chan_name: &str) -> Vec<(String, Member)> {
+        self.shared
+            .channels
+            .with(chan_name, |channel| {
+                let members = channel.members().into_iter();
+                members
+                    .map(|(name, member)| (name.to_owned(), member.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Describes `user_name`, with a [`WhoReply`] for each channel they are a member of.
+    pub(crate) fn who(&self, user_name: &str) -> Result<Vec<WhoReply>, ServerError> {
+        let registration = self
+            .shared
+            .users
+            .lock()
+            .unwrap()
+            .get(user_name)
+            .cloned()
+            .ok_or_else(|| ServerError::NoSuchUser(user_name.into()))?;
+        let mut chan_names: Vec<Option<String>> = Vec::new();
+        self.shared.channels.for_each(|chan_name, channel| {
+            if channel.is_member(user_name) {
+                chan_names.push(Some(chan_name.to_owned()));
+            }
+        });
+        if chan_names.is_empty() {
+            chan_names.push(None);
+        }
+        chan_names.sort();
+
+        let idle = registration.last_active.lock().unwrap().elapsed();
+        let replies = chan_names.into_iter().map(|channel| WhoReply {
+            user: user_name.to_owned(),
+            channel,
+            connected_since: registration.connected_at,
+            idle,
+        });
+        Ok(replies.collect())
+    }
+
+    /// Describes every channel whose name matches the glob `pattern`, or every channel if
+    /// there's no pattern, ordered by name.
+    pub(crate) fn list(&self, pattern: Option<&str>) -> Vec<ListReply> {
+        let mut replies: Vec<ListReply> = Vec::new();
+        self.shared.channels.for_each(|chan_name, channel| {
+            if pattern.map_or(true, |pattern| channel::glob_match(pattern, chan_name)) {
+                replies.push(ListReply {
+                    channel: chan_name.to_owned(),
+                    members: channel.member_count(),
+                    topic: channel.topic().map(str::to_owned),
+                });
+            }
+        });
+        replies.sort_by(|a, b| a.channel.cmp(&b.channel));
+        replies
+    }
+
+    /// The topic of `chan_name`, if it exists and has one.
+    pub(crate) fn topic(&self, chan_name: &str) -> Option<String> {
+        self.shared
+            .channels
+            .with(chan_name, |channel| channel.topic().map(str::to_owned))
+            .flatten()
+    }
+
+    /// Sets the topic of one of the channels the user is a member of, letting every member know.
+    pub(crate) fn set_topic(&self, chan_name: &str, topic: &str) -> Result<(), ServerError> {
+        let not_in_channel = || ServerError::NotInChannel(self.user_name.clone(), chan_name.into());
+        let membership = self.membership(chan_name).ok_or_else(not_in_channel)?;
+        self.shared
+            .channels
+            .with(chan_name, |channel| {
+                // The session only drops its membership once it's told of a kick, see `recv`.
+                if !channel.is_member(&self.user_name) {
+                    return Err(not_in_channel());
+                }
+                channel.set_topic(topic);
+                let msg = Message::Topic {
+                    user: self.user_name.clone(),
+                    topic: topic.to_owned(),
+                };
+                membership
+                    .tx
+                    .send(msg)
+                    .map_err(ServerError::BroadcastMessage)
+            })
+            .unwrap_or_else(|| Err(not_in_channel()))?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Marks the user as active just now.
+    fn touch(&self) {
+        *self.registration.last_active.lock().unwrap() = Instant::now();
+    }
+
+    /// Sends a message from the user to one of the channels they are a member of, adding it to
+    /// the channel's history.
+    pub(crate) fn say(&self, chan_name: &str, text: &str) -> Result<(), ServerError> {
+        let not_in_channel = || ServerError::NotInChannel(self.user_name.clone(), chan_name.into());
+        let membership = self.membership(chan_name).ok_or_else(not_in_channel)?;
+        let is_admin = self.is_admin();
+        // The message is recorded and sent under the channel's lock, which users joining take
+        // their replay under, so they get it either replayed or live, never both nor neither.
+        self.shared
+            .channels
+            .with(chan_name, |channel| {
+                // The session only drops its membership once it's told of a kick, see `recv`.
+                if !channel.is_member(&self.user_name) {
+                    return Err(not_in_channel());
+                }
+                if !channel.can_speak(&self.user_name) && !is_admin {
+                    return Err(ServerError::CannotSpeak(
+                        self.user_name.clone(),
+                        chan_name.into(),
+                    ));
+                }
+                if let Some(history) = &self.shared.history {
+                    history.record(chan_name, HistoryEntry::now(&self.user_name, text));
+                }
+                let msg = Message::Text {
+                    from: self.user_name.clone(),
+                    text: text.to_owned(),
+                };
+                membership
+                    .tx
+                    .send(msg)
+                    .map_err(ServerError::BroadcastMessage)
+            })
+            .unwrap_or_else(|| Err(not_in_channel()))?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Sends a message from the user to `user_name` alone.
+    pub(crate) fn message(&self, user_name: &str, text: &str) -> Result<(), ServerError> {
+        let registration = self.registration_of(user_name)?;
+        self.touch();
+        let msg = DirectMessage::Text {
+            from: self.user_name.clone(),
+            text: text.to_owned(),
+        };
+        Self::send_direct(&registration, user_name, msg)
+    }
+
+    fn registration_of(&self, user_name: &str) -> Result<Arc<Registration>, ServerError> {
+        self.shared
+            .users
+            .lock()
+            .unwrap()
+            .get(user_name)
+            .cloned()
+            .ok_or_else(|| ServerError::NoSuchUser(user_name.into()))
+    }
+
+    fn send_direct(
+        registration: &Registration,
+        user_name: &str,
+        msg: DirectMessage,
+    ) -> Result<(), ServerError> {
+        registration.tx.try_send(msg).map_err(|e| match e {
+            TrySendError::Full(_) => ServerError::UserLagging(user_name.into()),
+            TrySendError::Closed(_) => ServerError::NoSuchUser(user_name.into()),
+        })
+    }
+
+    /// Changes the user's name to `new_name`, letting every channel they are a member of know.
+    ///
+    /// The new name must be free, under the same rules as [`Session::register`].
+    pub(crate) fn rename(&mut self, new_name: &str) -> Result<(), ServerError> {
+        if new_name == self.user_name {
+            return Ok(());
+        }
+        Self::check_reserved(&self.shared, new_name, self.identified_as.as_deref())?;
+        {
+            // The channels are updated while the users are locked, so nobody can grab the name
+            // in between.
+            let mut users = self.shared.users.lock().unwrap();
+            if users.contains_key(new_name) {
+                debug!(
+                    "user `{}@{}` attempted to rename to unavailable username `{}`",
+                    self.user_name, self.addr, new_name
+                );
+                return Err(ServerError::UserNameInUse(new_name.into()));
+            }
+            users.remove(&self.user_name);
+            users.insert(new_name.to_owned(), self.registration.clone());
+
+            // The user may have been invited to any channel, not only those they're a member of.
+            self.shared
+                .channels
+                .for_each(|_, channel| channel.rename(&self.user_name, new_name));
+        }
+
+        let old_name = std::mem::replace(&mut self.user_name, new_name.to_owned());
+        for membership in &self.memberships {
+            let msg = Message::Renamed {
+                old: old_name.clone(),
+                new: new_name.to_owned(),
+            };
+            membership
+                .tx
+                .send(msg)
+                .map_err(ServerError::BroadcastMessage)?;
+        }
+        Ok(())
+    }
+
+    /// Joins `chan_name`, making it the current channel, with `key` if the channel has one.
+    ///
+    /// If the user is already a member of the channel, it simply becomes the current one.
+    pub(crate) fn join(&mut self, chan_name: &str, key: Option<&str>) -> Result<(), ServerError> {
+        if let Some(idx) = self
+            .memberships
+            .iter()
+            .position(|m| m.chan_name == chan_name)
+        {
+            let membership = self.memberships.remove(idx);
+            self.memberships.push(membership);
+            return Ok(());
+        }
+
+        // We get a reference to the channel the user asked to join, or create a new channel
+        // if there is none under that name.
+        // Here we also take care to check that the name the user chose is unique, to avoid
+        // confusion.
+        // The user is added while the channel is locked, so it can't be deleted from under us by
+        // the last member leaving in the meantime.
+        let capacity = self.config().channel_capacity;
+        let is_admin = self.is_admin();
+        let (tx, rx, replay) =
+            self.shared
+                .channels
+                .with_or_create(chan_name, capacity, |channel| {
+                    // Admins can't be kept out of any channel.
+                    if !is_admin {
+                        if let Err(refusal) = channel.admit(&self.user_name, self.addr, key) {
+                            debug!(
+                                "user `{}@{}` was refused from channel `{}`: {:?}",
+                                self.user_name, self.addr, chan_name, refusal
+                            );
+                            let (user_name, chan_name) = (self.user_name.clone(), chan_name.into());
+                            return Err(match refusal {
+                                Refusal::Banned => ServerError::Banned(user_name, chan_name),
+                                Refusal::InviteOnly => {
+                                    ServerError::InviteOnly(user_name, chan_name)
+                                }
+                                Refusal::BadKey => ServerError::BadChannelKey(user_name, chan_name),
+                                Refusal::Full => ServerError::ChannelFull(user_name, chan_name),
+                            });
+                        }
+                    }
+                    // The history is taken right before subscribing, so the replay leads
+                    // straight into the messages the user receives from then on.
+                    let replay = match (&self.shared.history, &self.config().history) {
+                        (Some(history), Some(config)) => {
+                            Some(history.last(chan_name, config.replay))
+                        }
+                        _ => None,
+                    };
+                    // Adding the user creates a receiver for them, this will allow them to read
+                    // messages from the broadcast channel.
+                    match channel.add(&self.user_name, self.addr, is_admin) {
+                        Some(rx) => Ok((channel.tx().clone(), rx, replay)),
+                        None => {
+                            debug!(
+                                "user `{}@{}` attempted to join channel with unavailable username",
+                                self.user_name, self.addr
+                            );
+                            Err(ServerError::UserAlreadyInChannel(self.user_name.clone()))
+                        }
+                    }
+                })?;
+        if let Some(entries) = replay {
+            self.pending_replay.insert(chan_name.into(), entries);
+        }
+
+        // Broadcast to the channel that a new user has joined.
+        let join_msg = Message::Joined {
+            user: self.user_name.clone(),
+        };
+        tx.send(join_msg).map_err(ServerError::BroadcastMessage)?;
+
+        self.receivers
+            .insert(chan_name.into(), BroadcastStream::new(rx));
+        self.memberships.push(Membership {
+            chan_name: chan_name.into(),
+            tx,
+        });
+
+        Ok(())
+    }
+
+    /// Leaves `chan_name`, notifying the rest of the channel, along with the `reason` if given.
+    pub(crate) fn part(
+        &mut self,
+        chan_name: &str,
+        reason: Option<&str>,
+    ) -> Result<(), ServerError> {
+        let membership = self
+            .membership(chan_name)
+            .ok_or_else(|| ServerError::NotInChannel(self.user_name.clone(), chan_name.into()))?;
+
+        // A user who was just kicked out has already left, as far as the channel is concerned.
+        if self.is_still_member(chan_name) {
+            let leave_msg = Message::Left {
+                user: self.user_name.clone(),
+                reason: reason.map(str::to_owned),
+            };
+            membership
+                .tx
+                .send(leave_msg)
+                .map_err(ServerError::BroadcastMessage)?;
+        }
+
+        self.forget(chan_name);
+        Ok(())
+    }
+
+    /// Whether the user is still among the members of `chan_name`, i.e. hasn't been kicked out.
+    fn is_still_member(&self, chan_name: &str) -> bool {
+        self.shared
+            .channels
+            .with(chan_name, |channel| channel.is_member(&self.user_name))
+            .unwrap_or(false)
+    }
+
+    /// Drops the user's membership of `chan_name`, along with the messages they had yet to
+    /// receive from it, and removes them from its members.
+    fn forget(&mut self, chan_name: &str) {
+        self.memberships.retain(|m| m.chan_name != chan_name);
+        self.receivers.remove(chan_name);
+        self.pending_replay.remove(chan_name);
+        self.replay.retain(|(c, _)| c != chan_name);
+
+        self.shared
+            .channels
+            .remove_member(chan_name, &self.user_name);
+    }
+
+    /// Runs `moderate` on `chan_name`, provided the user is one of its operators, then
+    /// broadcasts the message it returns, if any.
+    fn moderate(
+        &self,
+        chan_name: &str,
+        moderate: impl FnOnce(&mut Channel) -> Result<Option<Message>, ServerError>,
+    ) -> Result<(), ServerError> {
+        let membership = self
+            .membership(chan_name)
+            .ok_or_else(|| ServerError::NotInChannel(self.user_name.clone(), chan_name.into()))?;
+        let not_in_channel = || ServerError::NotInChannel(self.user_name.clone(), chan_name.into());
+        let msg = self
+            .shared
+            .channels
+            .with(chan_name, |channel| {
+                let member = channel.member(&self.user_name).ok_or_else(not_in_channel)?;
+                if !member.is_operator && !self.is_admin() {
+                    return Err(ServerError::NotOperator(
+                        self.user_name.clone(),
+                        chan_name.into(),
+                    ));
+                }
+                moderate(channel)
+            })
+            .unwrap_or_else(|| Err(not_in_channel()))?;
+        self.touch();
+        if let Some(msg) = msg {
+            membership
+                .tx
+                .send(msg)
+                .map_err(ServerError::BroadcastMessage)?;
+        }
+        Ok(())
+    }
+
+    /// The masks of the users banned from `chan_name`.
+    pub(crate) fn bans(&self, chan_name: &str) -> Vec<String> {
+        self.shared
+            .channels
+            .with(chan_name, |channel| channel.bans().to_vec())
+            .unwrap_or_default()
+    }
+
+    /// The modes of `chan_name`, which the user must be a member of.
+    pub(crate) fn modes(&self, chan_name: &str) -> Result<ChannelModes, ServerError> {
+        let not_in_channel = || ServerError::NotInChannel(self.user_name.clone(), chan_name.into());
+        self.membership(chan_name).ok_or_else(not_in_channel)?;
+        self.shared
+            .channels
+            .with(chan_name, |channel| channel.modes().clone())
+            .ok_or_else(not_in_channel)
+    }
+
+    /// Makes each of `changes` to the modes of `chan_name`, which the user must be an operator
+    /// of.
+    ///
+    /// The changes are made in order, up to the first one that fails.
+    pub(crate) fn set_modes(
+        &self,
+        chan_name: &str,
+        changes: &[ModeChange],
+    ) -> Result<(), ServerError> {
+        for change in changes {
+            self.moderate(chan_name, |channel| {
+                let changed = match change {
+                    ModeChange::Voice(user_name, is_voiced) => {
+                        let member = channel.member_mut(user_name).ok_or_else(|| {
+                            ServerError::UserNotInChannel(user_name.clone(), chan_name.into())
+                        })?;
+                        let changed = member.is_voiced != *is_voiced;
+                        member.is_voiced = *is_voiced;
+                        changed
+                    }
+                    change => channel.modes_mut().apply(change),
+                };
+                Ok(Some(Message::Mode {
+                    by: self.user_name.clone(),
+                    change: change.clone(),
+                })
+                .filter(|_| changed))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Invites `user_name` to `chan_name`, letting them join it even if it's invite-only.
+    ///
+    /// Any member may invite others, unless the channel is invite-only, in which case only its
+    /// operators may.
+    pub(crate) fn invite(&self, chan_name: &str, user_name: &str) -> Result<(), ServerError> {
+        let registration = self.registration_of(user_name)?;
+        let not_in_channel = || ServerError::NotInChannel(self.user_name.clone(), chan_name.into());
+        self.membership(chan_name).ok_or_else(not_in_channel)?;
+        let invite = |channel: &mut Channel| {
+            let is_operator = channel
+                .member(&self.user_name)
+                .map_or(false, |m| m.is_operator);
+            if channel.modes().invite_only && !is_operator && !self.is_admin() {
+                return Err(ServerError::NotOperator(
+                    self.user_name.clone(),
+                    chan_name.into(),
+                ));
+            }
+            if channel.is_member(user_name) {
+                return Err(ServerError::UserOnChannel(
+                    user_name.into(),
+                    chan_name.into(),
+                ));
+            }
+            channel.invite(user_name);
+            Ok(())
+        };
+        self.shared
+            .channels
+            .with(chan_name, invite)
+            .unwrap_or_else(|| Err(not_in_channel()))?;
+        self.touch();
+        let msg = DirectMessage::Invite {
+            from: self.user_name.clone(),
+            channel: chan_name.to_owned(),
+        };
+        Self::send_direct(&registration, user_name, msg)
+    }
+
+    /// Kicks `user_name` out of `chan_name`, which the user must be an operator of.
+    pub(crate) fn kick(
+        &self,
+        chan_name: &str,
+        user_name: &str,
+        reason: Option<&str>,
+    ) -> Result<(), ServerError> {
+        self.moderate(chan_name, |channel| {
+            // The kicked user's session drops its membership once it's told, see `recv`.
+            if !channel.remove(user_name) {
+                return Err(ServerError::UserNotInChannel(
+                    user_name.into(),
+                    chan_name.into(),
+                ));
+            }
+            Ok(Some(Message::Kicked {
+                user: user_name.to_owned(),
+                by: self.user_name.clone(),
+                reason: reason.map(str::to_owned),
+            }))
+        })
+    }
+
+    /// Makes `user_name` an operator of `chan_name`, or takes it away, which the user must be an
+    /// operator of.
+    pub(crate) fn set_operator(
+        &self,
+        chan_name: &str,
+        user_name: &str,
+        is_operator: bool,
+    ) -> Result<(), ServerError> {
+        self.moderate(chan_name, |channel| {
+            let member = channel
+                .member_mut(user_name)
+                .ok_or_else(|| ServerError::UserNotInChannel(user_name.into(), chan_name.into()))?;
+            if member.is_operator == is_operator {
+                return Ok(None);
+            }
+            member.is_operator = is_operator;
+            Ok(Some(Message::Operator {
+                user: user_name.to_owned(),
+                by: self.user_name.clone(),
+                is_operator,
+            }))
+        })
+    }
+
+    /// Bans the users matching `mask` from joining `chan_name`, or lifts the ban, which the user
+    /// must be an operator of.
+    ///
+    /// Members of the channel matching the mask aren't kicked out.
+    pub(crate) fn set_ban(
+        &self,
+        chan_name: &str,
+        mask: &str,
+        is_banned: bool,
+    ) -> Result<(), ServerError> {
+        self.moderate(chan_name, |channel| {
+            let changed = if is_banned {
+                channel.ban(mask)
+            } else {
+                channel.unban(mask)
+            };
+            Ok(Some(Message::Ban {
+                mask: mask.to_owned(),
+                by: self.user_name.clone(),
+                is_banned,
+            })
+            .filter(|_| changed))
+        })
+    }
+
+    /// The last `count` messages said in `chan_name`, oldest first.
+    ///
+    /// The user must be a member of the channel. If no history is kept, there are no messages.
+    pub(crate) fn history(
+        &self,
+        chan_name: &str,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>, ServerError> {
+        if !self.is_member(chan_name) {
+            return Err(ServerError::NotInChannel(
+                self.user_name.clone(),
+                chan_name.into(),
+            ));
+        }
+        Ok(match &self.shared.history {
+            Some(history) => history.last(chan_name, count),
+            None => Vec::new(),
+        })
+    }
+
+    /// Leaves every channel the user is a member of.
+    pub(crate) fn part_all(&mut self, reason: Option<&str>) -> Result<(), ServerError> {
+        while let Some(Membership { chan_name, .. }) = self.memberships.last() {
+            let chan_name = chan_name.clone();
+            self.part(&chan_name, reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    /// However the connection ends, be it through `QUIT`, the client going away or an error, the
+    /// user leaves every channel they are still a member of and their user name is freed.
+    fn drop(&mut self) {
+        // Nobody is told once the server is shutting down, every channel is going away anyway.
+        let notify = !self.shared.disconnect.is_cancelled();
+        let memberships: Vec<_> = self.memberships.drain(..).collect();
+        for Membership { chan_name, tx } in memberships {
+            if notify && self.is_still_member(&chan_name) {
+                let leave_msg = Message::Left {
+                    user: self.user_name.clone(),
+                    reason: None,
+                };
+                tx.send(leave_msg).ok();
+            }
+            self.shared
+                .channels
+                .remove_member(&chan_name, &self.user_name);
+        }
+
+        // A panic while holding the lock leaves the map consistent, so there's no reason to
+        // leak the user on top of it.
+        self.shared
+            .users
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.user_name);
+    }
+}
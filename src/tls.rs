@@ -0,0 +1,88 @@
+//! TLS configuration for the [`Server`](crate::server::Server) and [`Client`](crate::client::Client).
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use thiserror::Error;
+use tokio_rustls::{
+    rustls::{self, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor, TlsConnector,
+};
+
+/// Error type for loading TLS configuration.
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read `{0}`")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("no valid certificates found in `{0}`")]
+    NoCertificates(PathBuf),
+    #[error("no private key found in `{0}`")]
+    NoPrivateKey(PathBuf),
+    #[error("invalid certificate or private key")]
+    InvalidConfig(#[source] rustls::Error),
+}
+
+/// Reads every PEM encoded certificate in `path`.
+fn read_certs(path: &Path) -> Result<Vec<Vec<u8>>, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::Read(path.into(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| TlsError::Read(path.into(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.into()));
+    }
+    Ok(certs)
+}
+
+/// Reads the first PEM encoded private key in `path`.
+fn read_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::Read(path.into(), e))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| TlsError::Read(path.into(), e))? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(TlsError::NoPrivateKey(path.into())),
+        }
+    }
+}
+
+/// Creates a [`TlsAcceptor`] for the server, from the PEM encoded certificate chain in
+/// `cert_path` and private key in `key_path`.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, TlsError> {
+    let certs = read_certs(cert_path)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = read_key(key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(TlsError::InvalidConfig)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Creates a [`TlsConnector`] for clients, trusting only the PEM encoded certificate authorities
+/// in `ca_path`.
+pub fn connector(ca_path: &Path) -> Result<TlsConnector, TlsError> {
+    let mut roots = RootCertStore::empty();
+    let (valid, _invalid) = roots.add_parsable_certificates(&read_certs(ca_path)?);
+    if valid == 0 {
+        return Err(TlsError::NoCertificates(ca_path.into()));
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
@@ -0,0 +1,141 @@
+//! WebSocket transport for the [`Server`](crate::server::Server).
+//!
+//! Clients speak the same line protocol over WebSocket as they do over raw TCP, where each text
+//! frame carries a single line. [`WsStream`] adapts a WebSocket connection into a byte stream, so
+//! it can be handed to [`Server::handle_client`](crate::server::Server::handle_client) like any
+//! other connection.
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{Error as WsError, Message as WsMessage},
+    WebSocketStream,
+};
+
+/// A WebSocket connection, seen as a stream of newline-terminated lines.
+///
+/// Every text frame received is read as a line, and every line written is sent as a text frame.
+/// Any other kind of data frame is ignored.
+pub struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    /// The remainder of the last frame received, which hasn't been read yet.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Bytes written that don't yet make up a whole line.
+    write_buf: Vec<u8>,
+    /// Lines written, waiting to be sent as frames.
+    pending: VecDeque<String>,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Consumes the stream, returning the inner [`WebSocketStream`].
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.ws
+    }
+}
+
+fn into_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    /// Sends as many of the pending lines as possible.
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(line) = self.pending.pop_front() {
+            let mut ws = Pin::new(&mut self.ws);
+            if let Err(e) = ready!(ws.as_mut().poll_ready(cx)) {
+                self.pending.push_front(line);
+                return Poll::Ready(Err(into_io_error(e)));
+            }
+            ws.start_send(WsMessage::Text(line))
+                .map_err(into_io_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_pos == this.read_buf.len() {
+            let frame = match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(frame) => frame.map_err(into_io_error)?,
+                // The connection is closed, which is the end of the stream.
+                None => return Poll::Ready(Ok(())),
+            };
+            match frame {
+                WsMessage::Text(line) => {
+                    this.read_buf = line.into_bytes();
+                    this.read_buf.push(b'\n');
+                    this.read_pos = 0;
+                }
+                WsMessage::Close(_) => return Poll::Ready(Ok(())),
+                // Pings are answered by the WebSocket implementation itself.
+                WsMessage::Binary(_) | WsMessage::Ping(_) | WsMessage::Pong(_) => (),
+            }
+        }
+
+        let remaining = &this.read_buf[this.read_pos..];
+        let len = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Don't take any more data while the last lines are still waiting to be sent.
+        ready!(this.poll_send_pending(cx))?;
+
+        this.write_buf.extend_from_slice(buf);
+        while let Some(idx) = this.write_buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = this.write_buf.drain(..=idx).take(idx).collect();
+            let line = String::from_utf8(line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            this.pending.push_back(line);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        Pin::new(&mut this.ws).poll_flush(cx).map_err(into_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        Pin::new(&mut this.ws).poll_close(cx).map_err(into_io_error)
+    }
+}
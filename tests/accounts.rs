@@ -0,0 +1,204 @@
+mod common;
+
+use std::{fs, process};
+
+use anyhow::Error;
+use chat::{
+    client::ClientError,
+    config::{AccountsConfig, ServerConfig},
+    reply::ErrorCode,
+};
+use common::{TestClient as Client, TestServer as Server};
+
+/// The config of a server letting users register their names.
+fn config() -> ServerConfig {
+    ServerConfig {
+        accounts: Some(AccountsConfig::default()),
+        ..Server::config()
+    }
+}
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+/// The code of the error reply `result` failed with, if any.
+fn error_code(result: Result<(), Error>) -> Option<ErrorCode> {
+    match result.err()?.downcast_ref::<ClientError>()? {
+        ClientError::Server(reply) => Some(reply.code),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_register_and_identify() -> Result<(), Error> {
+    let server = Server::with_config(config()).await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.register("hunter2").await?;
+    assert_eq!(
+        error_code(joe.register("hunter3").await),
+        Some(ErrorCode::AlreadyRegistered)
+    );
+    joe.send("QUIT").await?;
+
+    // Nobody can take the name without the password.
+    let mut impostor = Client::new(&server.socket).await?;
+    impostor.send("JOIN rust joe").await?;
+    assert_eq!(impostor.recv_error().await?, ErrorCode::NameReserved);
+
+    let mut joe = Client::new(&server.socket).await?;
+    assert_eq!(
+        error_code(joe.identify("joe", "hunter3").await),
+        Some(ErrorCode::PasswordMismatch)
+    );
+    joe.identify("joe", "hunter2").await?;
+    joe.send("JOIN rust joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nick_to_registered_name() -> Result<(), Error> {
+    let server = Server::with_config(config()).await?;
+
+    let mut alice = join(&server, "rust", "alice").await?;
+    alice.register("s3cret").await?;
+    alice.send("QUIT").await?;
+
+    let mut bob = join(&server, "rust", "bob").await?;
+    bob.send("NICK alice").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::NameReserved);
+    bob.identify("alice", "s3cret").await?;
+    bob.send("NICK alice").await?;
+    assert_eq!(bob.recv().await?, "bob is now known as alice");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unreserved_names() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        accounts: Some(AccountsConfig {
+            reserve_names: false,
+            ..AccountsConfig::default()
+        }),
+        ..Server::config()
+    })
+    .await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.register("hunter2").await?;
+    joe.send("QUIT").await?;
+
+    // Registering still works, but the name is free for anyone to use.
+    let _joe = join(&server, "rust", "joe").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accounts_disabled() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("IDENTIFY joe hunter2").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::InvalidCommand);
+    joe.send("JOIN rust joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    joe.send("REGISTER hunter2").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::InvalidCommand);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accounts_file() -> Result<(), Error> {
+    let path = std::env::temp_dir().join(format!("chat-accounts-{}", process::id()));
+    let config = ServerConfig {
+        accounts: Some(AccountsConfig {
+            file: Some(path.clone()),
+            ..AccountsConfig::default()
+        }),
+        ..Server::config()
+    };
+
+    let mut server = Server::with_config(config.clone()).await?;
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.register("hunter2").await?;
+    server.shutdown().await?;
+
+    // Only the hash of the password is kept.
+    let contents = fs::read_to_string(&path)?;
+    assert!(contents.starts_with("joe $argon2"));
+    assert!(!contents.contains("hunter2"));
+
+    let server = Server::with_config(config).await?;
+    let mut impostor = Client::new(&server.socket).await?;
+    impostor.send("JOIN rust joe").await?;
+    assert_eq!(impostor.recv_error().await?, ErrorCode::NameReserved);
+    let mut joe = Client::new(&server.socket).await?;
+    let identified = joe.identify("joe", "hunter2").await;
+    fs::remove_file(&path)?;
+    identified?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_pass() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        irc_bind: vec![Server::any_port()],
+        ..config()
+    })
+    .await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.register("hunter2").await?;
+    joe.send("QUIT").await?;
+
+    let irc = server.irc_socket.unwrap();
+    let mut client = Client::new(&irc).await?;
+    client.send("NICK joe").await?;
+    client.send("USER joe 0 * :joe").await?;
+    assert_eq!(
+        client.recv().await?,
+        ":chat 433 * joe :Nickname is registered, send its password with PASS"
+    );
+
+    let mut client = Client::new(&irc).await?;
+    client.send("PASS hunter3").await?;
+    client.send("NICK joe").await?;
+    client.send("USER joe 0 * :joe").await?;
+    assert_eq!(client.recv_slow().await?, ":chat 464 * :Password incorrect");
+    assert_eq!(client.recv().await?, "ERROR :Closing link: Bad password");
+
+    let mut client = Client::new(&irc).await?;
+    client.send("PASS hunter2").await?;
+    client.send("NICK joe").await?;
+    client.send("USER joe 0 * :joe").await?;
+    assert_eq!(
+        client.recv_slow().await?,
+        ":chat 001 joe :Welcome to the chat server, joe"
+    );
+
+    for _ in 0..4 {
+        client.recv().await?;
+    }
+
+    // Registering from IRC works too.
+    client.send("NICK joseph").await?;
+    assert_eq!(client.recv().await?, ":joe!joe@chat NICK joseph");
+    client.send("REGISTER pa55word").await?;
+    assert_eq!(
+        client.recv_slow().await?,
+        ":chat NOTICE joseph :Nickname joseph is now registered"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+mod common;
+
+use anyhow::Error;
+use chat::reply::ErrorCode;
+use common::{TestClient as Client, TestServer as Server};
+
+#[tokio::test]
+async fn test_join_second_channel() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    let mut bob = Client::new(&server.socket).await?;
+    bob.send("JOIN rust bob").await?;
+    assert_eq!(bob.recv().await?, "bob has joined");
+
+    joe.send("JOIN rust").await?;
+    assert_eq!(joe.recv().await?, "[rust] joe has joined");
+    assert_eq!(bob.recv().await?, "joe has joined");
+    assert!(joe.recv().await.is_err()); // should timeout
+
+    // plain messages go to the most recently joined channel
+    joe.send("hi bob").await?;
+    assert_eq!(joe.recv().await?, "[rust] joe: hi bob");
+    assert_eq!(bob.recv().await?, "joe: hi bob");
+
+    bob.send("hi joe").await?;
+    assert_eq!(joe.recv().await?, "[rust] bob: hi joe");
+    assert_eq!(bob.recv().await?, "bob: hi joe");
+
+    assert!(joe.recv().await.is_err()); // should timeout
+    assert!(bob.recv().await.is_err()); // should timeout
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_switch_channel() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    joe.send("JOIN rust").await?;
+    assert_eq!(joe.recv().await?, "[rust] joe has joined");
+
+    // joining a channel we are already in makes it the current one
+    joe.send("JOIN cooking").await?;
+    assert!(joe.recv().await.is_err()); // should timeout
+    joe.send("pasta").await?;
+    assert_eq!(joe.recv().await?, "[cooking] joe: pasta");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_say_to_channel() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    joe.send("JOIN rust").await?;
+    assert_eq!(joe.recv().await?, "[rust] joe has joined");
+
+    joe.send("SAY cooking some pasta please").await?;
+    assert_eq!(joe.recv().await?, "[cooking] joe: some pasta please");
+
+    // we can only talk to channels we are a member of
+    joe.send("SAY gardening roses").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::NotInChannel);
+
+    joe.send("SAY cooking").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::InvalidCommand);
+
+    assert!(joe.recv().await.is_err()); // should timeout
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_part() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    let mut bob = Client::new(&server.socket).await?;
+    bob.send("JOIN cooking bob").await?;
+    assert_eq!(bob.recv().await?, "bob has joined");
+    assert_eq!(joe.recv().await?, "bob has joined");
+
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv().await?, "[rust] bob has joined");
+
+    bob.send("PART cooking").await?;
+    assert_eq!(joe.recv().await?, "bob has left");
+    assert!(bob.recv().await.is_err()); // should timeout
+
+    // bob is no longer in cooking, so won't see this
+    joe.send("bye bob").await?;
+    assert_eq!(joe.recv().await?, "joe: bye bob");
+    assert!(bob.recv().await.is_err()); // should timeout
+
+    // parting without a channel leaves the current one, after which bob is in no channel at all
+    bob.send("PART").await?;
+    assert!(bob.recv().await.is_err()); // should timeout
+    bob.send("anyone?").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::NoChannel);
+
+    bob.send("PART cooking").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::NotInChannel);
+
+    // the name was released, so bob may join cooking again
+    bob.send("JOIN cooking").await?;
+    assert_eq!(bob.recv().await?, "bob has joined");
+    assert_eq!(joe.recv().await?, "bob has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_second_channel_username_conflict() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN rust joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    // user names are unique across the server, not just within a channel
+    let mut other_joe = Client::new(&server.socket).await?;
+    other_joe.send("JOIN cooking joe").await?;
+    assert_eq!(other_joe.recv_error().await?, ErrorCode::NickInUse);
+    assert!(joe.recv().await.is_err()); // should timeout
+
+    // the name is free again once joe quits
+    joe.send("QUIT").await?;
+    let mut other_joe = Client::new(&server.socket).await?;
+    other_joe.send("JOIN cooking joe").await?;
+    assert_eq!(other_joe.recv().await?, "joe has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ping() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    joe.send("PING").await?;
+    assert_eq!(joe.recv().await?, "PONG");
+    joe.send("PING 1234").await?;
+    assert_eq!(joe.recv().await?, "PONG 1234");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_quit() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    let mut bob = Client::new(&server.socket).await?;
+    bob.send("JOIN cooking bob").await?;
+    assert_eq!(bob.recv().await?, "bob has joined");
+    assert_eq!(joe.recv().await?, "bob has joined");
+
+    bob.send("QUIT off to bed").await?;
+    assert_eq!(joe.recv().await?, "bob has left (off to bed)");
+    assert!(bob.recv().await.is_err()); // connection was closed
+
+    Ok(())
+}
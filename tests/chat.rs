@@ -1,6 +1,7 @@
 mod common;
 
 use anyhow::Error;
+use chat::reply::ErrorCode;
 use common::{TestClient as Client, TestServer as Server};
 
 #[tokio::test]
@@ -12,8 +13,8 @@ async fn test_chat_session() -> Result<(), Error> {
     assert_eq!(joe.recv().await?, "joe has joined");
     joe.send("no one here yet").await?;
     assert_eq!(joe.recv().await?, "joe: no one here yet");
-    joe.send("JOIN no longer does anything").await?;
-    assert_eq!(joe.recv().await?, "joe: JOIN no longer does anything");
+    joe.send("JOIN is now a command").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::InvalidCommand);
     assert!(joe.recv().await.is_err()); // should timeout
 
     let mut bob = Client::new(&server.socket).await?;
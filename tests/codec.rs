@@ -0,0 +1,29 @@
+use anyhow::Error;
+use bytes::BytesMut;
+use chat::codec::{Frame, LineCodec, DEFAULT_LENGTH_LIMIT};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[test]
+fn test_frame_is_encoded_as_a_line() -> Result<(), Error> {
+    let mut codec = LineCodec::new_with_max_length(DEFAULT_LENGTH_LIMIT);
+    let mut from_str = BytesMut::new();
+    codec.encode("joe: hi", &mut from_str)?;
+    let mut from_frame = BytesMut::new();
+    codec.encode(Frame::from("joe: hi"), &mut from_frame)?;
+    assert_eq!(from_frame, from_str);
+    assert_eq!(Frame::from("joe: hi".to_owned()).as_bytes(), b"joe: hi\n");
+
+    assert_eq!(codec.decode(&mut from_frame)?.as_deref(), Some("joe: hi"));
+    Ok(())
+}
+
+#[test]
+fn test_line_breaks_are_refused() {
+    let mut codec = LineCodec::new_with_max_length(DEFAULT_LENGTH_LIMIT);
+    let mut dst = BytesMut::new();
+    assert!(codec.encode("hi\nalice: psst", &mut dst).is_err());
+    assert!(codec
+        .encode(Frame::from("hi\r:chat 001 joe"), &mut dst)
+        .is_err());
+    assert!(dst.is_empty());
+}
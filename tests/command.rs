@@ -0,0 +1,393 @@
+use chat::command::{Command, ModeChange, ParseError, Verb, DEFAULT_MAX_NAME_LENGTH as MAX};
+
+#[test]
+fn test_parse_join() {
+    assert_eq!(
+        Command::parse_join("JOIN rust bernardo", MAX),
+        Ok(("rust".to_owned(), "bernardo".to_owned(), None))
+    );
+    // Keys aren't held to the length of names.
+    assert_eq!(
+        Command::parse_join("JOIN rust bernardo correct-horse-battery-staple", MAX),
+        Ok((
+            "rust".to_owned(),
+            "bernardo".to_owned(),
+            Some("correct-horse-battery-staple".to_owned())
+        ))
+    );
+    assert_eq!(
+        Command::parse_join("WRONG rust bernardo", MAX),
+        Err(ParseError::UnknownVerb("WRONG".to_owned()))
+    );
+    assert_eq!(
+        Command::parse_join("PART rust", MAX),
+        Err(ParseError::JoinRequired(Verb::Part))
+    );
+    assert_eq!(Command::parse_join("", MAX), Err(ParseError::Empty));
+    assert_eq!(
+        Command::parse_join("JOIN rust", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Join,
+            expected: "2 or 3",
+            found: 1
+        })
+    );
+    assert_eq!(
+        Command::parse_join("JOIN rust 012345678901234567890", MAX),
+        Err(ParseError::NameTooLong(
+            "012345678901234567890".to_owned(),
+            MAX
+        ))
+    );
+    assert_eq!(
+        Command::parse_join("JOIN rust bern\tardo", MAX),
+        Err(ParseError::InvalidName("bern\tardo".to_owned()))
+    );
+}
+
+#[test]
+fn test_parse_commands() {
+    assert_eq!(
+        Command::parse("JOIN rust", MAX),
+        Ok(Command::Join {
+            channel: "rust".to_owned(),
+            user: None,
+            key: None
+        })
+    );
+    assert_eq!(
+        Command::parse("JOIN rust secret", MAX),
+        Ok(Command::Join {
+            channel: "rust".to_owned(),
+            user: None,
+            key: Some("secret".to_owned())
+        })
+    );
+    assert_eq!(
+        Command::parse("JOIN rust bernardo secret", MAX),
+        Ok(Command::Join {
+            channel: "rust".to_owned(),
+            user: Some("bernardo".to_owned()),
+            key: Some("secret".to_owned())
+        })
+    );
+    assert_eq!(
+        Command::parse("MODE", MAX),
+        Ok(Command::Mode {
+            changes: Vec::new()
+        })
+    );
+    assert_eq!(
+        Command::parse("MODE +ikl-m secret 10", MAX),
+        Ok(Command::Mode {
+            changes: vec![
+                ModeChange::InviteOnly(true),
+                ModeChange::Key(Some("secret".to_owned())),
+                ModeChange::Limit(Some(10)),
+                ModeChange::Moderated(false),
+            ]
+        })
+    );
+    assert_eq!(
+        Command::parse("MODE -kl+v bernardo", MAX),
+        Ok(Command::Mode {
+            changes: vec![
+                ModeChange::Key(None),
+                ModeChange::Limit(None),
+                ModeChange::Voice("bernardo".to_owned(), true),
+            ]
+        })
+    );
+    assert_eq!(
+        Command::parse("INVITE bernardo", MAX),
+        Ok(Command::Invite {
+            user: "bernardo".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("PART", MAX),
+        Ok(Command::Part { channel: None })
+    );
+    assert_eq!(
+        Command::parse("SAY rust hello  there", MAX),
+        Ok(Command::Say {
+            channel: "rust".to_owned(),
+            text: "hello  there".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("MSG bernardo hi  there", MAX),
+        Ok(Command::Msg {
+            user: "bernardo".to_owned(),
+            text: "hi  there".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("QUIT gotta go", MAX),
+        Ok(Command::Quit {
+            reason: Some("gotta go".to_owned())
+        })
+    );
+    assert_eq!(
+        Command::parse("PING", MAX),
+        Ok(Command::Ping { token: None })
+    );
+    assert_eq!(
+        Command::parse("PONG 1234", MAX),
+        Ok(Command::Pong {
+            token: Some("1234".to_owned())
+        })
+    );
+    assert_eq!(
+        Command::parse("NAMES", MAX),
+        Ok(Command::Names { channel: None })
+    );
+    assert_eq!(
+        Command::parse("WHO bernardo", MAX),
+        Ok(Command::Who {
+            user: "bernardo".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("HISTORY rust 50", MAX),
+        Ok(Command::History {
+            channel: "rust".to_owned(),
+            count: 50
+        })
+    );
+    assert_eq!(
+        Command::parse("NICK bernie", MAX),
+        Ok(Command::Nick {
+            user: "bernie".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("REGISTER correct horse", MAX),
+        Ok(Command::Register {
+            password: "correct horse".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("IDENTIFY bernardo correct horse", MAX),
+        Ok(Command::Identify {
+            user: "bernardo".to_owned(),
+            password: "correct horse".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("LIST", MAX),
+        Ok(Command::List { pattern: None })
+    );
+    assert_eq!(
+        Command::parse("LIST ru*", MAX),
+        Ok(Command::List {
+            pattern: Some("ru*".to_owned())
+        })
+    );
+    assert_eq!(
+        Command::parse("TOPIC rust", MAX),
+        Ok(Command::Topic {
+            channel: "rust".to_owned(),
+            text: None
+        })
+    );
+    assert_eq!(
+        Command::parse("TOPIC rust all about  crabs", MAX),
+        Ok(Command::Topic {
+            channel: "rust".to_owned(),
+            text: Some("all about  crabs".to_owned())
+        })
+    );
+    assert_eq!(
+        Command::parse("KICK bernardo", MAX),
+        Ok(Command::Kick {
+            user: "bernardo".to_owned(),
+            reason: None
+        })
+    );
+    assert_eq!(
+        Command::parse("KICK bernardo stop spamming", MAX),
+        Ok(Command::Kick {
+            user: "bernardo".to_owned(),
+            reason: Some("stop spamming".to_owned())
+        })
+    );
+    // Masks may be longer than names.
+    assert_eq!(
+        Command::parse("BAN bernardo@192.168.100.100", MAX),
+        Ok(Command::Ban {
+            mask: "bernardo@192.168.100.100".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("UNBAN *@10.*", MAX),
+        Ok(Command::Unban {
+            mask: "*@10.*".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("OP bernardo", MAX),
+        Ok(Command::Op {
+            user: "bernardo".to_owned()
+        })
+    );
+    assert_eq!(
+        Command::parse("DEOP bernardo", MAX),
+        Ok(Command::Deop {
+            user: "bernardo".to_owned()
+        })
+    );
+}
+
+#[test]
+fn test_parse_text() {
+    assert_eq!(
+        Command::parse("hello there", MAX),
+        Ok(Command::Text("hello there".to_owned()))
+    );
+    // verbs are case sensitive
+    assert_eq!(
+        Command::parse("join the club", MAX),
+        Ok(Command::Text("join the club".to_owned()))
+    );
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(
+        Command::parse("PART rust cooking", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Part,
+            expected: "0 or 1",
+            found: 2
+        })
+    );
+    assert_eq!(
+        Command::parse("SAY rust", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Say,
+            expected: "2",
+            found: 1
+        })
+    );
+    assert_eq!(
+        Command::parse("WHO", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Who,
+            expected: "1",
+            found: 0
+        })
+    );
+    assert_eq!(
+        Command::parse("TOPIC", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Topic,
+            expected: "1 or 2",
+            found: 0
+        })
+    );
+    assert_eq!(
+        Command::parse("MODE +x", MAX),
+        Err(ParseError::UnknownMode('x'))
+    );
+    assert_eq!(
+        Command::parse("MODE +kl secret", MAX),
+        Err(ParseError::MissingModeArgument('l'))
+    );
+    assert_eq!(
+        Command::parse("MODE +l many", MAX),
+        Err(ParseError::InvalidNumber("many".to_owned()))
+    );
+    assert_eq!(
+        Command::parse("MODE +m bernardo", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Mode,
+            expected: "one for each mode taking one",
+            found: 1
+        })
+    );
+    assert_eq!(
+        Command::parse("KICK", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Kick,
+            expected: "1 or 2",
+            found: 0
+        })
+    );
+    assert_eq!(
+        Command::parse("BAN joe bob", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Ban,
+            expected: "1",
+            found: 2
+        })
+    );
+    assert_eq!(
+        Command::parse("LIST ru* go*", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::List,
+            expected: "0 or 1",
+            found: 2
+        })
+    );
+    assert_eq!(
+        Command::parse("IDENTIFY bernardo", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Identify,
+            expected: "2",
+            found: 1
+        })
+    );
+    assert_eq!(
+        Command::parse("MSG bernardo", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::Msg,
+            expected: "2",
+            found: 1
+        })
+    );
+    assert_eq!(
+        Command::parse("HISTORY rust", MAX),
+        Err(ParseError::WrongArgumentCount {
+            verb: Verb::History,
+            expected: "2",
+            found: 1
+        })
+    );
+    assert_eq!(
+        Command::parse("HISTORY rust -1", MAX),
+        Err(ParseError::InvalidNumber("-1".to_owned()))
+    );
+    assert_eq!(
+        Command::parse("JOIN this_channel_name_is_way_too_long", MAX),
+        Err(ParseError::NameTooLong(
+            "this_channel_name_is_way_too_long".to_owned(),
+            MAX
+        ))
+    );
+    // A carriage return would end the line early for IRC clients.
+    assert_eq!(
+        Command::parse("hi\r:chat 001 joe :pwned", MAX),
+        Err(ParseError::InvalidText)
+    );
+    assert_eq!(
+        Command::parse_join("JOIN rust bernardo se\rcret", MAX),
+        Err(ParseError::InvalidName("se\rcret".to_owned()))
+    );
+}
+
+#[test]
+fn test_parse_name_length() {
+    assert_eq!(
+        Command::parse_join("JOIN rust bernardo", 5),
+        Err(ParseError::NameTooLong("bernardo".to_owned(), 5))
+    );
+    assert_eq!(
+        Command::parse("JOIN this_channel_name_is_way_too_long", 40),
+        Ok(Command::Join {
+            channel: "this_channel_name_is_way_too_long".to_owned(),
+            user: None,
+            key: None
+        })
+    );
+}
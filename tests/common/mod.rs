@@ -3,28 +3,114 @@
 use std::{
     future::Future,
     net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
     time::Duration,
 };
 
 use anyhow::{anyhow, Error};
-use chat::client::Client;
-use chat::server::Server;
-use tokio::{task::JoinHandle, time::timeout};
+use chat::client::{Client, ClientError};
+use chat::config::{ServerConfig, TlsConfig};
+use chat::json::{Event, Request};
+use chat::ratelimit::FloodStats;
+use chat::reply::{ErrorCode, ListReply, WhoReply};
+use chat::server::{Server, ShutdownHandle};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_rustls::client::TlsStream;
 
 pub struct TestServer {
     pub socket: SocketAddr,
+    pub tls_socket: Option<SocketAddr>,
+    pub irc_socket: Option<SocketAddr>,
+    pub websocket_socket: Option<SocketAddr>,
+    pub flood_stats: Arc<FloodStats>,
+    shutdown: ShutdownHandle,
     handle: JoinHandle<Result<(), Error>>,
 }
 
 impl TestServer {
+    /// An address on localhost, with a port picked by the OS.
+    pub fn any_port() -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)
+    }
+
+    /// The config of a server accepting native clients on [`TestServer::any_port`].
+    pub fn config() -> ServerConfig {
+        ServerConfig {
+            bind: vec![Self::any_port()],
+            ..ServerConfig::default()
+        }
+    }
+
     pub async fn new() -> Result<Self, Error> {
-        let mut server = Server::new(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
-        let socket = server.local_addr()?;
+        Self::with_config(Self::config()).await
+    }
+
+    /// Creates a server that also accepts TLS clients, on `tls_socket`.
+    pub async fn with_tls(cert_path: &Path, key_path: &Path) -> Result<Self, Error> {
+        Self::with_config(ServerConfig {
+            tls: Some(TlsConfig {
+                bind: vec![Self::any_port()],
+                cert: cert_path.into(),
+                key: key_path.into(),
+            }),
+            ..Self::config()
+        })
+        .await
+    }
+
+    /// Creates a server that also accepts IRC clients, on `irc_socket`.
+    pub async fn with_irc() -> Result<Self, Error> {
+        Self::with_config(ServerConfig {
+            irc_bind: vec![Self::any_port()],
+            ..Self::config()
+        })
+        .await
+    }
+
+    /// Creates a server that also accepts WebSocket clients, on `websocket_socket`.
+    pub async fn with_websocket() -> Result<Self, Error> {
+        Self::with_config(ServerConfig {
+            websocket_bind: vec![Self::any_port()],
+            ..Self::config()
+        })
+        .await
+    }
+
+    pub async fn with_config(config: ServerConfig) -> Result<Self, Error> {
+        let mut server = Server::new(config).await?;
+        let socket = server
+            .local_addr()?
+            .expect("test servers accept native clients");
+        let tls_socket = server.tls_local_addr()?;
+        let irc_socket = server.irc_local_addr()?;
+        let websocket_socket = server.websocket_local_addr()?;
+        let flood_stats = server.flood_stats();
+        let shutdown = server.shutdown_handle();
         let handle = tokio::spawn(async move {
             server.listen().await?;
             Ok(())
         });
-        Ok(Self { socket, handle })
+        Ok(Self {
+            socket,
+            tls_socket,
+            irc_socket,
+            websocket_socket,
+            flood_stats,
+            shutdown,
+            handle,
+        })
+    }
+
+    /// Shuts the server down gracefully, waiting for it to finish.
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        self.shutdown.shutdown();
+        (&mut self.handle).await?
     }
 }
 
@@ -34,10 +120,31 @@ impl Drop for TestServer {
     }
 }
 
-pub struct TestClient(Client);
+pub struct TestClient<S = TcpStream>(Client<S>);
 
 impl TestClient {
+    pub async fn new(server_addr: &SocketAddr) -> Result<Self, Error> {
+        let client = Self::timeout_call(Client::new(server_addr)).await??;
+        Ok(Self(client))
+    }
+}
+
+impl TestClient<TlsStream<TcpStream>> {
+    pub async fn new_tls(
+        server_addr: &SocketAddr,
+        server_name: &str,
+        ca_path: &Path,
+    ) -> Result<Self, Error> {
+        let client =
+            Self::timeout_call(Client::new_tls(server_addr, server_name, ca_path)).await??;
+        Ok(Self(client))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestClient<S> {
     const TIMEOUT: Duration = Duration::from_millis(10);
+    /// Password hashing is slow on purpose, so commands doing it get longer.
+    const HASH_TIMEOUT: Duration = Duration::from_secs(5);
 
     async fn timeout_call<T: Future>(f: T) -> Result<T::Output, Error> {
         match timeout(Self::TIMEOUT, f).await {
@@ -46,11 +153,6 @@ impl TestClient {
         }
     }
 
-    pub async fn new(server_addr: &SocketAddr) -> Result<Self, Error> {
-        let client = Self::timeout_call(Client::new(server_addr)).await??;
-        Ok(Self(client))
-    }
-
     pub async fn send(&mut self, msg: &str) -> Result<(), Error> {
         Self::timeout_call(self.0.send(msg)).await??;
         Ok(())
@@ -60,4 +162,68 @@ impl TestClient {
         let msg = Self::timeout_call(self.0.recv()).await??;
         Ok(msg)
     }
+
+    /// Receives a message that takes a while to come, such as the reply to a password.
+    pub async fn recv_slow(&mut self) -> Result<String, Error> {
+        let msg = timeout(Self::HASH_TIMEOUT, self.0.recv()).await??;
+        Ok(msg)
+    }
+
+    pub async fn names(&mut self, channel: Option<&str>) -> Result<Vec<String>, Error> {
+        let names = Self::timeout_call(self.0.names(channel)).await??;
+        Ok(names)
+    }
+
+    pub async fn who(&mut self, user: &str) -> Result<Vec<WhoReply>, Error> {
+        let replies = Self::timeout_call(self.0.who(user)).await??;
+        Ok(replies)
+    }
+
+    pub async fn list(&mut self, pattern: Option<&str>) -> Result<Vec<ListReply>, Error> {
+        let replies = Self::timeout_call(self.0.list(pattern)).await??;
+        Ok(replies)
+    }
+
+    pub async fn register(&mut self, password: &str) -> Result<(), Error> {
+        timeout(Self::HASH_TIMEOUT, self.0.register(password)).await??;
+        Ok(())
+    }
+
+    pub async fn identify(&mut self, user: &str, password: &str) -> Result<(), Error> {
+        timeout(Self::HASH_TIMEOUT, self.0.identify(user, password)).await??;
+        Ok(())
+    }
+
+    pub async fn use_json(&mut self) -> Result<(), Error> {
+        Self::timeout_call(self.0.use_json()).await??;
+        Ok(())
+    }
+
+    pub async fn send_request(&mut self, request: &Request) -> Result<(), Error> {
+        Self::timeout_call(self.0.send_request(request)).await??;
+        Ok(())
+    }
+
+    pub async fn recv_event(&mut self) -> Result<Event, Error> {
+        let event = Self::timeout_call(self.0.recv_event()).await??;
+        Ok(event)
+    }
+
+    /// Receives an event, expecting it to be an error event from the server.
+    pub async fn recv_error_event(&mut self) -> Result<ErrorCode, Error> {
+        match Self::timeout_call(self.0.recv_event()).await? {
+            Err(ClientError::Server(reply)) => Ok(reply.code),
+            Ok(event) => Err(anyhow!("expected an error event, got `{:?}`", event)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Receives a message, expecting it to be an error reply from the server.
+    pub async fn recv_error(&mut self) -> Result<ErrorCode, Error> {
+        match Self::timeout_call(self.0.recv()).await? {
+            Err(ClientError::Server(reply)) => Ok(reply.code),
+            Ok(msg) => Err(anyhow!("expected an error reply, got `{}`", msg)),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
@@ -0,0 +1,241 @@
+mod common;
+
+use std::{fs, net::SocketAddr, path::PathBuf, process};
+
+use anyhow::Error;
+use chat::{
+    config::{ConfigError, FloodAction, RateLimitConfig, ServerConfig, SlowConsumerPolicy},
+    reply::ErrorCode,
+    server::ServerError,
+};
+use common::{TestClient as Client, TestServer as Server};
+use tracing::Level;
+
+/// Writes `contents` to a temporary file, returning its path.
+fn config_file(name: &str, contents: &str) -> Result<PathBuf, Error> {
+    let path = std::env::temp_dir().join(format!("chat-config-{}-{}.toml", process::id(), name));
+    fs::write(&path, contents)?;
+    Ok(path)
+}
+
+#[test]
+fn test_load_config() -> Result<(), Error> {
+    let path = config_file(
+        "load",
+        r#"
+        bind = ["127.0.0.1:4321", "[::1]:4321"]
+        irc_bind = ["0.0.0.0:6667"]
+        channel_capacity = 10
+        channel_shards = 16
+        slow_consumer = "buffer"
+        max_clients = 100
+        max_clients_per_ip = 5
+        admins = ["alice"]
+        log_level = "info"
+
+        [accounts]
+        file = "accounts"
+
+        [rate_limit]
+        per_second = 2
+        action = "disconnect"
+
+        [tls]
+        bind = ["0.0.0.0:4322"]
+        cert = "cert.pem"
+        key = "key.pem"
+        "#,
+    )?;
+    let config = ServerConfig::load(&path);
+    fs::remove_file(&path)?;
+    let config = config?;
+
+    let bind: Vec<SocketAddr> = vec!["127.0.0.1:4321".parse()?, "[::1]:4321".parse()?];
+    assert_eq!(config.bind, bind);
+    assert_eq!(config.irc_bind, vec!["0.0.0.0:6667".parse()?]);
+    assert!(config.websocket_bind.is_empty());
+    assert_eq!(config.channel_capacity, 10);
+    assert_eq!(config.channel_shards, 16);
+    assert_eq!(config.slow_consumer, SlowConsumerPolicy::Buffer);
+    assert_eq!(config.max_clients, Some(100));
+    assert_eq!(config.max_clients_per_ip, Some(5));
+    assert_eq!(config.admins, vec!["alice"]);
+    assert_eq!(config.log_level, Level::INFO);
+    let accounts = config.accounts.unwrap();
+    assert_eq!(accounts.file, Some(PathBuf::from("accounts")));
+    assert!(accounts.reserve_names);
+    let rate_limit = config.rate_limit.unwrap();
+    assert_eq!(rate_limit.per_second.get(), 2);
+    assert_eq!(rate_limit.burst, RateLimitConfig::default().burst);
+    assert_eq!(rate_limit.action, FloodAction::Disconnect);
+    let tls = config.tls.unwrap();
+    assert_eq!(tls.cert, PathBuf::from("cert.pem"));
+    assert_eq!(tls.key, PathBuf::from("key.pem"));
+
+    // Everything else keeps its default.
+    let default = ServerConfig::default();
+    assert_eq!(config.max_line_length, default.max_line_length);
+    assert_eq!(config.max_name_length, default.max_name_length);
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_config() -> Result<(), Error> {
+    let path = config_file("invalid", "max_name_lenght = 10")?;
+    let result = ServerConfig::load(&path);
+    fs::remove_file(&path)?;
+    assert!(matches!(result, Err(ConfigError::Parse(..))));
+
+    let path = config_file("invalid-level", r#"log_level = "loud""#)?;
+    let result = ServerConfig::load(&path);
+    fs::remove_file(&path)?;
+    assert!(matches!(result, Err(ConfigError::Parse(..))));
+
+    assert!(matches!(
+        ServerConfig::load(&PathBuf::from("/nonexistent/chat.toml")),
+        Err(ConfigError::Read(..))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_zero_settings() -> Result<(), Error> {
+    // Settings that would leave the server unable to run are refused when loaded.
+    for setting in &["channel_capacity", "channel_shards", "ping_interval_ms"] {
+        let path = config_file(setting, &format!("{} = 0", setting))?;
+        let result = ServerConfig::load(&path);
+        fs::remove_file(&path)?;
+        assert!(matches!(result, Err(ConfigError::Invalid(name)) if name == *setting));
+    }
+    let path = config_file("history", "[history]\nchannels = 0")?;
+    let result = ServerConfig::load(&path);
+    fs::remove_file(&path)?;
+    assert!(matches!(
+        result,
+        Err(ConfigError::Invalid("history.channels"))
+    ));
+
+    // As well as when given to the server directly.
+    let result = Server::with_config(ServerConfig {
+        outbound_queue: 0,
+        ..Server::config()
+    })
+    .await;
+    let err = result.err().expect("a server without an outbound queue");
+    let err = err.downcast::<ServerError>()?;
+    assert!(matches!(
+        err,
+        ServerError::Config(ConfigError::Invalid("outbound_queue"))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_name_length() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        max_name_length: 4,
+        ..Server::config()
+    })
+    .await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::InvalidJoin);
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN food joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    joe.send("JOIN cooking").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::InvalidCommand);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_line_length() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        max_line_length: 32,
+        ..Server::config()
+    })
+    .await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    joe.send(&"a".repeat(64)).await?;
+    joe.send("short").await?;
+    assert_eq!(joe.recv().await?, "joe: short");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_clients() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        max_clients: Some(1),
+        ..Server::config()
+    })
+    .await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    // The second client is told why, and disconnected straight away.
+    let mut alice = Client::new(&server.socket).await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::ServerFull);
+    alice.send("JOIN cooking alice").await.ok();
+    assert!(alice.recv().await.is_err());
+    assert!(joe.recv().await.is_err()); // should timeout
+
+    // Once the first client leaves, there's room for another one.
+    drop(joe);
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_clients_per_ip() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        irc_bind: vec![Server::any_port()],
+        max_clients_per_ip: Some(2),
+        ..Server::config()
+    })
+    .await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    // Clients that never get as far as joining count too.
+    let idle = Client::new(&server.socket).await?;
+
+    let mut alice = Client::new(&server.socket).await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::TooManyConnections);
+    assert!(alice.recv().await.is_err()); // connection was closed
+    let mut bob = Client::new(&server.irc_socket.unwrap()).await?;
+    assert_eq!(
+        bob.recv().await?,
+        "ERROR :Closing link: Too many connections from your host"
+    );
+
+    // The count goes down however clients leave, and refused clients never counted.
+    drop(idle);
+    joe.send("QUIT").await?;
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    let mut bob = Client::new(&server.socket).await?;
+    bob.send("JOIN cooking bob").await?;
+    assert_eq!(bob.recv().await?, "bob has joined");
+
+    Ok(())
+}
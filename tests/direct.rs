@@ -0,0 +1,106 @@
+mod common;
+
+use anyhow::Error;
+use chat::reply::ErrorCode;
+use common::{TestClient as Client, TestServer as Server};
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_direct_message() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "cooking", "joe").await?;
+    let mut alice = join(&server, "cooking", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    // bob isn't in any channel with joe, but can still be reached.
+    let mut bob = join(&server, "rust", "bob").await?;
+
+    joe.send("MSG alice psst").await?;
+    assert_eq!(alice.recv().await?, "joe (private): psst");
+    joe.send("MSG bob hello  there").await?;
+    assert_eq!(bob.recv().await?, "joe (private): hello  there");
+
+    // Nobody else sees them, not even the sender.
+    assert!(joe.recv().await.is_err()); // should timeout
+    assert!(alice.recv().await.is_err()); // should timeout
+
+    // Direct messages aren't prefixed, even for users in many channels.
+    alice.send("JOIN rust").await?;
+    assert_eq!(alice.recv().await?, "[rust] alice has joined");
+    assert_eq!(bob.recv().await?, "alice has joined");
+    bob.send("MSG alice hi").await?;
+    assert_eq!(alice.recv().await?, "bob (private): hi");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_direct_message_offline() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "cooking", "joe").await?;
+    joe.send("MSG alice anyone there?").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::NoSuchUser);
+
+    let mut alice = join(&server, "rust", "alice").await?;
+    joe.send("MSG alice anyone there?").await?;
+    assert_eq!(alice.recv().await?, "joe (private): anyone there?");
+
+    // Once alice is gone, she can't be reached anymore.
+    alice.send("QUIT").await?;
+    joe.send("MSG alice come back").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::NoSuchUser);
+
+    drop(alice);
+    let mut alice = join(&server, "cooking", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    joe.send("MSG alice welcome back").await?;
+    assert_eq!(alice.recv().await?, "joe (private): welcome back");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_direct_message_irc() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut joe = join(&server, "cooking", "joe").await?;
+
+    let mut alice = Client::new(&server.irc_socket.unwrap()).await?;
+    alice.send("NICK joe").await?;
+    alice.send("USER alice 0 * :Alice").await?;
+    // The nickname is taken, so alice has to pick another one.
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 433 * joe :Nickname is already in use"
+    );
+    alice.send("NICK alice").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 001 alice :Welcome to the chat server, alice"
+    );
+    for _ in 0..4 {
+        alice.recv().await?;
+    }
+
+    joe.send("MSG alice hi").await?;
+    assert_eq!(alice.recv().await?, ":joe!joe@chat PRIVMSG alice hi");
+
+    alice.send("PRIVMSG joe :hello yourself").await?;
+    assert_eq!(joe.recv().await?, "alice (private): hello yourself");
+
+    alice.send("PRIVMSG bob :are you there?").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 401 alice bob :No such nick/channel"
+    );
+
+    Ok(())
+}
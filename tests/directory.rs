@@ -0,0 +1,163 @@
+mod common;
+
+use anyhow::Error;
+use chat::reply::{ErrorCode, ListReply};
+use common::{TestClient as Client, TestServer as Server};
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+fn listing(channel: &str, members: usize, topic: Option<&str>) -> ListReply {
+    ListReply {
+        channel: channel.to_owned(),
+        members,
+        topic: topic.map(str::to_owned),
+    }
+}
+
+#[tokio::test]
+async fn test_list() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    let _bob = join(&server, "ruby", "bob").await?;
+    let _carol = join(&server, "cooking", "carol").await?;
+
+    // Channels are listed by name.
+    assert_eq!(
+        alice.list(None).await?,
+        vec![
+            listing("cooking", 1, None),
+            listing("ruby", 1, None),
+            listing("rust", 2, None),
+        ]
+    );
+    assert_eq!(
+        alice.list(Some("ru*")).await?,
+        vec![listing("ruby", 1, None), listing("rust", 2, None)]
+    );
+    assert_eq!(
+        alice.list(Some("r?st")).await?,
+        vec![listing("rust", 2, None)]
+    );
+    assert_eq!(
+        alice.list(Some("*ing")).await?,
+        vec![listing("cooking", 1, None)]
+    );
+    assert!(alice.list(Some("go*")).await?.is_empty());
+
+    alice.send("LIST ru*").await?;
+    assert_eq!(alice.recv().await?, "LIST ruby 1");
+    assert_eq!(alice.recv().await?, "LIST rust 2");
+    assert_eq!(alice.recv().await?, "END LIST ru*");
+
+    // Empty channels are gone from the listing.
+    alice.send("PART").await?;
+    assert_eq!(joe.recv().await?, "alice has left");
+    joe.send("PART").await?;
+    assert_eq!(
+        alice.list(None).await?,
+        vec![listing("cooking", 1, None), listing("ruby", 1, None)]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_topic() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    let mut bob = join(&server, "cooking", "bob").await?;
+
+    joe.send("TOPIC rust").await?;
+    assert_eq!(joe.recv().await?, "TOPIC rust");
+
+    // Every member is told about the new topic.
+    joe.send("TOPIC rust all about crabs").await?;
+    assert_eq!(joe.recv().await?, "joe set the topic to: all about crabs");
+    assert_eq!(alice.recv().await?, "joe set the topic to: all about crabs");
+
+    // Anyone may read the topic, but only members may set it.
+    bob.send("TOPIC rust").await?;
+    assert_eq!(bob.recv().await?, "TOPIC rust all about crabs");
+    bob.send("TOPIC rust all about rubies").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::NotInChannel);
+
+    assert_eq!(
+        bob.list(None).await?,
+        vec![
+            listing("cooking", 1, None),
+            listing("rust", 2, Some("all about crabs")),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_topic() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.send("TOPIC rust all about crabs").await?;
+    assert_eq!(joe.recv().await?, "joe set the topic to: all about crabs");
+
+    let mut alice = Client::new(&server.irc_socket.unwrap()).await?;
+    alice.send("NICK alice").await?;
+    alice.send("USER alice 0 * :alice").await?;
+    // The welcome burst.
+    for _ in 0..5 {
+        alice.recv().await?;
+    }
+
+    alice.send("LIST").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 322 alice #rust 1 :all about crabs"
+    );
+    assert_eq!(alice.recv().await?, ":chat 323 alice :End of /LIST");
+
+    // The topic is sent right after joining.
+    alice.send("JOIN #rust").await?;
+    assert_eq!(alice.recv().await?, ":alice!alice@chat JOIN #rust");
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 332 alice #rust :all about crabs"
+    );
+    assert_eq!(alice.recv().await?, ":chat 353 alice = #rust :@joe alice");
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 366 alice #rust :End of /NAMES list"
+    );
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    alice.send("TOPIC #rust :crabs only").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":alice!alice@chat TOPIC #rust :crabs only"
+    );
+    assert_eq!(joe.recv().await?, "alice set the topic to: crabs only");
+
+    alice.send("TOPIC #cooking").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 331 alice #cooking :No topic is set"
+    );
+    alice.send("TOPIC #cooking :recipes").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 442 alice #cooking :You're not on that channel"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+mod common;
+
+use anyhow::{anyhow, Error};
+use chat::{
+    codec::ChatCodec,
+    config::{HistoryConfig, ServerConfig},
+    json::{ChannelMessage, Event, MessageBody, Request},
+    session::MessageKind,
+};
+use chrono::{DateTime, Utc};
+use common::{TestClient as Client, TestServer as Server};
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+
+fn join_request(chan: &str, user: Option<&str>) -> Request {
+    Request::Join {
+        channel: chan.into(),
+        user: user.map(str::to_owned),
+        key: None,
+    }
+}
+
+/// Connects to the server's native listener speaking JSON, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<(Client, ChannelMessage), Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.use_json().await?;
+    client.send_request(&join_request(chan, Some(user))).await?;
+    let joined = recv_message(&mut client).await?;
+    Ok((client, joined))
+}
+
+/// Receives an event, expecting it to be a message broadcast to a channel.
+async fn recv_message(client: &mut Client) -> Result<ChannelMessage, Error> {
+    match client.recv_event().await? {
+        Event::Message(msg) => Ok(msg),
+        event => Err(anyhow!("expected a message, got `{:?}`", event)),
+    }
+}
+
+fn say(text: &str) -> Request {
+    Request::Say {
+        channel: None,
+        text: text.into(),
+    }
+}
+
+/// Checks `msg` was sent between `before` and now.
+fn assert_sent_since(msg: &ChannelMessage, before: DateTime<Utc>) {
+    assert!(msg.time >= before, "{:?} sent before {}", msg, before);
+    assert!(msg.time <= Utc::now(), "{:?} sent in the future", msg);
+}
+
+#[tokio::test]
+async fn test_ids_and_times() -> Result<(), Error> {
+    let server = Server::new().await?;
+    let before = Utc::now();
+
+    let (mut joe, joined) = join(&server, "rust", "joe").await?;
+    let (mut alice, _) = join(&server, "rust", "alice").await?;
+    joe.send_request(&say("one")).await?;
+    alice.send_request(&say("two")).await?;
+
+    let mut msgs = vec![joined];
+    for _ in 0..3 {
+        msgs.push(recv_message(&mut joe).await?);
+    }
+    // Ids grow in the order the channel's messages are received.
+    for pair in msgs.windows(2) {
+        assert!(pair[0].id < pair[1].id, "{:?}", pair);
+    }
+    for msg in &msgs {
+        assert_eq!(msg.channel, "rust");
+        assert_sent_since(msg, before);
+    }
+
+    // Ids are unique across channels too.
+    joe.send_request(&join_request("go", None)).await?;
+    let go = recv_message(&mut joe).await?;
+    assert_eq!(go.channel, "go");
+    assert!(go.id > msgs.last().unwrap().id);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_utc_timestamps() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut socket = ChatCodec::new(TcpStream::connect(&server.socket).await?);
+    socket.send("PROTO json").await?;
+    socket.next().await.unwrap()?;
+    socket
+        .send(&join_request("rust", Some("joe")).to_line())
+        .await?;
+    let line = socket.next().await.unwrap()?;
+
+    let event: serde_json::Value = serde_json::from_str(&line)?;
+    let time = event["time"]
+        .as_str()
+        .ok_or_else(|| anyhow!("no time in `{}`", line))?;
+    assert!(time.ends_with('Z'), "`{}` isn't in UTC", time);
+    DateTime::parse_from_rfc3339(time)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sender_and_kind() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let (mut joe, joined) = join(&server, "rust", "joe").await?;
+    assert_eq!(joined.sender.as_deref(), Some("joe"));
+    assert_eq!(joined.kind, MessageKind::Join);
+
+    let (mut alice, _) = join(&server, "rust", "alice").await?;
+    let (_bob, _) = join(&server, "rust", "bob").await?;
+    recv_message(&mut joe).await?;
+    recv_message(&mut joe).await?;
+
+    // A user can't pass for someone else by typing their name.
+    alice.send_request(&say("bob: hi")).await?;
+    let text = recv_message(&mut joe).await?;
+    assert_eq!(text.sender.as_deref(), Some("alice"));
+    assert_eq!(text.kind, MessageKind::Text);
+    assert_eq!(
+        text.body,
+        MessageBody::Text {
+            text: "bob: hi".into()
+        }
+    );
+
+    joe.send_request(&Request::Kick {
+        user: "bob".into(),
+        reason: None,
+    })
+    .await?;
+    let kicked = recv_message(&mut joe).await?;
+    assert_eq!(kicked.sender.as_deref(), Some("joe"));
+    assert_eq!(kicked.kind, MessageKind::Leave);
+
+    alice.send_request(&Request::Part { channel: None }).await?;
+    let left = recv_message(&mut joe).await?;
+    assert_eq!(left.sender.as_deref(), Some("alice"));
+    assert_eq!(left.kind, MessageKind::Leave);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replayed_envelope() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        history: Some(HistoryConfig::default()),
+        ..Server::config()
+    })
+    .await?;
+
+    let before = Utc::now();
+    let (mut joe, _) = join(&server, "rust", "joe").await?;
+    joe.send_request(&say("hello")).await?;
+    let said = recv_message(&mut joe).await?;
+
+    // The replay comes right after joining, keeps the time it was said at rather than the
+    // time it's replayed at, and gets a new id.
+    let (mut alice, joined) = join(&server, "rust", "alice").await?;
+    let replayed = recv_message(&mut alice).await?;
+    assert_eq!(replayed.sender.as_deref(), Some("joe"));
+    assert_eq!(replayed.kind, MessageKind::Text);
+    assert_eq!(
+        replayed.body,
+        MessageBody::History {
+            text: "hello".into()
+        }
+    );
+    assert!(before <= replayed.time && replayed.time <= said.time);
+    assert!(replayed.id > joined.id);
+
+    Ok(())
+}
@@ -0,0 +1,222 @@
+mod common;
+
+use std::{fs, path::Path, process};
+
+use anyhow::{anyhow, Error};
+use chat::{
+    config::{HistoryConfig, ServerConfig},
+    reply::ErrorCode,
+};
+use chrono::DateTime;
+use common::{TestClient as Client, TestServer as Server};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+fn history_config(replay: usize) -> ServerConfig {
+    ServerConfig {
+        history: Some(HistoryConfig {
+            replay,
+            ..HistoryConfig::default()
+        }),
+        ..Server::config()
+    }
+}
+
+/// Strips the timestamp off of a replayed message, checking it is one.
+fn strip_time(line: &str) -> Result<&str, Error> {
+    let rest = line
+        .strip_prefix('[')
+        .ok_or_else(|| anyhow!("no timestamp in `{}`", line))?;
+    let (time, rest) = rest
+        .split_once("] ")
+        .ok_or_else(|| anyhow!("no timestamp in `{}`", line))?;
+    DateTime::parse_from_rfc3339(time)?;
+    Ok(rest)
+}
+
+/// Has `joe` join `cooking` and say each of `lines` in it.
+async fn chatter(server: &Server, lines: &[&str]) -> Result<Client, Error> {
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    for line in lines {
+        joe.send(line).await?;
+        assert_eq!(joe.recv().await?, format!("joe: {}", line));
+    }
+    Ok(joe)
+}
+
+#[tokio::test]
+async fn test_replay_on_join() -> Result<(), Error> {
+    let server = Server::with_config(history_config(2)).await?;
+    let mut joe = chatter(&server, &["one", "two", "three"]).await?;
+
+    // Only the last messages are replayed, right after joining.
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: two");
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: three");
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    joe.send("four").await?;
+    assert_eq!(alice.recv().await?, "joe: four");
+
+    // Replays in a second channel are prefixed like any other message.
+    let mut bob = Client::new(&server.socket).await?;
+    bob.send("JOIN rust bob").await?;
+    assert_eq!(bob.recv().await?, "bob has joined");
+    bob.send("JOIN cooking").await?;
+    assert_eq!(bob.recv().await?, "[cooking] bob has joined");
+    for expected in ["joe: three", "joe: four"] {
+        let line = bob.recv().await?;
+        let line = line.strip_prefix("[cooking] ").unwrap_or_default();
+        assert_eq!(strip_time(line)?, expected);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_history_channels_are_bounded() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        history: Some(HistoryConfig {
+            channels: 2,
+            ..HistoryConfig::default()
+        }),
+        channel_shards: 1,
+        ..Server::config()
+    })
+    .await?;
+    let mut joe = chatter(&server, &["one"]).await?;
+    for chan in &["rust", "gardening"] {
+        joe.send(&format!("JOIN {}", chan)).await?;
+        assert_eq!(joe.recv().await?, format!("[{}] joe has joined", chan));
+        joe.send(&format!("SAY {} hi", chan)).await?;
+        assert_eq!(joe.recv().await?, format!("[{}] joe: hi", chan));
+    }
+
+    // The history of the channel used least recently made room for the last one.
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert!(alice.recv().await.is_err()); // should timeout
+    alice.send("JOIN rust").await?;
+    assert_eq!(alice.recv().await?, "[rust] alice has joined");
+    let line = alice.recv().await?;
+    let line = line.strip_prefix("[rust] ").unwrap_or_default();
+    assert_eq!(strip_time(line)?, "joe: hi");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_history_command() -> Result<(), Error> {
+    let server = Server::with_config(history_config(0)).await?;
+    let _joe = chatter(&server, &["one", "two", "three"]).await?;
+
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+
+    alice.send("HISTORY cooking 2").await?;
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: two");
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: three");
+    assert_eq!(alice.recv().await?, "END HISTORY cooking");
+
+    alice.send("HISTORY cooking 100").await?;
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: one");
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: two");
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: three");
+    assert_eq!(alice.recv().await?, "END HISTORY cooking");
+
+    alice.send("HISTORY rust 10").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NotInChannel);
+    alice.send("HISTORY cooking lots").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::InvalidCommand);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_history_disabled() -> Result<(), Error> {
+    let server = Server::new().await?;
+    let _joe = chatter(&server, &["one"]).await?;
+
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert!(alice.recv().await.is_err()); // should timeout
+
+    alice.send("HISTORY cooking 10").await?;
+    assert_eq!(alice.recv().await?, "END HISTORY cooking");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_history_file() -> Result<(), Error> {
+    let path = std::env::temp_dir().join(format!("chat-history-{}.log", process::id()));
+    let config = ServerConfig {
+        history: Some(HistoryConfig {
+            file: Some(path.clone()),
+            ..HistoryConfig::default()
+        }),
+        ..Server::config()
+    };
+    let result = history_file(config, &path).await;
+    fs::remove_file(&path).ok();
+    result
+}
+
+async fn history_file(config: ServerConfig, path: &Path) -> Result<(), Error> {
+    let mut server = Server::with_config(config.clone()).await?;
+    drop(chatter(&server, &["before the restart"]).await?);
+    server.shutdown().await?;
+
+    let contents = fs::read_to_string(path)?;
+    assert!(contents.ends_with(" cooking joe before the restart\n"));
+
+    // A new server picks up where the last one left off.
+    let server = Server::with_config(config).await?;
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert_eq!(strip_time(&alice.recv().await?)?, "joe: before the restart");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_irc() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        irc_bind: vec![Server::any_port()],
+        ..history_config(1)
+    })
+    .await?;
+    let _joe = chatter(&server, &["hello irc"]).await?;
+
+    let stream = TcpStream::connect(server.irc_socket.unwrap()).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer
+        .write_all(b"NICK alice\r\nUSER alice 0 * :Alice\r\nJOIN #cooking\r\n")
+        .await?;
+
+    let replay = loop {
+        let line = lines.next_line().await?.unwrap();
+        if line.contains(" PRIVMSG ") {
+            break line;
+        }
+    };
+    let (prefix, text) = replay
+        .split_once(" :")
+        .ok_or_else(|| anyhow!("malformed `{}`", replay))?;
+    assert!(prefix.starts_with(":joe!"));
+    assert!(prefix.ends_with(" PRIVMSG #cooking"));
+    assert_eq!(strip_time(text)?, "hello irc");
+
+    Ok(())
+}
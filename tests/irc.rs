@@ -0,0 +1,234 @@
+mod common;
+
+use anyhow::Error;
+use chat::{irc::IrcMessage, json::Request, reply::ErrorCode};
+use common::{TestClient as Client, TestServer as Server};
+
+/// Connects to the server's IRC listener and registers as `nick`.
+async fn register(server: &Server, nick: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.irc_socket.unwrap()).await?;
+    client.send(&format!("NICK {}", nick)).await?;
+    client.send(&format!("USER {} 0 * :{}", nick, nick)).await?;
+    assert_eq!(
+        client.recv().await?,
+        format!(":chat 001 {} :Welcome to the chat server, {}", nick, nick)
+    );
+    for numeric in &["002", "003", "004", "422"] {
+        let reply = IrcMessage::parse(&client.recv().await?).unwrap();
+        assert_eq!(&reply.command, numeric);
+    }
+    Ok(client)
+}
+
+#[test]
+fn test_irc_message() {
+    let msg = IrcMessage::parse(":joe!joe@chat PRIVMSG #rust :hello there").unwrap();
+    assert_eq!(msg.prefix.as_deref(), Some("joe!joe@chat"));
+    assert_eq!(msg.command, "PRIVMSG");
+    assert_eq!(msg.params, vec!["#rust", "hello there"]);
+    assert_eq!(msg.to_string(), ":joe!joe@chat PRIVMSG #rust :hello there");
+
+    let msg = IrcMessage::parse("join #a,#b").unwrap();
+    assert_eq!(msg.prefix, None);
+    assert_eq!(msg.command, "JOIN");
+    assert_eq!(msg.params, vec!["#a,#b"]);
+    assert_eq!(msg.to_string(), "JOIN #a,#b");
+
+    assert_eq!(IrcMessage::parse(""), None);
+    assert_eq!(IrcMessage::parse("PRIVMSG #rust :hi\r:chat 001 joe"), None);
+}
+
+#[tokio::test]
+async fn test_irc_registration() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut client = Client::new(&server.irc_socket.unwrap()).await?;
+    client.send("JOIN #rust").await?;
+    assert_eq!(client.recv().await?, ":chat 451 * :You have not registered");
+    client.send("NICK bad\x07nick").await?;
+    assert_eq!(
+        client.recv().await?,
+        ":chat 432 * bad\x07nick :Erroneous nickname"
+    );
+    client.send("PING :abc").await?;
+    assert_eq!(client.recv().await?, ":chat PONG chat abc");
+
+    let mut alice = register(&server, "alice").await?;
+    alice.send("PING 1234").await?;
+    assert_eq!(alice.recv().await?, ":chat PONG chat 1234");
+    alice.send("FROB").await?;
+    assert_eq!(alice.recv().await?, ":chat 421 alice FROB :Unknown command");
+    alice.send("USER alice 0 * :alice").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 462 alice :You may not reregister"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_chat_with_native_client() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut alice = register(&server, "alice").await?;
+    alice.send("JOIN #cooking").await?;
+    assert_eq!(alice.recv().await?, ":alice!alice@chat JOIN #cooking");
+    assert_eq!(alice.recv().await?, ":chat 353 alice = #cooking @alice");
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 366 alice #cooking :End of /NAMES list"
+    );
+    assert!(alice.recv().await.is_err()); // should timeout
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    assert_eq!(alice.recv().await?, ":joe!joe@chat JOIN #cooking");
+
+    joe.send("hi alice").await?;
+    assert_eq!(joe.recv().await?, "joe: hi alice");
+    assert_eq!(
+        alice.recv().await?,
+        ":joe!joe@chat PRIVMSG #cooking :hi alice"
+    );
+
+    // IRC clients don't get their own messages echoed back
+    alice.send("PRIVMSG #cooking :hello joe").await?;
+    assert_eq!(joe.recv().await?, "alice: hello joe");
+    assert!(alice.recv().await.is_err()); // should timeout
+
+    alice.send("NAMES #cooking").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 353 alice = #cooking :@alice joe"
+    );
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 366 alice #cooking :End of /NAMES list"
+    );
+
+    alice.send("PART #cooking :gotta go").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":alice!alice@chat PART #cooking :gotta go"
+    );
+    assert_eq!(joe.recv().await?, "alice has left (gotta go)");
+
+    joe.send("JOIN gardening").await?;
+    assert_eq!(joe.recv().await?, "[gardening] joe has joined");
+    drop(joe);
+    assert!(alice.recv().await.is_err()); // should timeout
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_errors() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    // The nickname is taken, across the whole server.
+    let mut other_joe = Client::new(&server.irc_socket.unwrap()).await?;
+    other_joe.send("NICK joe").await?;
+    other_joe.send("USER joe 0 * :joe").await?;
+    assert_eq!(
+        other_joe.recv().await?,
+        ":chat 433 * joe :Nickname is already in use"
+    );
+
+    let mut alice = register(&server, "alice").await?;
+    alice.send("JOIN cooking").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 403 alice cooking :No such channel"
+    );
+    alice.send("PRIVMSG #cooking :hi").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 404 alice #cooking :Cannot send to channel"
+    );
+    alice.send("PRIVMSG bob :hi").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 401 alice bob :No such nick/channel"
+    );
+    alice.send("PART #cooking").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 442 alice #cooking :You're not on that channel"
+    );
+    assert!(joe.recv().await.is_err()); // should timeout
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_quit() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut alice = register(&server, "alice").await?;
+    alice.send("JOIN #cooking,#rust").await?;
+    for _ in 0..6 {
+        alice.recv().await?;
+    }
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN rust joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+    assert_eq!(alice.recv().await?, ":joe!joe@chat JOIN #rust");
+
+    alice.send("QUIT :bye").await?;
+    assert_eq!(alice.recv().await?, "ERROR :Closing link: bye");
+    assert_eq!(joe.recv().await?, "alice has left (bye)");
+    assert!(alice.recv().await.is_err()); // connection was closed
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_json_line_injection() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut carol = register(&server, "carol").await?;
+    carol.send("JOIN #rust").await?;
+    for _ in 0..3 {
+        carol.recv().await?;
+    }
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.use_json().await?;
+    joe.send_request(&Request::Join {
+        channel: "rust".into(),
+        user: Some("joe".into()),
+        key: None,
+    })
+    .await?;
+    joe.recv_event().await?;
+    assert_eq!(carol.recv().await?, ":joe!joe@chat JOIN #rust");
+
+    // Line breaks in JSON strings mustn't reach IRC clients as lines of their own.
+    joe.send_request(&Request::Say {
+        channel: None,
+        text: "hi\r\n:chat 001 carol :pwned".into(),
+    })
+    .await?;
+    assert_eq!(joe.recv_error_event().await?, ErrorCode::InvalidCommand);
+    joe.send_request(&Request::Quit {
+        reason: Some("bye\r\n:chat 001 carol :pwned".into()),
+    })
+    .await?;
+    assert_eq!(joe.recv_error_event().await?, ErrorCode::InvalidCommand);
+    assert!(carol.recv().await.is_err()); // should timeout
+
+    joe.send_request(&Request::Quit {
+        reason: Some("bye".into()),
+    })
+    .await?;
+    assert_eq!(carol.recv().await?, ":joe!joe@chat PART #rust bye");
+
+    Ok(())
+}
@@ -1,6 +1,7 @@
 mod common;
 
 use anyhow::Error;
+use chat::reply::ErrorCode;
 use common::{TestClient as Client, TestServer as Server};
 
 #[tokio::test]
@@ -29,8 +30,7 @@ async fn test_join_wrong_command() -> Result<(), Error> {
 
     let mut client = Client::new(&server.socket).await?;
     client.send("WRONG some_chan foo").await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     Ok(())
 }
@@ -43,13 +43,11 @@ async fn test_join_wrong_channel_length() -> Result<(), Error> {
     client
         .send("JOIN this_channel_name_is_way_too_long_the_limit_is_20 foo")
         .await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     let mut client = Client::new(&server.socket).await?;
     client.send("JOIN 012345678901234567890 foo").await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     Ok(())
 }
@@ -62,13 +60,11 @@ async fn test_join_wrong_user_length() -> Result<(), Error> {
     client
         .send("JOIN some_chan this_user_name_is_way_too_long_the_limit_is_20")
         .await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     let mut client = Client::new(&server.socket).await?;
     client.send("JOIN some_chan 012345678901234567890").await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     Ok(())
 }
@@ -81,8 +77,7 @@ async fn test_join_all_wrong_lengths() -> Result<(), Error> {
     client
         .send("JOIN this_channel_name_is_way_too_long_the_limit_is_20 this_user_name_is_way_too_long_the_limit_is_20")
         .await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     Ok(())
 }
@@ -93,13 +88,11 @@ async fn test_join_incomplete_args() -> Result<(), Error> {
 
     let mut client = Client::new(&server.socket).await?;
     client.send("JOIN").await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     let mut client = Client::new(&server.socket).await?;
     client.send("JOIN some_chan").await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     Ok(())
 }
@@ -109,16 +102,15 @@ async fn test_join_too_many_args() -> Result<(), Error> {
     let server = Server::new().await?;
 
     let mut client = Client::new(&server.socket).await?;
-    client.send("JOIN some_chan some_user invalid").await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    // A third term is the channel's key, but nothing may come after it.
+    client.send("JOIN some_chan some_user key invalid").await?;
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     let mut client = Client::new(&server.socket).await?;
     client
         .send("JOIN some_chan some_user invalid invalid invalid invalid invalid invalid")
         .await?;
-    let err_msg = client.recv().await?;
-    assert_eq!(err_msg, "ERROR");
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidJoin);
 
     Ok(())
 }
@@ -136,7 +128,7 @@ async fn test_join_username_conflict() -> Result<(), Error> {
     client_b.send("JOIN some_chan foo").await?;
     assert!(client_a.recv().await.is_err()); // should timeout
 
-    assert_eq!(client_b.recv().await?, "ERROR");
+    assert_eq!(client_b.recv_error().await?, ErrorCode::NickInUse);
 
     Ok(())
 }
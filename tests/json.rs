@@ -0,0 +1,324 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use chat::{
+    codec::ChatCodec,
+    config::{HistoryConfig, ServerConfig},
+    json::{ChannelMessage, Event, MessageBody, Request},
+    reply::ErrorCode,
+    session::MessageKind,
+};
+use common::{TestClient as Client, TestServer as Server};
+use futures::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, time::timeout};
+
+/// Connects to the server's native listener speaking JSON, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.use_json().await?;
+    client
+        .send_request(&Request::Join {
+            channel: chan.into(),
+            user: Some(user.into()),
+            key: None,
+        })
+        .await?;
+    let joined = recv_message(&mut client).await?;
+    assert_eq!(joined.kind, MessageKind::Join);
+    assert_eq!(joined.sender.as_deref(), Some(user));
+    Ok(client)
+}
+
+/// Receives an event, expecting it to be a message broadcast to a channel.
+async fn recv_message(client: &mut Client) -> Result<ChannelMessage, Error> {
+    match client.recv_event().await? {
+        Event::Message(msg) => Ok(msg),
+        event => Err(anyhow!("expected a message, got `{:?}`", event)),
+    }
+}
+
+fn text(text: &str) -> MessageBody {
+    MessageBody::Text { text: text.into() }
+}
+
+fn say(text: &str) -> Request {
+    Request::Say {
+        channel: None,
+        text: text.into(),
+    }
+}
+
+#[tokio::test]
+async fn test_negotiation() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut socket = ChatCodec::new(TcpStream::connect(&server.socket).await?);
+    socket.send("PROTO json").await?;
+    let ack = socket.next().await.unwrap()?;
+    assert_eq!(ack, r#"{"event":"proto","format":"json"}"#);
+
+    // Unknown formats are refused, and the connection goes on with the line protocol.
+    let mut client = Client::new(&server.socket).await?;
+    client.send("PROTO xml").await?;
+    assert_eq!(client.recv_error().await?, ErrorCode::InvalidCommand);
+    client.send("JOIN rust joe").await?;
+    assert_eq!(client.recv().await?, "joe has joined");
+
+    // The format can only be picked with the very first line, later on it's just text.
+    client.send("PROTO json").await?;
+    assert_eq!(client.recv().await?, "joe: PROTO json");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_messages() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN rust alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+
+    let joined = recv_message(&mut joe).await?;
+    assert_eq!(joined.channel, "rust");
+    assert_eq!(joined.sender.as_deref(), Some("alice"));
+    assert_eq!(joined.kind, MessageKind::Join);
+    assert_eq!(joined.body, MessageBody::Joined);
+
+    // Both protocols share the same channels.
+    joe.send_request(&say("hello")).await?;
+    assert_eq!(alice.recv().await?, "joe: hello");
+    let hello = recv_message(&mut joe).await?;
+    assert_eq!(hello.sender.as_deref(), Some("joe"));
+    assert_eq!(hello.kind, MessageKind::Text);
+    assert_eq!(hello.body, text("hello"));
+    assert!(hello.id > joined.id);
+
+    alice.send("hi joe").await?;
+    let hi = recv_message(&mut joe).await?;
+    assert_eq!(hi.sender.as_deref(), Some("alice"));
+    assert_eq!(hi.body, text("hi joe"));
+    assert!(hi.id > hello.id);
+
+    alice.send("MSG joe psst").await?;
+    let direct = Event::Direct {
+        from: "alice".into(),
+        text: "psst".into(),
+    };
+    assert_eq!(joe.recv_event().await?, direct);
+
+    alice.send("PART").await?;
+    let left = recv_message(&mut joe).await?;
+    assert_eq!(left.sender.as_deref(), Some("alice"));
+    assert_eq!(left.kind, MessageKind::Leave);
+    assert_eq!(left.body, MessageBody::Left { reason: None });
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_moderation_fields() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut bob = join(&server, "rust", "bob").await?;
+    recv_message(&mut joe).await?;
+
+    // What happened is spelled out, rather than only described.
+    joe.send_request(&Request::Mode {
+        modes: Some("+k".into()),
+        args: vec!["secret".into()],
+    })
+    .await?;
+    let mode = MessageBody::Mode {
+        by: "joe".into(),
+        mode: "+k".into(),
+        arg: Some("secret".into()),
+    };
+    assert_eq!(recv_message(&mut joe).await?.body, mode);
+    assert_eq!(recv_message(&mut bob).await?.body, mode);
+
+    joe.send_request(&Request::Kick {
+        user: "bob".into(),
+        reason: Some("spam".into()),
+    })
+    .await?;
+    let kicked = recv_message(&mut joe).await?;
+    assert_eq!(kicked.sender.as_deref(), Some("joe"));
+    assert_eq!(kicked.kind, MessageKind::Leave);
+    let body = MessageBody::Kicked {
+        target: "bob".into(),
+        by: "joe".into(),
+        reason: Some("spam".into()),
+    };
+    assert_eq!(kicked.body, body);
+    assert_eq!(recv_message(&mut bob).await?.body, body);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_queries() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        history: Some(HistoryConfig {
+            replay: 0,
+            ..HistoryConfig::default()
+        }),
+        ..Server::config()
+    })
+    .await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let _alice = join(&server, "rust", "alice").await?;
+    recv_message(&mut joe).await?;
+
+    joe.send_request(&Request::Names { channel: None }).await?;
+    let names = Event::Names {
+        channel: "rust".into(),
+        users: vec!["joe".into(), "alice".into()],
+    };
+    assert_eq!(joe.recv_event().await?, names);
+
+    joe.send_request(&Request::Ping {
+        token: Some("42".into()),
+    })
+    .await?;
+    let pong = Event::Pong {
+        token: Some("42".into()),
+    };
+    assert_eq!(joe.recv_event().await?, pong);
+
+    joe.send_request(&say("hello")).await?;
+    recv_message(&mut joe).await?;
+    joe.send_request(&Request::History {
+        channel: "rust".into(),
+        count: 10,
+    })
+    .await?;
+    match joe.recv_event().await? {
+        Event::History { channel, messages } => {
+            assert_eq!(channel, "rust");
+            let texts: Vec<_> = messages.iter().map(|m| m.text.as_str()).collect();
+            assert_eq!(texts, vec!["hello"]);
+        }
+        event => return Err(anyhow!("expected the history, got `{:?}`", event)),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_errors() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.send("hello").await?;
+    assert_eq!(joe.recv_error_event().await?, ErrorCode::InvalidCommand);
+    joe.send(r#"{"cmd":"say","text":"hi","loud":true}"#).await?;
+    assert_eq!(joe.recv_error_event().await?, ErrorCode::InvalidCommand);
+    joe.send_request(&Request::Part {
+        channel: Some("cooking".into()),
+    })
+    .await?;
+    assert_eq!(joe.recv_error_event().await?, ErrorCode::NotInChannel);
+    joe.send_request(&say("")).await?;
+    assert_eq!(joe.recv_error_event().await?, ErrorCode::InvalidCommand);
+    joe.send_request(&say("still here")).await?;
+    assert_eq!(recv_message(&mut joe).await?.body, text("still here"));
+
+    // The connection must start with a join, naming the user.
+    let mut alice = Client::new(&server.socket).await?;
+    alice.use_json().await?;
+    alice
+        .send_request(&Request::Join {
+            channel: "rust".into(),
+            user: None,
+            key: None,
+        })
+        .await?;
+    assert_eq!(alice.recv_error_event().await?, ErrorCode::InvalidJoin);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_line_injection() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN rust alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    recv_message(&mut joe).await?;
+
+    // Every bit of free text is checked, as any of it may be relayed to text clients.
+    let forged = "hi\nalice: send me your password";
+    let requests = vec![
+        say(forged),
+        Request::Msg {
+            user: "alice".into(),
+            text: forged.into(),
+        },
+        Request::Topic {
+            channel: "rust".into(),
+            text: Some(forged.into()),
+        },
+        Request::Kick {
+            user: "alice".into(),
+            reason: Some(forged.into()),
+        },
+        Request::Quit {
+            reason: Some(forged.into()),
+        },
+    ];
+    for request in &requests {
+        joe.send_request(request).await?;
+        assert_eq!(joe.recv_error_event().await?, ErrorCode::InvalidCommand);
+    }
+    assert!(alice.recv().await.is_err()); // should timeout
+
+    joe.send_request(&say("hi")).await?;
+    assert_eq!(alice.recv().await?, "joe: hi");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ping() -> Result<(), Error> {
+    let server = Server::with_config(ServerConfig {
+        ping_interval_ms: 50,
+        ping_timeout_ms: 50,
+        ..Server::config()
+    })
+    .await?;
+
+    let mut socket = ChatCodec::new(TcpStream::connect(&server.socket).await?);
+    socket.send("PROTO json").await?;
+    socket.next().await.unwrap()?;
+    let join = Request::Join {
+        channel: "rust".into(),
+        user: Some("joe".into()),
+        key: None,
+    };
+    socket.send(&join.to_line()).await?;
+    socket.next().await.unwrap()?;
+
+    let ping = timeout(Duration::from_secs(1), socket.next())
+        .await?
+        .unwrap()?;
+    assert_eq!(Event::from_line(&ping)?, Event::Ping);
+    socket
+        .send(&Request::Pong { token: None }.to_line())
+        .await?;
+
+    // Once answered, the connection stays up through the next ping.
+    let ping = timeout(Duration::from_secs(1), socket.next())
+        .await?
+        .unwrap()?;
+    assert_eq!(Event::from_line(&ping)?, Event::Ping);
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Error;
+use chat::{
+    client::{Client, ClientError},
+    codec::ChatCodec,
+    config::ServerConfig,
+};
+use common::TestServer as Server;
+use futures::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, time::timeout};
+
+/// Longer than any of the timeouts given to [`quick_server`], by a good margin.
+const WAIT: Duration = Duration::from_secs(1);
+
+/// Creates a server that gives clients 50ms to join, to answer pings, and before pinging them.
+async fn quick_server() -> Result<Server, Error> {
+    Server::with_config(ServerConfig {
+        irc_bind: vec![Server::any_port()],
+        handshake_timeout_ms: 50,
+        ping_interval_ms: 50,
+        ping_timeout_ms: 50,
+        ..Server::config()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() -> Result<(), Error> {
+    let server = quick_server().await?;
+
+    let mut client = Client::new(&server.socket).await?;
+    assert!(matches!(
+        timeout(WAIT, client.recv()).await?,
+        Err(ClientError::ConnectionClosed)
+    ));
+
+    let mut client = Client::new(&server.irc_socket.unwrap()).await?;
+    client.send("NICK joe").await?;
+    assert_eq!(
+        timeout(WAIT, client.recv()).await??,
+        "ERROR :Closing link: Registration timed out"
+    );
+    assert!(matches!(
+        timeout(WAIT, client.recv()).await?,
+        Err(ClientError::ConnectionClosed)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ping_answered() -> Result<(), Error> {
+    let server = quick_server().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN rust joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    // The client answers every ping while waiting, none of which it returns.
+    assert!(timeout(Duration::from_millis(300), joe.recv())
+        .await
+        .is_err());
+    joe.send("hello").await?;
+    assert_eq!(timeout(WAIT, joe.recv()).await??, "joe: hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ping_timeout() -> Result<(), Error> {
+    let server = quick_server().await?;
+
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN rust alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+
+    let mut joe = ChatCodec::new(TcpStream::connect(server.socket).await?);
+    joe.send("JOIN rust joe").await?;
+    assert_eq!(timeout(WAIT, joe.next()).await?.unwrap()?, "joe has joined");
+    assert_eq!(timeout(WAIT, alice.recv()).await??, "joe has joined");
+
+    // Joe never answers, so he is disconnected, and leaves the channel. Alice keeps answering
+    // while she waits, so she stays.
+    let (pinged, left) = tokio::join!(
+        async {
+            let ping = timeout(WAIT, joe.next()).await?.unwrap()?;
+            let closed = timeout(WAIT, joe.next()).await?.is_none();
+            Ok::<_, Error>((ping, closed))
+        },
+        timeout(WAIT, alice.recv()),
+    );
+    assert_eq!(pinged?, ("PING".to_owned(), true));
+    assert_eq!(left??, "joe has left");
+
+    let mut bob = ChatCodec::new(TcpStream::connect(server.irc_socket.unwrap()).await?);
+    bob.send("NICK bob").await?;
+    bob.send("USER bob 0 * :bob").await?;
+    let mut lines = Vec::new();
+    while let Some(line) = timeout(WAIT, bob.next()).await? {
+        lines.push(line?);
+    }
+    assert_eq!(lines[lines.len() - 2], "PING chat");
+    assert_eq!(lines[lines.len() - 1], "ERROR :Closing link: Ping timeout");
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use anyhow::Error;
+use common::{TestClient as Client, TestServer as Server};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_rejoin_after_disconnect() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut alice = join(&server, "cooking", "alice").await?;
+    let joe = join(&server, "cooking", "joe").await?;
+    assert_eq!(alice.recv().await?, "joe has joined");
+
+    // joe goes away without a word.
+    drop(joe);
+    assert_eq!(alice.recv().await?, "joe has left");
+
+    // ... and can come back under the same name.
+    let mut joe = join(&server, "cooking", "joe").await?;
+    assert_eq!(alice.recv().await?, "joe has joined");
+    joe.send("I'm back").await?;
+    assert_eq!(alice.recv().await?, "joe: I'm back");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rejoin_after_connection_reset() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut alice = join(&server, "cooking", "alice").await?;
+
+    // joe's connection is reset rather than closed, so the server fails to read from it.
+    let stream = TcpStream::connect(server.socket).await?;
+    stream.set_linger(Some(std::time::Duration::from_secs(0)))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b"JOIN cooking joe\n").await?;
+    assert_eq!(lines.next_line().await?.as_deref(), Some("joe has joined"));
+    assert_eq!(alice.recv().await?, "joe has joined");
+    drop((lines, writer));
+
+    assert_eq!(alice.recv().await?, "joe has left");
+    let _joe = join(&server, "cooking", "joe").await?;
+    assert_eq!(alice.recv().await?, "joe has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_roster_after_departures() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut alice = Client::new(&server.irc_socket.unwrap()).await?;
+    alice.send("NICK alice").await?;
+    alice.send("USER alice 0 * :alice").await?;
+    alice.send("JOIN #cooking").await?;
+    // The welcome burst, the echoed join and the names.
+    for _ in 0..8 {
+        alice.recv().await?;
+    }
+
+    // Users coming and going, whether they quit or not, don't linger in the roster.
+    for _ in 0..3 {
+        let mut joe = join(&server, "cooking", "joe").await?;
+        assert_eq!(alice.recv().await?, ":joe!joe@chat JOIN #cooking");
+        let bob = join(&server, "cooking", "bob").await?;
+        assert_eq!(alice.recv().await?, ":bob!bob@chat JOIN #cooking");
+        assert_eq!(joe.recv().await?, "bob has joined");
+
+        joe.send("QUIT").await?;
+        assert_eq!(alice.recv().await?, ":joe!joe@chat PART #cooking");
+        drop(bob);
+        assert_eq!(alice.recv().await?, ":bob!bob@chat PART #cooking");
+    }
+
+    alice.send("NAMES #cooking").await?;
+    assert_eq!(alice.recv().await?, ":chat 353 alice = #cooking @alice");
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 366 alice #cooking :End of /NAMES list"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use anyhow::Error;
+use chat::reply::ErrorCode;
+use common::{TestClient as Client, TestServer as Server};
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_kick() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    // Only the first member of the channel is an operator.
+    alice.send("KICK joe").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NotOperator);
+    joe.send("KICK bob").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::UserNotInChannel);
+
+    joe.send("KICK alice no crabs allowed").await?;
+    assert_eq!(
+        joe.recv().await?,
+        "alice was kicked by joe (no crabs allowed)"
+    );
+    assert_eq!(
+        alice.recv().await?,
+        "[rust] alice was kicked by joe (no crabs allowed)"
+    );
+    assert_eq!(joe.names(None).await?, vec!["joe"]);
+
+    // The kicked user no longer hears from the channel, but may come back.
+    alice.send("hello?").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NoChannel);
+    joe.send("anyone?").await?;
+    alice.send("JOIN rust").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert_eq!(joe.recv().await?, "joe: anyone?");
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_op() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    let mut bob = join(&server, "rust", "bob").await?;
+    assert_eq!(joe.recv().await?, "bob has joined");
+    assert_eq!(alice.recv().await?, "bob has joined");
+
+    joe.send("OP alice").await?;
+    assert_eq!(joe.recv().await?, "joe made alice an operator");
+    assert_eq!(alice.recv().await?, "joe made alice an operator");
+    assert_eq!(bob.recv().await?, "joe made alice an operator");
+
+    // Operators can take it away from each other.
+    alice.send("DEOP joe").await?;
+    assert_eq!(alice.recv().await?, "alice took operator status from joe");
+    assert_eq!(joe.recv().await?, "alice took operator status from joe");
+    assert_eq!(bob.recv().await?, "alice took operator status from joe");
+    joe.send("KICK alice").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::NotOperator);
+
+    alice.send("KICK bob").await?;
+    assert_eq!(alice.recv().await?, "bob was kicked by alice");
+    assert_eq!(joe.recv().await?, "bob was kicked by alice");
+    assert_eq!(bob.recv().await?, "[rust] bob was kicked by alice");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ban() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+
+    joe.send("BAN bo*").await?;
+    assert_eq!(joe.recv().await?, "joe banned bo*");
+    let mut turned_away = Client::new(&server.socket).await?;
+    turned_away.send("JOIN rust bob").await?;
+    assert_eq!(turned_away.recv_error().await?, ErrorCode::Banned);
+    // Past the handshake, the user stays connected and may join other channels.
+    let mut bob = join(&server, "cooking", "bob").await?;
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::Banned);
+
+    // Bans on addresses match whoever connects from there.
+    joe.send("UNBAN bo*").await?;
+    assert_eq!(joe.recv().await?, "joe lifted the ban on bo*");
+    joe.send("BAN *@127.0.0.*").await?;
+    assert_eq!(joe.recv().await?, "joe banned *@127.0.0.*");
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::Banned);
+
+    joe.send("UNBAN *@127.0.0.*").await?;
+    assert_eq!(joe.recv().await?, "joe lifted the ban on *@127.0.0.*");
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv().await?, "[rust] bob has joined");
+    assert_eq!(joe.recv().await?, "bob has joined");
+
+    // Only operators may ban.
+    bob.send("BAN joe").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::NotOperator);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admins() -> Result<(), Error> {
+    let server = Server::with_config(chat::config::ServerConfig {
+        admins: vec!["root".to_owned()],
+        ..Server::config()
+    })
+    .await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.send("BAN r*").await?;
+    assert_eq!(joe.recv().await?, "joe banned r*");
+
+    // Admins get past bans, and are operators of every channel they join.
+    let mut root = join(&server, "rust", "root").await?;
+    assert_eq!(joe.recv().await?, "root has joined");
+    root.send("KICK joe").await?;
+    assert_eq!(root.recv().await?, "joe was kicked by root");
+    assert_eq!(joe.recv().await?, "[rust] joe was kicked by root");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_moderation() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut bob = join(&server, "rust", "bob").await?;
+    let mut alice = Client::new(&server.irc_socket.unwrap()).await?;
+    alice.send("NICK alice").await?;
+    alice.send("USER alice 0 * :alice").await?;
+    // The welcome burst.
+    for _ in 0..5 {
+        alice.recv().await?;
+    }
+    alice.send("JOIN #rust").await?;
+    assert_eq!(alice.recv().await?, ":alice!alice@chat JOIN #rust");
+    assert_eq!(alice.recv().await?, ":chat 353 alice = #rust :@bob alice");
+    alice.recv().await?;
+    assert_eq!(bob.recv().await?, "alice has joined");
+
+    alice.send("KICK #rust bob").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 482 alice #rust :You're not channel operator"
+    );
+
+    bob.send("OP alice").await?;
+    assert_eq!(bob.recv().await?, "bob made alice an operator");
+    assert_eq!(alice.recv().await?, ":bob!bob@chat MODE #rust +o alice");
+
+    alice.send("MODE #rust +b eve").await?;
+    assert_eq!(alice.recv().await?, ":alice!alice@chat MODE #rust +b eve");
+    assert_eq!(bob.recv().await?, "alice banned eve");
+    alice.send("MODE #rust b").await?;
+    assert_eq!(alice.recv().await?, ":chat 367 alice #rust eve");
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 368 alice #rust :End of channel ban list"
+    );
+
+    alice.send("KICK #rust carol").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 441 alice carol #rust :They aren't on that channel"
+    );
+    alice.send("KICK #rust bob :be nice").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":alice!alice@chat KICK #rust bob :be nice"
+    );
+    assert_eq!(
+        bob.recv().await?,
+        "[rust] bob was kicked by alice (be nice)"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_say_right_after_kick() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut bob = join(&server, "rust", "bob").await?;
+    assert_eq!(joe.recv().await?, "bob has joined");
+
+    // Bob's session may not have heard of the kick yet, which mustn't let Bob speak, nor set the
+    // topic, anyway.
+    for _ in 0..20 {
+        joe.send("KICK bob").await?;
+        bob.send("SAY rust still here").await?;
+        bob.send("TOPIC rust bob was here").await?;
+        let mut lines = Vec::new();
+        while let Ok(line) = joe.recv().await {
+            lines.push(line);
+        }
+        // Bob may have spoken before the kick, but not after.
+        let last = lines.last().map(String::as_str);
+        assert_eq!(last, Some("bob was kicked by joe"), "{:?}", lines);
+
+        bob.send("JOIN rust").await?;
+        // Past the kick and the errors, in whichever order they came.
+        let mut rejoined = false;
+        for _ in 0..5 {
+            if bob.recv().await.ok().as_deref() == Some("bob has joined") {
+                rejoined = true;
+                break;
+            }
+        }
+        assert!(rejoined);
+        assert_eq!(joe.recv().await?, "bob has joined");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+mod common;
+
+use anyhow::Error;
+use chat::{
+    channel::ChannelModes,
+    reply::{ErrorCode, ModeReply},
+};
+use common::{TestClient as Client, TestServer as Server};
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_mode_query() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.send("MODE").await?;
+    let reply = ModeReply::parse(&joe.recv().await?).unwrap();
+    assert_eq!(reply.channel, "rust");
+    assert_eq!(reply.modes, ChannelModes::default());
+
+    joe.send("MODE +kl secret 10").await?;
+    assert_eq!(joe.recv().await?, "joe set mode +k secret");
+    assert_eq!(joe.recv().await?, "joe set mode +l 10");
+    // Setting a mode the channel already has is a no-op.
+    joe.send("MODE +l 10").await?;
+    joe.send("MODE").await?;
+    assert_eq!(joe.recv().await?, "MODE rust +kl secret 10");
+
+    let mut alice = join(&server, "cooking", "alice").await?;
+    alice.send("JOIN rust bob secret").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::AlreadyJoined);
+    alice.send("JOIN rust guess").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::BadChannelKey);
+    // Once the user has a name, a lone argument after the channel is its key.
+    alice.send("JOIN rust secret").await?;
+    assert_eq!(alice.recv().await?, "[rust] alice has joined");
+    alice.send("MODE -k").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NotOperator);
+    alice.send("MODE +q").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::InvalidCommand);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_key() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.send("MODE +k secret").await?;
+    assert_eq!(joe.recv().await?, "joe set mode +k secret");
+
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN rust alice").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::BadChannelKey);
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN rust alice guess").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::BadChannelKey);
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN rust alice secret").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invite_only() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    let mut bob = join(&server, "cooking", "bob").await?;
+
+    joe.send("MODE +i").await?;
+    assert_eq!(joe.recv().await?, "joe set mode +i");
+    assert_eq!(alice.recv().await?, "joe set mode +i");
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::InviteOnly);
+
+    // Only operators may invite to invite-only channels.
+    alice.send("INVITE bob").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NotOperator);
+    joe.send("INVITE alice").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::UserOnChannel);
+    joe.send("INVITE carol").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::NoSuchUser);
+
+    joe.send("INVITE bob").await?;
+    assert_eq!(joe.recv().await?, "INVITE bob rust");
+    assert_eq!(bob.recv().await?, "joe invited you to rust");
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv().await?, "[rust] bob has joined");
+    assert_eq!(joe.recv().await?, "bob has joined");
+
+    // An invitation is good for a single join.
+    bob.send("PART rust").await?;
+    assert_eq!(joe.recv().await?, "bob has left");
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::InviteOnly);
+
+    // The invitation follows the user through a change of name, rather than staying with it.
+    joe.send("INVITE bob").await?;
+    assert_eq!(joe.recv().await?, "INVITE bob rust");
+    assert_eq!(bob.recv().await?, "joe invited you to rust");
+    bob.send("NICK robert").await?;
+    assert_eq!(bob.recv().await?, "bob is now known as robert");
+    let mut impostor = join(&server, "cooking", "bob").await?;
+    assert_eq!(bob.recv().await?, "bob has joined");
+    impostor.send("JOIN rust").await?;
+    assert_eq!(impostor.recv_error().await?, ErrorCode::InviteOnly);
+    bob.send("JOIN rust").await?;
+    assert_eq!(bob.recv().await?, "[rust] robert has joined");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_limit() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    joe.send("MODE +l 2").await?;
+    assert_eq!(joe.recv().await?, "joe set mode +l 2");
+    let _alice = join(&server, "rust", "alice").await?;
+    let mut bob = Client::new(&server.socket).await?;
+    bob.send("JOIN rust bob").await?;
+    assert_eq!(bob.recv_error().await?, ErrorCode::ChannelFull);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_moderated() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    joe.send("MODE +m").await?;
+    assert_eq!(joe.recv().await?, "joe set mode +m");
+    assert_eq!(alice.recv().await?, "joe set mode +m");
+    alice.send("hello?").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::CannotSpeak);
+    joe.send("operators still can").await?;
+    assert_eq!(joe.recv().await?, "joe: operators still can");
+    assert_eq!(alice.recv().await?, "joe: operators still can");
+
+    joe.send("MODE +v alice").await?;
+    assert_eq!(joe.recv().await?, "joe set mode +v alice");
+    assert_eq!(alice.recv().await?, "joe set mode +v alice");
+    alice.send("hello!").await?;
+    assert_eq!(alice.recv().await?, "alice: hello!");
+    assert_eq!(joe.recv().await?, "alice: hello!");
+
+    joe.send("MODE +v bob").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::UserNotInChannel);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_modes() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut bob = join(&server, "rust", "bob").await?;
+    bob.send("MODE +ik secret").await?;
+    assert_eq!(bob.recv().await?, "bob set mode +i");
+    assert_eq!(bob.recv().await?, "bob set mode +k secret");
+
+    let mut alice = Client::new(&server.irc_socket.unwrap()).await?;
+    alice.send("NICK alice").await?;
+    alice.send("USER alice 0 * :alice").await?;
+    // The welcome burst.
+    for _ in 0..5 {
+        alice.recv().await?;
+    }
+    alice.send("JOIN #rust").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 473 alice #rust :Cannot join channel (+i)"
+    );
+
+    bob.send("INVITE alice").await?;
+    assert_eq!(bob.recv().await?, "INVITE alice rust");
+    assert_eq!(alice.recv().await?, ":bob!bob@chat INVITE alice #rust");
+    alice.send("JOIN #rust").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 475 alice #rust :Cannot join channel (+k)"
+    );
+    alice.send("JOIN #rust secret").await?;
+    assert_eq!(alice.recv().await?, ":alice!alice@chat JOIN #rust");
+    alice.recv().await?;
+    alice.recv().await?;
+    assert_eq!(bob.recv().await?, "alice has joined");
+
+    alice.send("MODE #rust").await?;
+    assert_eq!(alice.recv().await?, ":chat 324 alice #rust +ik secret");
+
+    bob.send("MODE +m").await?;
+    assert_eq!(bob.recv().await?, "bob set mode +m");
+    assert_eq!(alice.recv().await?, ":bob!bob@chat MODE #rust +m");
+    alice.send("PRIVMSG #rust :hi").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 404 alice #rust :Cannot send to channel"
+    );
+
+    bob.send("OP alice").await?;
+    bob.recv().await?;
+    alice.recv().await?;
+    alice.send("MODE #rust -ik+l secret 5").await?;
+    assert_eq!(alice.recv().await?, ":alice!alice@chat MODE #rust -i");
+    assert_eq!(alice.recv().await?, ":alice!alice@chat MODE #rust -k");
+    assert_eq!(alice.recv().await?, ":alice!alice@chat MODE #rust +l 5");
+    assert_eq!(bob.recv().await?, "alice set mode -i");
+    assert_eq!(bob.recv().await?, "alice set mode -k");
+    assert_eq!(bob.recv().await?, "alice set mode +l 5");
+
+    Ok(())
+}
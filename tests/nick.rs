@@ -0,0 +1,115 @@
+mod common;
+
+use anyhow::Error;
+use chat::reply::ErrorCode;
+use common::{TestClient as Client, TestServer as Server};
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_nick() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    let mut bob = join(&server, "cooking", "bob").await?;
+    joe.send("JOIN cooking").await?;
+    assert_eq!(joe.recv().await?, "[cooking] joe has joined");
+    assert_eq!(bob.recv().await?, "joe has joined");
+
+    // Every channel the user is in hears about it.
+    joe.send("NICK joseph").await?;
+    let mut notices = vec![joe.recv().await?, joe.recv().await?];
+    notices.sort();
+    assert_eq!(
+        notices,
+        vec![
+            "[cooking] joe is now known as joseph",
+            "[rust] joe is now known as joseph"
+        ]
+    );
+    assert_eq!(alice.recv().await?, "joe is now known as joseph");
+    assert_eq!(bob.recv().await?, "joe is now known as joseph");
+
+    joe.send("hello").await?;
+    assert_eq!(bob.recv().await?, "joseph: hello");
+    assert_eq!(joe.recv().await?, "[cooking] joseph: hello");
+    // The user keeps their place in the channels.
+    assert_eq!(alice.names(None).await?, vec!["joseph", "alice"]);
+
+    // Direct messages follow the new name, and the old one is free again.
+    alice.send("MSG joe hi").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NoSuchUser);
+    alice.send("MSG joseph hi").await?;
+    assert_eq!(joe.recv().await?, "alice (private): hi");
+    let _joe = join(&server, "gardening", "joe").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nick_errors() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    let _bob = join(&server, "cooking", "bob").await?;
+
+    // Names are unique across the server, not just the user's channels.
+    joe.send("NICK alice").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::NickInUse);
+    joe.send("NICK bob").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::NickInUse);
+    joe.send("NICK this_name_is_way_too_long_to_use").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::InvalidCommand);
+
+    // Nothing happened, and keeping one's own name is a no-op.
+    joe.send("NICK joe").await?;
+    joe.send("still joe").await?;
+    assert_eq!(alice.recv().await?, "joe: still joe");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_nick() -> Result<(), Error> {
+    let server = Server::with_irc().await?;
+
+    let mut bob = join(&server, "rust", "bob").await?;
+    let mut alice = Client::new(&server.irc_socket.unwrap()).await?;
+    alice.send("NICK alice").await?;
+    alice.send("USER alice 0 * :alice").await?;
+    alice.send("JOIN #rust").await?;
+    // The welcome burst, the echoed join and the names.
+    for _ in 0..8 {
+        alice.recv().await?;
+    }
+    assert_eq!(bob.recv().await?, "alice has joined");
+
+    alice.send("NICK bob").await?;
+    assert_eq!(
+        alice.recv().await?,
+        ":chat 433 alice bob :Nickname is already in use"
+    );
+
+    alice.send("NICK alicia").await?;
+    assert_eq!(alice.recv().await?, ":alice!alice@chat NICK alicia");
+    assert_eq!(bob.recv().await?, "alice is now known as alicia");
+
+    bob.send("NICK robert").await?;
+    assert_eq!(bob.recv().await?, "bob is now known as robert");
+    assert_eq!(alice.recv().await?, ":bob!bob@chat NICK robert");
+
+    alice.send("PRIVMSG #rust :hi").await?;
+    assert_eq!(bob.recv().await?, "alicia: hi");
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use std::{num::NonZeroU32, time::Duration};
+
+use anyhow::Error;
+use chat::{
+    config::{FloodAction, RateLimitConfig, ServerConfig},
+    reply::ErrorCode,
+};
+use common::{TestClient as Client, TestServer as Server};
+use tokio::time::Instant;
+
+/// Creates a server letting clients send `burst` lines in a row, then one a second.
+async fn limited_server(burst: u32, action: FloodAction) -> Result<Server, Error> {
+    Server::with_config(ServerConfig {
+        irc_bind: vec![Server::any_port()],
+        rate_limit: Some(RateLimitConfig {
+            per_second: NonZeroU32::new(1).unwrap(),
+            burst: NonZeroU32::new(burst).unwrap(),
+            action,
+            max_violations: 2,
+        }),
+        ..Server::config()
+    })
+    .await
+}
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_drop() -> Result<(), Error> {
+    let server = limited_server(3, FloodAction::Drop).await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    // Joining took the first line of the burst.
+    for i in 0..2 {
+        joe.send(&format!("hello {}", i)).await?;
+        assert_eq!(joe.recv().await?, format!("joe: hello {}", i));
+        assert_eq!(alice.recv().await?, format!("joe: hello {}", i));
+    }
+    for i in 2..5 {
+        joe.send(&format!("hello {}", i)).await?;
+    }
+    // Only the first line dropped is reported.
+    assert_eq!(joe.recv_error().await?, ErrorCode::RateLimited);
+    assert!(joe.recv().await.is_err()); // should timeout
+    assert!(alice.recv().await.is_err()); // should timeout
+
+    assert_eq!(server.flood_stats.throttled_users(), 1);
+    assert_eq!(server.flood_stats.dropped_lines(), 3);
+    assert_eq!(server.flood_stats.disconnected_users(), 0);
+
+    // Alice sent nothing yet, so she keeps her whole burst.
+    alice.send("hi").await?;
+    assert_eq!(joe.recv().await?, "alice: hi");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delay() -> Result<(), Error> {
+    let server = limited_server(1, FloodAction::Delay).await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    let start = Instant::now();
+    joe.send("hello").await?;
+    joe.send("there").await?;
+    assert_eq!(alice.recv_slow().await?, "joe: hello");
+    assert_eq!(alice.recv_slow().await?, "joe: there");
+    // Nothing is lost, it only arrives at the rate allowed.
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+    assert_eq!(joe.recv().await?, "joe: hello");
+    assert_eq!(joe.recv().await?, "joe: there");
+
+    assert_eq!(server.flood_stats.throttled_users(), 1);
+    assert_eq!(server.flood_stats.delayed_lines(), 2);
+    assert_eq!(server.flood_stats.dropped_lines(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disconnect() -> Result<(), Error> {
+    let server = limited_server(2, FloodAction::Disconnect).await?;
+
+    let mut joe = join(&server, "rust", "joe").await?;
+    let mut alice = join(&server, "rust", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    joe.send("hello 0").await?;
+    assert_eq!(joe.recv().await?, "joe: hello 0");
+    assert_eq!(alice.recv().await?, "joe: hello 0");
+    joe.send("hello 1").await?;
+    joe.send("hello 2").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::RateLimited);
+
+    // Past the allowed number of lines dropped in a row, the connection is closed.
+    joe.send("hello 3").await?;
+    assert_eq!(joe.recv_error().await?, ErrorCode::RateLimited);
+    assert!(joe.recv().await.is_err()); // connection was closed
+    assert_eq!(alice.recv().await?, "joe has left");
+
+    assert_eq!(server.flood_stats.throttled_users(), 1);
+    assert_eq!(server.flood_stats.dropped_lines(), 2);
+    assert_eq!(server.flood_stats.disconnected_users(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_flood() -> Result<(), Error> {
+    // Registering takes two of the lines already.
+    let server = limited_server(4, FloodAction::Disconnect).await?;
+
+    let mut joe = Client::new(&server.irc_socket.unwrap()).await?;
+    joe.send("NICK joe").await?;
+    joe.send("USER joe 0 * :joe").await?;
+    for _ in 0..5 {
+        joe.recv().await?;
+    }
+
+    for i in 0..2 {
+        joe.send(&format!("PING {}", i)).await?;
+        assert_eq!(joe.recv().await?, format!(":chat PONG chat {}", i));
+    }
+    joe.send("PING 2").await?;
+    joe.send("PING 3").await?;
+    assert_eq!(
+        joe.recv().await?,
+        ":chat NOTICE joe :You are sending too fast, messages are being dropped"
+    );
+    joe.send("PING 4").await?;
+    assert_eq!(joe.recv().await?, "ERROR :Closing link: Excess Flood");
+    assert!(joe.recv().await.is_err()); // connection was closed
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_irc_flood_before_registration() -> Result<(), Error> {
+    let server = limited_server(2, FloodAction::Disconnect).await?;
+
+    let mut joe = Client::new(&server.irc_socket.unwrap()).await?;
+    joe.send("PASS one").await?;
+    joe.send("PASS two").await?;
+    joe.send("PASS three").await?;
+    joe.send("PASS four").await?;
+    assert_eq!(
+        joe.recv().await?,
+        ":chat NOTICE * :You are sending too fast, messages are being dropped"
+    );
+    joe.send("PASS five").await?;
+    assert_eq!(joe.recv().await?, "ERROR :Closing link: Excess Flood");
+    assert!(joe.recv().await.is_err()); // connection was closed
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use chat::channel::ChannelModes;
+use chat::reply::{ErrorCode, ErrorReply, ListReply, ModeReply, NamesReply, TopicReply, WhoReply};
+use chrono::{TimeZone, Utc};
+
+#[test]
+fn test_reply_line() {
+    let reply = ErrorReply::new(ErrorCode::NickInUse, "foo");
+    assert_eq!(reply.to_line(), "ERR 433 NICK_IN_USE foo");
+    assert_eq!(ErrorReply::parse(&reply.to_line()), Some(reply));
+}
+
+#[test]
+fn test_parse_reply() {
+    assert_eq!(
+        ErrorReply::parse("ERR 421 INVALID_COMMAND unknown verb `FOO`"),
+        Some(ErrorReply::new(
+            ErrorCode::InvalidCommand,
+            "unknown verb `FOO`"
+        ))
+    );
+
+    // regular messages aren't error replies
+    assert_eq!(ErrorReply::parse("joe: hello"), None);
+    assert_eq!(ErrorReply::parse("ERR has joined"), None);
+    // the code and name must agree
+    assert_eq!(ErrorReply::parse("ERR 433 NOT_IN_CHANNEL foo"), None);
+    assert_eq!(ErrorReply::parse("ERR 999 UNKNOWN foo"), None);
+}
+
+#[test]
+fn test_names_reply() {
+    let reply = NamesReply {
+        channel: "rust".to_owned(),
+        user: "joe".to_owned(),
+    };
+    assert_eq!(reply.to_line(), "NAMES rust joe");
+    assert_eq!(NamesReply::parse(&reply.to_line()), Some(reply));
+
+    assert_eq!(NamesReply::parse("NAMES rust"), None);
+    assert_eq!(NamesReply::parse("NAMES rust joe alice"), None);
+    assert_eq!(NamesReply::parse("joe: NAMES rust joe"), None);
+}
+
+#[test]
+fn test_who_reply() {
+    let reply = WhoReply {
+        user: "joe".to_owned(),
+        channel: Some("rust".to_owned()),
+        connected_since: Utc.ymd(2021, 5, 1).and_hms(12, 30, 0),
+        idle: Duration::from_secs(42),
+    };
+    assert_eq!(reply.to_line(), "WHO joe rust 2021-05-01T12:30:00Z 42");
+    assert_eq!(WhoReply::parse(&reply.to_line()), Some(reply));
+
+    let reply = WhoReply::parse("WHO joe * 2021-05-01T12:30:00Z 0").unwrap();
+    assert_eq!(reply.channel, None);
+
+    assert_eq!(WhoReply::parse("WHO joe rust yesterday 42"), None);
+    assert_eq!(WhoReply::parse("WHO joe rust 2021-05-01T12:30:00Z"), None);
+}
+
+#[test]
+fn test_list_reply() {
+    let reply = ListReply {
+        channel: "rust".to_owned(),
+        members: 3,
+        topic: Some("all about crabs".to_owned()),
+    };
+    assert_eq!(reply.to_line(), "LIST rust 3 all about crabs");
+    assert_eq!(ListReply::parse(&reply.to_line()), Some(reply));
+
+    let reply = ListReply::parse("LIST rust 3").unwrap();
+    assert_eq!(reply.topic, None);
+
+    assert_eq!(ListReply::parse("LIST rust lots"), None);
+    assert_eq!(ListReply::parse("LIST rust"), None);
+}
+
+#[test]
+fn test_topic_reply() {
+    let reply = TopicReply {
+        channel: "rust".to_owned(),
+        topic: Some("all about crabs".to_owned()),
+    };
+    assert_eq!(reply.to_line(), "TOPIC rust all about crabs");
+    assert_eq!(TopicReply::parse(&reply.to_line()), Some(reply));
+
+    let reply = TopicReply::parse("TOPIC rust").unwrap();
+    assert_eq!(reply.topic, None);
+
+    assert_eq!(TopicReply::parse("TOPIC"), None);
+}
+
+#[test]
+fn test_mode_reply() {
+    let reply = ModeReply {
+        channel: "rust".to_owned(),
+        modes: ChannelModes {
+            invite_only: true,
+            key: Some("secret".to_owned()),
+            limit: Some(10),
+            moderated: false,
+        },
+    };
+    assert_eq!(reply.to_line(), "MODE rust +ikl secret 10");
+    assert_eq!(ModeReply::parse(&reply.to_line()), Some(reply));
+
+    let reply = ModeReply::parse("MODE rust +").unwrap();
+    assert_eq!(reply.modes, ChannelModes::default());
+
+    assert_eq!(ModeReply::parse("MODE rust +k"), None);
+    assert_eq!(ModeReply::parse("MODE rust +m extra"), None);
+}
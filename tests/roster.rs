@@ -0,0 +1,92 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Error;
+use chat::reply::ErrorCode;
+use chrono::Utc;
+use common::{TestClient as Client, TestServer as Server};
+
+/// Connects to the server's native listener, joining `chan` as `user`.
+async fn join(server: &Server, chan: &str, user: &str) -> Result<Client, Error> {
+    let mut client = Client::new(&server.socket).await?;
+    client.send(&format!("JOIN {} {}", chan, user)).await?;
+    assert_eq!(client.recv().await?, format!("{} has joined", user));
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_names() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "cooking", "joe").await?;
+    let mut alice = join(&server, "cooking", "alice").await?;
+    assert_eq!(joe.recv().await?, "alice has joined");
+    let _bob = join(&server, "rust", "bob").await?;
+
+    // Members are listed in the order they joined.
+    assert_eq!(alice.names(None).await?, vec!["joe", "alice"]);
+    assert_eq!(alice.names(Some("rust")).await?, vec!["bob"]);
+    assert!(alice.names(Some("gardening")).await?.is_empty());
+
+    alice.send("NAMES").await?;
+    assert_eq!(alice.recv().await?, "NAMES cooking joe");
+    assert_eq!(alice.recv().await?, "NAMES cooking alice");
+    assert_eq!(alice.recv().await?, "END NAMES cooking");
+
+    alice.send("PART").await?;
+    alice.send("NAMES").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NoChannel);
+    assert_eq!(joe.recv().await?, "alice has left");
+    assert_eq!(joe.names(None).await?, vec!["joe"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_names_keeps_messages() -> Result<(), Error> {
+    let server = Server::new().await?;
+
+    let mut joe = join(&server, "cooking", "joe").await?;
+    joe.send("hello").await?;
+    // The message arrives before the reply, and is kept for later.
+    assert_eq!(joe.names(None).await?, vec!["joe"]);
+    assert_eq!(joe.recv().await?, "joe: hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_who() -> Result<(), Error> {
+    let server = Server::new().await?;
+    let before = Utc::now() - chrono::Duration::seconds(1);
+
+    let mut joe = join(&server, "cooking", "joe").await?;
+    joe.send("JOIN rust").await?;
+    assert_eq!(joe.recv().await?, "[rust] joe has joined");
+    let mut alice = join(&server, "gardening", "alice").await?;
+
+    let replies = alice.who("joe").await?;
+    let channels: Vec<_> = replies.iter().map(|r| r.channel.as_deref()).collect();
+    assert_eq!(channels, vec![Some("cooking"), Some("rust")]);
+    for reply in &replies {
+        assert_eq!(reply.user, "joe");
+        assert!(reply.connected_since >= before);
+        assert!(reply.connected_since <= Utc::now());
+        assert!(reply.idle < Duration::from_secs(5));
+    }
+
+    // Users in no channel at all can still be looked up.
+    joe.send("PART cooking").await?;
+    joe.send("PART rust").await?;
+    joe.send("PING").await?;
+    assert_eq!(joe.recv().await?, "PONG");
+    let replies = alice.who("joe").await?;
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].channel, None);
+
+    alice.send("WHO bob").await?;
+    assert_eq!(alice.recv_error().await?, ErrorCode::NoSuchUser);
+
+    Ok(())
+}
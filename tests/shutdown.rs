@@ -0,0 +1,114 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use chat::{client::ClientError, config::ServerConfig};
+use common::{TestClient as Client, TestServer as Server};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// How long the server may take to shut down in these tests.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn is_closed(result: Result<String, Error>) -> bool {
+    matches!(
+        result.map_err(|e| e.downcast::<ClientError>()),
+        Err(Ok(ClientError::ConnectionClosed))
+    )
+}
+
+#[tokio::test]
+async fn test_shutdown_notifies_clients() -> Result<(), Error> {
+    let mut server = Server::new().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert_eq!(joe.recv().await?, "alice has joined");
+    alice.send("JOIN rust").await?;
+    assert_eq!(alice.recv().await?, "[rust] alice has joined");
+
+    timeout(SHUTDOWN_TIMEOUT, server.shutdown()).await??;
+
+    // Every client is told once, even when they're in several channels.
+    assert_eq!(joe.recv().await?, "server is shutting down");
+    assert!(is_closed(joe.recv().await));
+    assert_eq!(alice.recv().await?, "server is shutting down");
+    assert!(is_closed(alice.recv().await));
+
+    // No more clients are accepted.
+    assert!(Client::new(&server.socket).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_during_handshake() -> Result<(), Error> {
+    let mut server = Server::new().await?;
+
+    // This client never sends its join command.
+    let mut joe = Client::new(&server.socket).await?;
+    // Give the server a moment to accept the connection, connections still waiting to be
+    // accepted are simply reset once it shuts down.
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    timeout(SHUTDOWN_TIMEOUT, server.shutdown()).await??;
+
+    assert_eq!(joe.recv().await?, "server is shutting down");
+    assert!(is_closed(joe.recv().await));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_irc() -> Result<(), Error> {
+    let mut server = Server::with_irc().await?;
+
+    let stream = TcpStream::connect(server.irc_socket.unwrap()).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer
+        .write_all(b"NICK alice\r\nUSER alice 0 * :Alice\r\n")
+        .await?;
+    // Wait for the end of the welcome burst.
+    while !lines.next_line().await?.unwrap().contains(" 422 ") {}
+
+    timeout(SHUTDOWN_TIMEOUT, server.shutdown()).await??;
+
+    assert_eq!(
+        lines.next_line().await?.as_deref(),
+        Some("ERROR :Closing link: Server shutting down")
+    );
+    assert_eq!(lines.next_line().await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_deadline() -> Result<(), Error> {
+    let mut server = Server::with_config(ServerConfig {
+        websocket_bind: vec![Server::any_port()],
+        shutdown_timeout_ms: 50,
+        ..Server::config()
+    })
+    .await?;
+
+    // This client never completes its WebSocket handshake, so it can't be told to go away.
+    let _joe = TcpStream::connect(server.websocket_socket.unwrap()).await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // The server gives up on it once the deadline has passed.
+    let start = Instant::now();
+    timeout(SHUTDOWN_TIMEOUT, server.shutdown()).await??;
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    Ok(())
+}
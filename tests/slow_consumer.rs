@@ -0,0 +1,151 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Error;
+use chat::{
+    client::Client,
+    codec::ChatCodec,
+    config::{ServerConfig, SlowConsumerPolicy},
+    reply::{ErrorCode, ErrorReply},
+};
+use common::TestServer as Server;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    time::timeout,
+};
+
+/// Number of messages sent while the slow client isn't reading, enough to fill any socket
+/// buffer along the way.
+const MESSAGES: usize = 1000;
+
+/// How long the slow client waits for more lines before deciding there are none.
+const WAIT: Duration = Duration::from_millis(500);
+
+async fn server(slow_consumer: SlowConsumerPolicy) -> Result<Server, Error> {
+    Server::with_config(ServerConfig {
+        channel_capacity: 8,
+        slow_consumer,
+        outbound_queue: 2 * MESSAGES,
+        ..Server::config()
+    })
+    .await
+}
+
+/// Joins `rust` as `slow`, over a socket with a small receive buffer so it fills up quickly.
+async fn join_slow(server: &Server) -> Result<ChatCodec<TcpStream>, Error> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_recv_buffer_size(4096)?;
+    let mut slow = ChatCodec::new(socket.connect(server.socket).await?);
+    slow.send("JOIN rust slow").await?;
+    assert_eq!(
+        timeout(WAIT, slow.next()).await?.unwrap()?,
+        "slow has joined"
+    );
+    Ok(slow)
+}
+
+/// Has `alice` send [`MESSAGES`] long messages to `rust`, waiting for each to come back so
+/// she never falls behind herself.
+///
+/// Returns alice, along with the other lines she received in the meantime.
+async fn flood(server: &Server) -> Result<(Client, Vec<String>), Error> {
+    let mut alice = Client::new(&server.socket).await?;
+    alice.send("JOIN rust alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+
+    let padding = "x".repeat(10_000);
+    let mut others = Vec::new();
+    for i in 0..MESSAGES {
+        let msg = format!("{} {}", i, padding);
+        alice.send(&msg).await?;
+        // Whatever happens to the slow client, alice gets every message.
+        let echo = format!("alice: {}", msg);
+        loop {
+            let line = timeout(WAIT, alice.recv()).await??;
+            if line == echo {
+                break;
+            }
+            others.push(line);
+        }
+    }
+    Ok((alice, others))
+}
+
+/// Reads every line the slow client was sent, returning the number of messages from alice
+/// along with the number of messages it was told were dropped.
+async fn catch_up(slow: &mut ChatCodec<TcpStream>) -> Result<(usize, u64), Error> {
+    let (mut received, mut dropped) = (0, 0);
+    while let Ok(Some(line)) = timeout(WAIT, slow.next()).await {
+        let line = line?;
+        if line == "alice has joined" {
+            continue;
+        } else if line.starts_with("alice: ") {
+            received += 1;
+        } else if let Some(reply) = ErrorReply::parse(&line) {
+            assert_eq!(reply.code, ErrorCode::MessagesDropped);
+            let count = reply.text.split(' ').next().unwrap();
+            dropped += count.parse::<u64>()?;
+        } else {
+            panic!("unexpected line `{}`", line);
+        }
+    }
+    Ok((received, dropped))
+}
+
+#[tokio::test]
+async fn test_notify() -> Result<(), Error> {
+    let server = server(SlowConsumerPolicy::Notify).await?;
+    let mut slow = join_slow(&server).await?;
+    let (mut alice, others) = flood(&server).await?;
+    assert!(others.is_empty());
+
+    // Every message is accounted for, either received or reported as dropped.
+    let (received, dropped) = catch_up(&mut slow).await?;
+    assert!(dropped > 0);
+    assert_eq!(received + dropped as usize, MESSAGES);
+
+    // The slow client is still there.
+    alice.send("still there?").await?;
+    assert_eq!(alice.recv().await?, "alice: still there?");
+    assert_eq!(
+        timeout(WAIT, slow.next()).await?.unwrap()?,
+        "alice: still there?"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disconnect() -> Result<(), Error> {
+    let server = server(SlowConsumerPolicy::Disconnect).await?;
+    let mut slow = join_slow(&server).await?;
+    let (mut alice, others) = flood(&server).await?;
+    assert!(others.is_empty());
+
+    // Once they get to it, the slow client is told how much they missed, then disconnected.
+    let (received, dropped) = catch_up(&mut slow).await?;
+    assert!(dropped > 0);
+    assert!(received < MESSAGES);
+    assert!(timeout(WAIT, slow.next()).await?.is_none());
+
+    alice.send("still there?").await?;
+    assert_eq!(alice.recv().await?, "slow has left");
+    assert_eq!(alice.recv().await?, "alice: still there?");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_buffer() -> Result<(), Error> {
+    let server = server(SlowConsumerPolicy::Buffer).await?;
+    let mut slow = join_slow(&server).await?;
+    let (_alice, others) = flood(&server).await?;
+    assert!(others.is_empty());
+
+    // The messages waited for the slow client to catch up, none were dropped.
+    assert_eq!(catch_up(&mut slow).await?, (MESSAGES, 0));
+
+    Ok(())
+}
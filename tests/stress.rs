@@ -21,7 +21,6 @@ async fn test_many_simultaneous_connections() -> Result<(), Error> {
 
     let user_table: Arc<Mutex<AHashSet<String>>> = Arc::new(Mutex::new(
         (0..CONCURRENCY_LIMIT)
-            .into_iter()
             .map(|i| format!("user_{} has joined", i))
             .collect(),
     ));
@@ -32,7 +31,7 @@ async fn test_many_simultaneous_connections() -> Result<(), Error> {
 
     let _users =
         stream::iter(0..CONCURRENCY_LIMIT)
-            .then(|i| async move {
+            .map(|i| {
                 tokio::spawn(async move {
                     let mut client = Client::new(&socket).await?;
                     client.send(&format!("JOIN test user_{}", i)).await?;
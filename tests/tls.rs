@@ -0,0 +1,118 @@
+mod common;
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Error};
+use chat::client::{Client as ChatClient, ClientError};
+use common::{TestClient as Client, TestServer as Server};
+
+/// A self-signed certificate for `localhost`, along with its private key, written to a temporary
+/// directory that is removed on drop.
+struct SelfSigned {
+    dir: PathBuf,
+}
+
+impl SelfSigned {
+    fn new(name: &str) -> Result<Self, Error> {
+        let dir = std::env::temp_dir().join(format!("chat-tls-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir)?;
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+        fs::write(dir.join("cert.pem"), cert.serialize_pem()?)?;
+        fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+        Ok(Self { dir })
+    }
+
+    fn cert(&self) -> PathBuf {
+        self.dir.join("cert.pem")
+    }
+
+    fn key(&self) -> PathBuf {
+        self.dir.join("key.pem")
+    }
+}
+
+impl Drop for SelfSigned {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+async fn tls_server(certs: &SelfSigned) -> Result<Server, Error> {
+    Server::with_tls(&certs.cert(), &certs.key()).await
+}
+
+#[tokio::test]
+async fn test_tls_and_tcp_clients() -> Result<(), Error> {
+    let certs = SelfSigned::new("chat")?;
+    let server = tls_server(&certs).await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    let mut alice =
+        Client::new_tls(&server.tls_socket.unwrap(), "localhost", &certs.cert()).await?;
+    alice.send("JOIN cooking alice").await?;
+    assert_eq!(alice.recv().await?, "alice has joined");
+    assert_eq!(joe.recv().await?, "alice has joined");
+
+    alice.send("hi joe").await?;
+    assert_eq!(alice.recv().await?, "alice: hi joe");
+    assert_eq!(joe.recv().await?, "alice: hi joe");
+
+    joe.send("hello alice").await?;
+    assert_eq!(joe.recv().await?, "joe: hello alice");
+    assert_eq!(alice.recv().await?, "joe: hello alice");
+
+    assert!(alice.recv().await.is_err()); // should timeout
+    assert!(joe.recv().await.is_err()); // should timeout
+
+    drop(alice);
+    assert_eq!(joe.recv().await?, "alice has left");
+
+    Ok(())
+}
+
+async fn connect_tls(addr: &SocketAddr, name: &str, ca: &Path) -> Result<(), Error> {
+    match ChatClient::new_tls(addr, name, ca).await {
+        Err(ClientError::TlsHandshake(..)) => Ok(()),
+        Err(e) => Err(e.into()),
+        Ok(_) => Err(anyhow!("expected the tls handshake to fail")),
+    }
+}
+
+#[tokio::test]
+async fn test_tls_untrusted_certificate() -> Result<(), Error> {
+    let certs = SelfSigned::new("untrusted-server")?;
+    let other = SelfSigned::new("untrusted-other")?;
+    let server = tls_server(&certs).await?;
+
+    connect_tls(&server.tls_socket.unwrap(), "localhost", &other.cert()).await
+}
+
+#[tokio::test]
+async fn test_tls_wrong_server_name() -> Result<(), Error> {
+    let certs = SelfSigned::new("wrong-name")?;
+    let server = tls_server(&certs).await?;
+
+    connect_tls(&server.tls_socket.unwrap(), "example.com", &certs.cert()).await
+}
+
+#[tokio::test]
+async fn test_tls_missing_certificate() -> Result<(), Error> {
+    let certs = SelfSigned::new("missing")?;
+
+    assert!(Server::with_tls(&certs.dir.join("nope.pem"), &certs.key())
+        .await
+        .is_err());
+    assert!(Server::with_tls(&certs.cert(), &certs.cert())
+        .await
+        .is_err());
+
+    Ok(())
+}
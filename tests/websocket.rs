@@ -0,0 +1,80 @@
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, Error};
+use common::{TestClient as Client, TestServer as Server};
+use futures::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{client_async, tungstenite::Message, WebSocketStream};
+
+struct WsClient(WebSocketStream<TcpStream>);
+
+impl WsClient {
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    async fn new(server_addr: &SocketAddr) -> Result<Self, Error> {
+        let stream = TcpStream::connect(server_addr).await?;
+        let url = format!("ws://{}/", server_addr);
+        let (ws, _) = timeout(Self::TIMEOUT, client_async(url, stream)).await??;
+        Ok(Self(ws))
+    }
+
+    async fn send(&mut self, msg: &str) -> Result<(), Error> {
+        timeout(Self::TIMEOUT, self.0.send(Message::Text(msg.to_owned()))).await??;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<String, Error> {
+        match timeout(Self::TIMEOUT, self.0.next()).await? {
+            Some(Ok(Message::Text(line))) => Ok(line),
+            Some(Ok(msg)) => Err(anyhow!("unexpected frame {:?}", msg)),
+            Some(Err(e)) => Err(e.into()),
+            None => Err(anyhow!("connection closed")),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_websocket_and_tcp_clients() -> Result<(), Error> {
+    let server = Server::with_websocket().await?;
+
+    let mut joe = Client::new(&server.socket).await?;
+    joe.send("JOIN cooking joe").await?;
+    assert_eq!(joe.recv().await?, "joe has joined");
+
+    let mut dash = WsClient::new(&server.websocket_socket.unwrap()).await?;
+    dash.send("JOIN cooking dashboard").await?;
+    assert_eq!(dash.recv().await?, "dashboard has joined");
+    assert_eq!(joe.recv().await?, "dashboard has joined");
+
+    joe.send("hi dashboard").await?;
+    assert_eq!(joe.recv().await?, "joe: hi dashboard");
+    assert_eq!(dash.recv().await?, "joe: hi dashboard");
+
+    dash.send("hello joe").await?;
+    assert_eq!(dash.recv().await?, "dashboard: hello joe");
+    assert_eq!(joe.recv().await?, "dashboard: hello joe");
+
+    assert!(dash.recv().await.is_err()); // should timeout
+    assert!(joe.recv().await.is_err()); // should timeout
+
+    dash.0.close(None).await?;
+    assert_eq!(joe.recv().await?, "dashboard has left");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket_errors() -> Result<(), Error> {
+    let server = Server::with_websocket().await?;
+
+    let mut dash = WsClient::new(&server.websocket_socket.unwrap()).await?;
+    dash.send("WRONG cooking dashboard").await?;
+    assert_eq!(
+        dash.recv().await?,
+        "ERR 451 INVALID_JOIN unknown verb `WRONG`"
+    );
+
+    Ok(())
+}